@@ -1,17 +1,25 @@
-#![allow(non_snake_case, static_mut_refs)]
+#![allow(non_snake_case)]
 
 mod system;
+use std::time::Instant;
+use system::Log;
 use system::Server::Server;
 
 fn main()
 {
-	let server = Server::getInstance();
+	// Binds the sockets before anything is logged as running
+	drop(Server::getInstance());
 
-	println!("Server is running. Waiting for players...");
+	Log::info("Server is running. Waiting for players...");
 
 	loop
 	{
-		server.listen();
+		// Released every iteration so other threads can get in between updates
+		let mut server = Server::getInstance();
+		if !server.isRunning() { break; }
+		server.listen(Instant::now());
 		server.update();
 	}
+
+	Log::info("Server is stopped.");
 }
\ No newline at end of file
@@ -1,14 +1,50 @@
-use std::{io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}};
+use std::{collections::VecDeque, io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, time::{Duration, Instant}};
 
+use super::Log;
+use super::Config::ClassInfo;
 use super::Transmission::{ClientMessage, ServerMessage};
 
+const TRAIL_LENGTH: usize = 10;
+
 pub struct Client
 {
 	pub id: u8,
 	pub tcp: Option<TcpStream>,
 	pub name: String,
 	pub class: String,
-	pub udp: Option<SocketAddr>
+	pub udp: Option<SocketAddr>,
+	pub team: u8,
+	pub buffer: Vec<u8>,
+	// Bytes the socket did not accept yet
+	pub outgoing: Vec<u8>,
+	pub hp: (u16, u16),
+	pub mana: (u16, u16),
+	pub lastTcp: Option<Instant>,
+	pub lastUdp: Option<Instant>,
+	// Last datagram the server sent to the client
+	pub lastUdpSent: Option<Instant>,
+	// Any traffic from the client, TCP or UDP
+	pub lastSeen: Instant,
+	pub lastPing: Option<Instant>,
+	// Round trip of the last answered Ping
+	pub rtt: Option<Duration>,
+	pub connectedAt: Instant,
+	pub udpSequence: Option<u16>,
+	pub udpReceived: u32,
+	pub udpLost: u32,
+	pub lastChat: Option<Instant>,
+	pub muted: bool,
+	pub mutedUntil: Option<Instant>,
+	pub trail: VecDeque<(u16, u16, Instant)>,
+	// Item id and count, never zero
+	pub inventory: Vec<(String, u16)>,
+	pub resync: bool,
+	// Bit N is set while the state of P(N+1) changed since the last send to this client
+	pub stale: u8,
+	// The client asked for Ack messages in Register
+	pub acks: bool,
+	// Lets the player take the slot back after a dropped connection, 0 if not registered
+	pub token: u64
 }
 
 impl Client
@@ -21,13 +57,43 @@ impl Client
 			tcp: None,
 			name: String::new(),
 			class: String::new(),
-			udp: None
+			udp: None,
+			team: 0,
+			buffer: vec![],
+			outgoing: vec![],
+			hp: (0, 0),
+			mana: (0, 0),
+			lastTcp: None,
+			lastUdp: None,
+			lastUdpSent: None,
+			lastSeen: Instant::now(),
+			lastPing: None,
+			rtt: None,
+			connectedAt: Instant::now(),
+			udpSequence: None,
+			udpReceived: 0,
+			udpLost: 0,
+			lastChat: None,
+			muted: false,
+			mutedUntil: None,
+			trail: VecDeque::new(),
+			inventory: vec![],
+			resync: false,
+			stale: u8::MAX,
+			acks: false,
+			token: 0
 		}
 	}
-	pub fn connect(tcp: TcpStream, id: u8, name: String, class: String) -> Self
+	// The socket is nonblocking, so `timeout` never fires on the polling reads
+	// and writes here. It only bounds a blocking use of the socket, if any code
+	// switches it back. A zero one is raised to a millisecond
+	pub fn connect(tcp: TcpStream, id: u8, name: String, class: String, timeout: Duration) -> Self
 	{
+		let timeout = Some(timeout.max(Duration::from_millis(1)));
 		let _ = tcp.set_nodelay(true);
 		let _ = tcp.set_nonblocking(true);
+		let _ = tcp.set_read_timeout(timeout);
+		let _ = tcp.set_write_timeout(timeout);
 		
 		let mut client = Self
 		{
@@ -35,7 +101,31 @@ impl Client
 			tcp: Some(tcp),
 			name: name.clone(),
 			class: class.clone(),
-			udp: None
+			udp: None,
+			team: 0,
+			buffer: vec![],
+			outgoing: vec![],
+			hp: (0, 0),
+			mana: (0, 0),
+			lastTcp: None,
+			lastUdp: None,
+			lastUdpSent: None,
+			lastSeen: Instant::now(),
+			lastPing: None,
+			rtt: None,
+			connectedAt: Instant::now(),
+			udpSequence: None,
+			udpReceived: 0,
+			udpLost: 0,
+			lastChat: None,
+			muted: false,
+			mutedUntil: None,
+			trail: VecDeque::new(),
+			inventory: vec![],
+			resync: false,
+			stale: u8::MAX,
+			acks: false,
+			token: 0
 		};
 
 		client.sendTCP(ClientMessage::Login(id, name, class));
@@ -43,36 +133,306 @@ impl Client
 		client
 	}
 
+	// Starts the player with full HP and mana of the class
+	pub fn setClass(&mut self, class: String, info: &ClassInfo)
+	{
+		self.class = class;
+		self.hp = (info.hp, info.hp);
+		self.mana = (info.mana, info.mana);
+	}
+
+	// Applies a change to HP and mana keeping them within [0, max]
+	pub fn changeStats(&mut self, hp: i16, mana: i16)
+	{
+		let apply = |value: (u16, u16), delta: i16|
+		{
+			(((value.0 as i32) + (delta as i32)).clamp(0, value.1 as i32) as u16, value.1)
+		};
+		self.hp = apply(self.hp, hp);
+		self.mana = apply(self.mana, mana);
+	}
+
+	pub fn addItem(&mut self, item: String, count: u16)
+	{
+		if count == 0 { return; }
+		match self.inventory.iter_mut().find(|(x, _)| *x == item)
+		{
+			Some((_, n)) => *n = n.saturating_add(count),
+			None => self.inventory.push((item, count))
+		}
+	}
+
+	// Removing more than the player has just takes everything
+	pub fn removeItem(&mut self, item: String, count: u16)
+	{
+		if let Some((_, n)) = self.inventory.iter_mut().find(|(x, _)| *x == item)
+		{
+			*n = n.saturating_sub(count);
+		}
+		self.inventory.retain(|(_, n)| *n > 0);
+	}
+
+	// Accepts only packets newer than the last one, the sequence
+	// number wraps around so "newer" means less than half the range ahead
+	pub fn acceptSequence(&mut self, seq: u16) -> bool
+	{
+		if let Some(last) = self.udpSequence
+		{
+			let diff = seq.wrapping_sub(last) as i16;
+			if diff <= 0 { return false; }
+			self.udpLost += (diff - 1) as u32;
+		}
+		self.udpSequence = Some(seq);
+		self.udpReceived += 1;
+		true
+	}
+
+	// A mute without an end time lasts until /unmute
+	pub fn isMuted(&self) -> bool
+	{
+		self.muted && self.mutedUntil.is_none_or(|t| Instant::now() < t)
+	}
+
+	// Remembers the last TRAIL_LENGTH positions the player moved to
+	pub fn addTrail(&mut self, x: u16, y: u16)
+	{
+		if self.trail.back().is_some_and(|(lx, ly, _)| *lx == x && *ly == y) { return; }
+		self.trail.push_back((x, y, Instant::now()));
+		if self.trail.len() > TRAIL_LENGTH { self.trail.pop_front(); }
+	}
+
 	pub fn sendTCP(&mut self, msg: ClientMessage)
 	{
 		if self.tcp.is_none() { return; }
-		let _ = self.tcp.as_mut().unwrap().write_all(&msg.toRaw());
+		self.outgoing.extend_from_slice(&msg.toRaw());
+		let _ = self.flushTCP();
 	}
 
-	pub fn receiveTCP(&mut self) -> Option<ServerMessage>
+	// Writes as much of the queued data as the socket takes,
+	// fails only if the connection is broken
+	pub fn flushTCP(&mut self) -> std::io::Result<()>
 	{
-		if self.tcp.is_none() { return None; }
-		let buffer = &mut [0u8; 1024];
-		match self.tcp.as_mut().unwrap().read(buffer)
+		let tcp = match self.tcp.as_mut()
 		{
-			Ok(size) =>
+			Some(x) => x,
+			None => return Ok(())
+		};
+		while !self.outgoing.is_empty()
+		{
+			match tcp.write(&self.outgoing)
 			{
-				if size == 0 { Some(ServerMessage::Disconnected) }
-				else { Some(ServerMessage::fromRaw(&buffer[0..size])) }
-			},
-			Err(x) =>
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(size) => { self.outgoing.drain(0..size); },
+				Err(x) if x.kind() == ErrorKind::WouldBlock => break,
+				Err(x) if x.kind() == ErrorKind::Interrupted => continue,
+				Err(x) => return Err(x)
+			}
+		}
+		Ok(())
+	}
+
+	// Decodes at most `limit` messages, the rest waits for the next call.
+	// Data read at `now` counts as a sign of life
+	pub fn receiveTCP(&mut self, limit: usize, now: Instant) -> Vec<ServerMessage>
+	{
+		let mut msgs = vec![];
+		if self.tcp.is_none() { return msgs; }
+		let buffer = &mut [0u8; 1024];
+		while self.buffer.len() < 65536
+		{
+			match self.tcp.as_mut().unwrap().read(buffer)
 			{
-				match x.kind()
+				Ok(size) =>
 				{
-					ErrorKind::WouldBlock => { return None; },
-					_ =>
+					if size == 0
 					{
-						println!("Error occured on player {}: {x}", self.name);
-						self.tcp = None;
-						return Some(ServerMessage::Disconnected);
+						msgs.push(ServerMessage::Disconnected);
+						return msgs;
+					}
+					self.buffer.extend_from_slice(&buffer[0..size]);
+					self.lastTcp = Some(now);
+					self.lastSeen = now;
+				},
+				Err(x) =>
+				{
+					match x.kind()
+					{
+						ErrorKind::WouldBlock => break,
+						// Only seen if the socket was made blocking, the
+						// peer is slow and dropSilentClients decides if it's gone
+						ErrorKind::TimedOut =>
+						{
+							Log::debug(format!("Read from player {} timed out", self.name));
+							break;
+						},
+						_ =>
+						{
+							Log::warn(format!("Error occured on player {}: {x}", self.name));
+							self.tcp = None;
+							msgs.push(ServerMessage::Disconnected);
+							return msgs;
+						}
 					}
 				}
 			}
 		}
+
+		// Frames are prefixed with their length (u16 LE), incomplete
+		// frames stay in the buffer until the rest arrives
+		while self.buffer.len() >= 2 && msgs.len() < limit
+		{
+			let size = u16::from_le_bytes([self.buffer[0], self.buffer[1]]) as usize;
+			if self.buffer.len() < size + 2 { break; }
+			let frame = self.buffer.drain(0..size + 2).collect::<Vec<u8>>();
+			if size == 0 { continue; }
+			msgs.push(ServerMessage::fromRaw(&frame[2..]));
+		}
+
+		msgs
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::net::TcpListener;
+
+	use super::*;
+
+	// A client on the server side of a loopback connection and its peer
+	fn pair() -> (Client, TcpStream)
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (tcp, _) = listener.accept().unwrap();
+		(Client::connect(tcp, 1, String::from("noname"), String::new(), Duration::from_millis(50)), peer)
+	}
+
+	// Reads until the bytes written by the peer are there
+	fn receive(c: &mut Client) -> Vec<ServerMessage>
+	{
+		std::thread::sleep(Duration::from_millis(20));
+		c.receiveTCP(16, Instant::now())
+	}
+
+	#[test]
+	fn splitFrameIsDecodedOnce()
+	{
+		let (mut c, mut peer) = pair();
+		let frame = [&6u16.to_le_bytes() as &[u8], &[2], "hello".as_bytes()].concat();
+		peer.write_all(&frame[..4]).unwrap();
+		assert!(receive(&mut c).is_empty());
+		peer.write_all(&frame[4..]).unwrap();
+		let msgs = receive(&mut c);
+		assert_eq!(msgs.len(), 1);
+		assert!(matches!(&msgs[0], ServerMessage::Chat(x, _) if x == "hello"));
+		assert!(c.buffer.is_empty());
+	}
+
+	#[test]
+	fn framesSentTogetherStaySeparate()
+	{
+		let (mut c, mut peer) = pair();
+		let frame = [&3u16.to_le_bytes() as &[u8], &[2], "hi".as_bytes()].concat();
+		peer.write_all(&[frame.clone(), frame].concat()).unwrap();
+		assert_eq!(receive(&mut c).len(), 2);
+	}
+
+	#[test]
+	fn stalePacketsAreDropped()
+	{
+		let mut c = Client::default();
+		assert!(c.acceptSequence(10));
+		assert!(c.acceptSequence(12));
+		assert!(!c.acceptSequence(11));
+		assert!(!c.acceptSequence(12));
+		assert_eq!((c.udpReceived, c.udpLost), (2, 1));
+
+		// 0 comes right after u16::MAX
+		let mut c = Client::default();
+		assert!(c.acceptSequence(u16::MAX - 1));
+		assert!(c.acceptSequence(1));
+		assert!(!c.acceptSequence(u16::MAX));
+		assert_eq!(c.udpLost, 2);
+	}
+
+	#[test]
+	fn floodIsSpreadOverTicks()
+	{
+		let (mut c, mut peer) = pair();
+		let frame = [&3u16.to_le_bytes() as &[u8], &[2], "hi".as_bytes()].concat();
+		peer.write_all(&frame.repeat(40)).unwrap();
+		assert_eq!(receive(&mut c).len(), 16);
+		assert_eq!(c.receiveTCP(16, Instant::now()).len(), 16);
+		assert_eq!(c.receiveTCP(16, Instant::now()).len(), 8);
+		assert!(c.receiveTCP(16, Instant::now()).is_empty());
+	}
+
+	#[test]
+	fn unsentBytesWaitForTheNextFlush()
+	{
+		let (mut c, mut peer) = pair();
+		let frame = ClientMessage::Chat("x".repeat(60000)).toRaw();
+
+		// The peer doesn't read until the socket stops taking data
+		let mut sent = 0;
+		while c.outgoing.is_empty() && sent < 10_000
+		{
+			c.sendTCP(ClientMessage::Chat("x".repeat(60000)));
+			sent += 1;
+		}
+		assert!(!c.outgoing.is_empty() && c.tcp.is_some());
+		let last = ClientMessage::Chat(String::from("последнее")).toRaw();
+		c.sendTCP(ClientMessage::Chat(String::from("последнее")));
+		let login = ClientMessage::Login(1, String::from("noname"), String::new()).toRaw();
+		let total = login.len() + sent * frame.len() + last.len();
+
+		let _ = peer.set_read_timeout(Some(Duration::from_millis(50)));
+		let mut received = vec![];
+		let buffer = &mut [0u8; 65536];
+		while received.len() < total
+		{
+			c.flushTCP().unwrap();
+			match peer.read(buffer)
+			{
+				Ok(size) => received.extend_from_slice(&buffer[..size]),
+				Err(_) => break
+			}
+		}
+		assert_eq!(received.len(), total);
+		assert!(received.starts_with(&login) && received.ends_with(&last));
+	}
+
+	#[test]
+	fn itemsAddUpAndRunOut()
+	{
+		let mut c = Client::default();
+		c.addItem(String::from("зелье"), 2);
+		c.addItem(String::from("меч"), 1);
+		c.addItem(String::from("зелье"), 3);
+		c.addItem(String::from("пусто"), 0);
+		assert_eq!(c.inventory, vec![(String::from("зелье"), 5), (String::from("меч"), 1)]);
+
+		c.removeItem(String::from("зелье"), 2);
+		c.removeItem(String::from("меч"), 10);
+		c.removeItem(String::from("щит"), 1);
+		assert_eq!(c.inventory, vec![(String::from("зелье"), 3)]);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn timeoutsAreSetOnTheStream()
+	{
+		let (c, _peer) = pair();
+		let tcp = c.tcp.as_ref().unwrap();
+		// The kernel rounds timeouts up to its clock ticks
+		let near = |x: Option<Duration>, ms: u128| x.is_some_and(|x| (ms..ms + 10).contains(&x.as_millis()));
+		assert!(near(tcp.read_timeout().unwrap(), 50));
+		assert!(near(tcp.write_timeout().unwrap(), 50));
+
+		// Zero would mean no timeout at all
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let c = Client::connect(listener.accept().unwrap().0, 1, String::new(), String::new(), Duration::ZERO);
+		assert!(near(c.tcp.as_ref().unwrap().read_timeout().unwrap(), 1));
+	}
+}
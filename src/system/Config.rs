@@ -1,11 +1,28 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, net::IpAddr, time::Duration};
 
-#[derive(Clone, PartialEq)]
+use super::Log::{self, Level};
+use super::RES_DIR;
+use super::State::State;
+
+// Player IDs take the lower 3 bits of the state packet's first byte
+pub const MAX_PLAYERS: u8 = 7;
+
+// Settings editable from the web client: key, title and the allowed range.
+// getSettings shows them and saveSettings accepts them only from here,
+// validate() still applies on top (port 1-1023 is refused, 0 picks any).
+const SETTINGS: &[(&str, &str, u64, u64)] = &[
+	("maxPlayersCount", "Количество игроков", 1, MAX_PLAYERS as u64),
+	("port", "Игровой порт", 0, u16::MAX as u64),
+	("tickRate", "Частота обновления", 1, 100)
+];
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Permission
 {
 	Developer,
 	Admin,
-	Player
+	Player,
+	Guest
 }
 
 impl Permission
@@ -14,6 +31,7 @@ impl Permission
 	{
 		if x == "dev" { return Permission::Developer; }
 		if x == "admin" { return Permission::Admin; }
+		if x == "guest" { return Permission::Guest; }
 		Permission::Player
 	}
 
@@ -23,7 +41,8 @@ impl Permission
 		{
 			Permission::Developer => String::from("dev"),
 			Permission::Admin => String::from("admin"),
-			Permission::Player => String::from("player")
+			Permission::Player => String::from("player"),
+			Permission::Guest => String::from("guest")
 		}
 	}
 
@@ -31,21 +50,74 @@ impl Permission
 	{
 		match lvl
 		{
-			Permission::Player => true,
+			Permission::Guest => true,
+			Permission::Player => *self != Permission::Guest,
 			Permission::Admin => { *self == Permission::Admin || *self == Permission::Developer },
 			Permission::Developer => *self == Permission::Developer
 		}
 	}
 }
 
+// Base stats a player of the class starts with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassInfo
+{
+	pub name: String,
+	pub hp: u16,
+	pub mana: u16
+}
+
+#[derive(Debug, Clone)]
 pub struct Config
 {
+	pub name: String,
 	pub maxPlayersCount: u8,
 	pub port: u16,
+	pub bindAddress: String,
+	pub webPort: u16,
 	pub tickRate: u8,
+	pub teamsCount: u8,
+	pub strictUdp: bool,
+	pub sharedPort: bool,
+	// X-Forwarded-For is believed, only safe behind a reverse proxy
+	pub trustProxy: bool,
+	// A name already in use gets a number appended, otherwise it's refused
+	pub renameDuplicates: bool,
+	pub defaultPermission: Permission,
 	pub sendTime: Duration,
 	pub recvTime: Duration,
+	pub chatCooldown: Duration,
+	pub maxChatHistory: usize,
+	pub maxChatPerPlayer: usize,
+	pub maxMessagesPerTick: usize,
+	pub maxBackups: usize,
+	pub maxWebConnections: usize,
+	pub autosaveInterval: Duration,
+	pub reconnectGrace: Duration,
+	pub clientTimeout: Duration,
+	// Time between full state snapshots, only changes are sent in between
+	pub keyframeInterval: Duration,
+	// Idle time after which an empty datagram keeps the client's NAT mapping open
+	pub udpKeepalive: Duration,
+	// How often TCP clients are pinged to measure the round trip, zero turns it off
+	pub pingInterval: Duration,
+	pub logLevel: Level,
+	// Fixed seed of the server's random numbers, random if not set
+	pub rngSeed: Option<u64>,
+	pub corsOrigin: String,
+	// Directory the web client files are served from
+	pub webRoot: String,
+	// Required by restricted web endpoints, empty keeps them closed
+	pub webToken: String,
+	// {name} and {players} are replaced on login
+	pub motd: String,
+	// Class of players who register without one and have none saved
+	pub defaultClass: String,
 	pub permissions: HashMap<String, Permission>,
+	// Minimal time between two calls of a command by the same executor
+	pub cooldowns: HashMap<String, Duration>,
+	// Loaded from res/system/classes.json, any class is accepted while empty
+	pub classes: HashMap<String, ClassInfo>
 }
 
 impl Default for Config
@@ -54,12 +126,42 @@ impl Default for Config
 	{
 		Self
 		{
+			name: String::from("Envell"),
 			maxPlayersCount: 5,
 			port: 0,
+			bindAddress: String::from("0.0.0.0"),
+			webPort: 8080,
 			tickRate: 1,
+			teamsCount: 2,
+			strictUdp: false,
+			sharedPort: false,
+			trustProxy: false,
+			renameDuplicates: true,
+			defaultPermission: Permission::Player,
 			sendTime: Duration::from_secs(1),
 			recvTime: Duration::from_secs_f32(0.5),
-			permissions: HashMap::new()
+			chatCooldown: Duration::from_secs(1),
+			maxChatHistory: 1000,
+			maxChatPerPlayer: 100,
+			maxMessagesPerTick: 16,
+			maxBackups: 5,
+			maxWebConnections: 64,
+			autosaveInterval: Duration::from_secs(300),
+			reconnectGrace: Duration::from_secs(30),
+			clientTimeout: Duration::from_secs(30),
+			keyframeInterval: Duration::from_secs(2),
+			udpKeepalive: Duration::from_secs(5),
+			pingInterval: Duration::from_secs(5),
+			logLevel: Level::Info,
+			rngSeed: None,
+			corsOrigin: String::from("*"),
+			webRoot: String::from("res/web"),
+			webToken: String::new(),
+			motd: String::new(),
+			defaultClass: String::new(),
+			permissions: HashMap::new(),
+			cooldowns: HashMap::new(),
+			classes: HashMap::new()
 		}
 	}
 }
@@ -71,7 +173,7 @@ impl Config
 		let doc = json::parse(&file);
 		if doc.is_err()
 		{
-			println!("Failed to load config: {}", doc.unwrap_err());
+			Log::warn(format!("Failed to load config: {}", doc.unwrap_err()));
 			return Self::default();
 		}
 		let doc = doc.unwrap();
@@ -83,20 +185,132 @@ impl Config
 			{
 				for (name, value) in section.1.entries()
 				{
+					if name == "name"
+					{
+						state.name = value.as_str().unwrap_or("Envell").to_string();
+					}
 					if name == "maxPlayersCount"
 					{
-						state.maxPlayersCount = value.as_u8().unwrap_or(1);
+						state.maxPlayersCount = value.as_u8().unwrap_or(1).clamp(1, MAX_PLAYERS);
 					}
 					if name == "port"
 					{
 						state.port = value.as_u16().unwrap_or(2018);
 					}
+					if name == "bindAddress"
+					{
+						state.bindAddress = value.as_str().unwrap_or("0.0.0.0").to_string();
+					}
+					if name == "webPort"
+					{
+						state.webPort = value.as_u16().unwrap_or(8080);
+					}
 					if name == "tickRate"
 					{
 						state.tickRate = value.as_u8().unwrap_or(30);
 						state.sendTime = Duration::from_secs_f32(1.0 / state.tickRate as f32);
 						state.recvTime = Duration::from_secs_f32(0.5 / state.tickRate as f32);
 					}
+					if name == "teamsCount"
+					{
+						state.teamsCount = value.as_u8().unwrap_or(2);
+					}
+					if name == "strictUdp"
+					{
+						state.strictUdp = value.as_bool().unwrap_or(false);
+					}
+					if name == "sharedPort"
+					{
+						state.sharedPort = value.as_bool().unwrap_or(false);
+					}
+					if name == "trustProxy"
+					{
+						state.trustProxy = value.as_bool().unwrap_or(false);
+					}
+					if name == "renameDuplicates"
+					{
+						state.renameDuplicates = value.as_bool().unwrap_or(true);
+					}
+					if name == "defaultPermission"
+					{
+						state.defaultPermission = Permission::fromString(value.as_str().unwrap_or(""));
+					}
+					if name == "chatCooldown"
+					{
+						state.chatCooldown = Duration::from_secs_f32(value.as_f32().unwrap_or(1.0).max(0.0));
+					}
+					if name == "maxChatHistory"
+					{
+						state.maxChatHistory = value.as_usize().unwrap_or(1000).max(1);
+					}
+					if name == "maxChatPerPlayer"
+					{
+						state.maxChatPerPlayer = value.as_usize().unwrap_or(100).max(1);
+					}
+					if name == "maxMessagesPerTick"
+					{
+						state.maxMessagesPerTick = value.as_usize().unwrap_or(16).max(1);
+					}
+					if name == "maxBackups"
+					{
+						state.maxBackups = value.as_usize().unwrap_or(5);
+					}
+					if name == "maxWebConnections"
+					{
+						state.maxWebConnections = value.as_usize().unwrap_or(64).max(1);
+					}
+					if name == "autosaveInterval"
+					{
+						state.autosaveInterval = Duration::from_secs(value.as_u64().unwrap_or(300));
+					}
+					if name == "reconnectGrace"
+					{
+						state.reconnectGrace = Duration::from_secs(value.as_u64().unwrap_or(30));
+					}
+					if name == "clientTimeout"
+					{
+						state.clientTimeout = Duration::from_secs(value.as_u64().unwrap_or(30));
+					}
+					if name == "keyframeInterval"
+					{
+						state.keyframeInterval = Duration::from_secs_f32(value.as_f32().unwrap_or(2.0).max(0.0));
+					}
+					if name == "udpKeepalive"
+					{
+						state.udpKeepalive = Duration::from_secs_f32(value.as_f32().unwrap_or(5.0).max(0.0));
+					}
+					if name == "pingInterval"
+					{
+						state.pingInterval = Duration::from_secs_f32(value.as_f32().unwrap_or(5.0).max(0.0));
+					}
+					if name == "logLevel"
+					{
+						state.logLevel = Level::fromString(value.as_str().unwrap_or(""));
+					}
+					if name == "rngSeed"
+					{
+						state.rngSeed = value.as_u64();
+					}
+					if name == "motd"
+					{
+						state.motd = value.as_str().unwrap_or("").to_string();
+					}
+					if name == "defaultClass"
+					{
+						state.defaultClass = value.as_str().unwrap_or("").to_string();
+					}
+					if name == "corsOrigin"
+					{
+						state.corsOrigin = value.as_str().unwrap_or("*").to_string();
+					}
+					if name == "webToken"
+					{
+						state.webToken = value.as_str().unwrap_or("").to_string();
+					}
+					if name == "webRoot"
+					{
+						state.webRoot = value.as_str().unwrap_or("res/web").to_string();
+					}
 				}
 			}
 			if section.0 == "permissions"
@@ -109,6 +323,16 @@ impl Config
 					);
 				}
 			}
+			if section.0 == "cooldowns"
+			{
+				for (cmd, time) in section.1.entries()
+				{
+					state.cooldowns.insert(
+						cmd.to_lowercase(),
+						Duration::from_secs_f32(time.as_f32().unwrap_or(0.0).max(0.0))
+					);
+				}
+			}
 		}
 		
 		state
@@ -116,45 +340,363 @@ impl Config
 
 	pub fn init() -> Self
 	{
-		match std::fs::read_to_string("res/system/config.json")
+		let mut config = match std::fs::read_to_string(format!("{RES_DIR}/config.json"))
 		{
-			Ok(file) => { Self::load(file) },
+			Ok(file) =>
+			{
+				let config = Self::load(file);
+				if let Err(errors) = config.validate()
+				{
+					for x in errors { Log::warn(format!("Config error: {x}")); }
+				}
+				config
+			},
 			Err(error) =>
 			{
-				println!("Failed to load config: {:?}\nCreating new config.", error);
+				Log::warn(format!("Failed to load config: {:?}\nCreating new config.", error));
 				Self::default()
 			}
+		};
+		config.classes = Config::loadClasses();
+		config
+	}
+
+	// `{ "warrior": { "name": "Воин", "hp": 150, "mana": 20 } }`
+	pub fn loadClasses() -> HashMap<String, ClassInfo>
+	{
+		let doc = match std::fs::read_to_string(format!("{RES_DIR}/classes.json"))
+			.map_err(|x| x.to_string())
+			.and_then(|x| json::parse(&x).map_err(|x| x.to_string()))
+		{
+			Ok(x) => x,
+			Err(x) =>
+			{
+				Log::warn(format!("Failed to load classes: {x}. Any class will be accepted."));
+				return HashMap::new();
+			}
+		};
+		let mut classes = HashMap::new();
+		for (id, info) in doc.entries()
+		{
+			classes.insert(id.to_string(), ClassInfo
+			{
+				name: info["name"].as_str().unwrap_or(id).to_string(),
+				hp: info["hp"].as_u16().unwrap_or(100),
+				mana: info["mana"].as_u16().unwrap_or(100)
+			});
 		}
+		classes
 	}
 
-	pub fn save(&self)
+	pub fn validate(&self) -> Result<(), Vec<String>>
+	{
+		let mut errors = vec![];
+		if self.port != 0 && self.port < 1024
+		{
+			errors.push(format!("port: {} is not 0 or in 1024-65535", self.port));
+		}
+		if self.bindAddress.parse::<IpAddr>().is_err()
+		{
+			errors.push(format!("bindAddress: {} is not an IP address", self.bindAddress));
+		}
+		if !(1..=100).contains(&self.tickRate)
+		{
+			errors.push(format!("tickRate: {} is not in 1-100", self.tickRate));
+		}
+		if !(1..=MAX_PLAYERS).contains(&self.maxPlayersCount)
+		{
+			errors.push(format!("maxPlayersCount: {} is not in 1-{MAX_PLAYERS}", self.maxPlayersCount));
+		}
+		if errors.is_empty() { Ok(()) } else { Err(errors) }
+	}
+
+	// Re-reads the config file. Sockets are bound at startup, so the port
+	// settings stay as they are. Fails if a connected player's ID would not
+	// fit into the new player limit.
+	pub fn reload(&mut self, highestID: u8) -> Result<(), String>
+	{
+		let file = std::fs::read_to_string(format!("{RES_DIR}/config.json")).map_err(|x| x.to_string())?;
+		if let Err(x) = json::parse(&file) { return Err(x.to_string()); }
+		let mut config = Self::load(file);
+		if config.maxPlayersCount < highestID
+		{
+			return Err(format!("P{highestID} is connected, maxPlayersCount can't be less than {highestID}"));
+		}
+		config.port = self.port;
+		config.bindAddress = self.bindAddress.clone();
+		config.webPort = self.webPort;
+		config.sharedPort = self.sharedPort;
+		config.classes = Config::loadClasses();
+		*self = config;
+		Ok(())
+	}
+
+	// Re-reads only the permissions section and merges it into the live one.
+	// Returns the number of entries read.
+	pub fn reloadPermissions(&mut self) -> Result<usize, String>
+	{
+		let file = std::fs::read_to_string(format!("{RES_DIR}/config.json")).map_err(|x| x.to_string())?;
+		let doc = json::parse(&file).map_err(|x| x.to_string())?;
+		let section = &doc["permissions"];
+		if !section.is_object() { return Err(String::from("no permissions section")); }
+		for (name, group) in section.entries()
+		{
+			self.permissions.insert(
+				name.to_string(),
+				Permission::fromString(group.as_str().unwrap_or(""))
+			);
+		}
+		Ok(section.len())
+	}
+
+	// An empty token never matches
+	pub fn checkToken(&self, token: &str) -> bool
+	{
+		!self.webToken.is_empty() && token == self.webToken
+	}
+
+	pub fn isSetting(key: &str) -> bool
+	{
+		SETTINGS.iter().any(|(name, _, _, _)| *name == key)
+	}
+
+	fn setting(&self, key: &str) -> u64
+	{
+		match key
+		{
+			"maxPlayersCount" => self.maxPlayersCount as u64,
+			"port" => self.port as u64,
+			"tickRate" => self.tickRate as u64,
+			_ => 0
+		}
+	}
+
+	// Form values from the web client arrive as strings like "30"
+	pub fn number(value: &json::JsonValue) -> Option<u64>
+	{
+		value.as_u64().or_else(|| value.as_str().and_then(|x| x.trim().parse().ok()))
+	}
+
+	// Values outside of the range are rejected, not clamped
+	pub fn applySetting(&mut self, key: &str, value: &json::JsonValue) -> Result<(), String>
+	{
+		let (_, _, min, max) = SETTINGS.iter()
+			.find(|(name, _, _, _)| *name == key)
+			.ok_or(format!("{key}: unknown setting"))?;
+		let x = Config::number(value).ok_or(format!("{key}: {value} is not a number"))?;
+		if !(*min..=*max).contains(&x) { return Err(format!("{key}: {x} is not in {min}-{max}")); }
+		match key
+		{
+			"maxPlayersCount" => self.maxPlayersCount = x as u8,
+			"port" => self.port = x as u16,
+			"tickRate" =>
+			{
+				self.tickRate = x as u8;
+				self.sendTime = Duration::from_secs_f32(1.0 / self.tickRate as f32);
+				self.recvTime = Duration::from_secs_f32(0.5 / self.tickRate as f32);
+			},
+			_ => {}
+		}
+		Ok(())
+	}
+
+	// Editable settings with their current values for the web client
+	pub fn schema(&self) -> json::JsonValue
+	{
+		let mut schema = json::JsonValue::new_object();
+		for (key, title, min, max) in SETTINGS
+		{
+			let _ = schema.insert(key, json::object!
+			{
+				type: "range",
+				name: *title,
+				value: self.setting(key),
+				props: json::object! { min: *min, max: *max }
+			});
+		}
+		schema
+	}
+
+	pub fn toJson(&self) -> json::JsonValue
 	{
 		let mut settings = json::JsonValue::new_object();
+		let _ = settings.insert("name", self.name.clone());
 		let _ = settings.insert("maxPlayersCount", self.maxPlayersCount);
 		let _ = settings.insert("port", self.port);
+		let _ = settings.insert("bindAddress", self.bindAddress.clone());
+		let _ = settings.insert("webPort", self.webPort);
 		let _ = settings.insert("tickRate", self.tickRate);
+		let _ = settings.insert("teamsCount", self.teamsCount);
+		let _ = settings.insert("strictUdp", self.strictUdp);
+		let _ = settings.insert("sharedPort", self.sharedPort);
+		let _ = settings.insert("trustProxy", self.trustProxy);
+		let _ = settings.insert("renameDuplicates", self.renameDuplicates);
+		let _ = settings.insert("defaultPermission", self.defaultPermission.toString());
+		let _ = settings.insert("chatCooldown", self.chatCooldown.as_secs_f32());
+		let _ = settings.insert("maxChatHistory", self.maxChatHistory);
+		let _ = settings.insert("maxChatPerPlayer", self.maxChatPerPlayer);
+		let _ = settings.insert("maxMessagesPerTick", self.maxMessagesPerTick);
+		let _ = settings.insert("maxBackups", self.maxBackups);
+		let _ = settings.insert("maxWebConnections", self.maxWebConnections);
+		let _ = settings.insert("autosaveInterval", self.autosaveInterval.as_secs());
+		let _ = settings.insert("reconnectGrace", self.reconnectGrace.as_secs());
+		let _ = settings.insert("clientTimeout", self.clientTimeout.as_secs());
+		let _ = settings.insert("keyframeInterval", self.keyframeInterval.as_secs_f32());
+		let _ = settings.insert("udpKeepalive", self.udpKeepalive.as_secs_f32());
+		let _ = settings.insert("pingInterval", self.pingInterval.as_secs_f32());
+		let _ = settings.insert("logLevel", self.logLevel.toString());
+		let _ = settings.insert("corsOrigin", self.corsOrigin.clone());
+		let _ = settings.insert("webRoot", self.webRoot.clone());
+		let _ = settings.insert("webToken", self.webToken.clone());
+		let _ = settings.insert("motd", self.motd.clone());
+		let _ = settings.insert("defaultClass", self.defaultClass.clone());
+		if let Some(seed) = self.rngSeed { let _ = settings.insert("rngSeed", seed); }
 
 		let mut permissions = json::JsonValue::new_object();
 		for (name, group) in &self.permissions
 		{
 			let _ = permissions.insert(&name, group.toString());
 		}
+
+		let mut cooldowns = json::JsonValue::new_object();
+		for (cmd, time) in &self.cooldowns
+		{
+			let _ = cooldowns.insert(cmd, time.as_secs_f32());
+		}
 		
 		let mut state = json::JsonValue::new_object();
 		let _ = state.insert("settings", settings);
 		let _ = state.insert("permissions", permissions);
-		
-		let _ = std::fs::write("res/system/config.json", json::stringify_pretty(state, 4));
+		let _ = state.insert("cooldowns", cooldowns);
+		state
 	}
 
+	pub fn save(&self)
+	{
+		let _ = State::write(&format!("{RES_DIR}/config.json"), json::stringify_pretty(self.toJson(), 4));
+	}
+
+	// Rates are clamped to 1-100 Hz
+	pub fn setTiming(&mut self, sendRate: u8, recvRate: u8)
+	{
+		let sendRate = sendRate.clamp(1, 100);
+		let recvRate = recvRate.clamp(1, 100);
+		self.tickRate = sendRate;
+		self.sendTime = Duration::from_secs_f32(1.0 / sendRate as f32);
+		self.recvTime = Duration::from_secs_f32(1.0 / recvRate as f32);
+	}
+
+	// The server refuses to start on an invalid address
+	pub fn bindIP(&self) -> Result<IpAddr, String>
+	{
+		self.bindAddress.parse().map_err(|_| format!("{} is not an IP address", self.bindAddress))
+	}
+
+	// Players only, the web client's rights come from Server::executor
 	pub fn getPermission(&mut self, name: &String) -> Permission
 	{
-		if name == "WebClient" { return Permission::Developer; }
-		self.permissions.get(name).unwrap_or(&Permission::Player).clone()
+		self.permissions.get(name).unwrap_or(&self.defaultPermission).clone()
 	}
 
 	pub fn setPermission(&mut self, name: String, group: Permission)
 	{
 		self.permissions.insert(name, group);
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn timingDerivesDurations()
+	{
+		let mut config = Config::default();
+		config.setTiming(20, 40);
+		assert_eq!(config.tickRate, 20);
+		assert_eq!(config.sendTime.as_millis(), 50);
+		assert_eq!(config.recvTime.as_millis(), 25);
+
+		// Clamped to 1-100 Hz
+		config.setTiming(0, 255);
+		assert_eq!(config.sendTime.as_millis(), 1000);
+		assert_eq!(config.recvTime.as_millis(), 10);
+	}
+
+	#[test]
+	fn playerLimitIsClamped()
+	{
+		let load = |x: u64| Config::load(format!("{{ \"settings\": {{ \"maxPlayersCount\": {x} }} }}"));
+		assert_eq!(load(0).maxPlayersCount, 1);
+		assert_eq!(load(3).maxPlayersCount, 3);
+		assert_eq!(load(200).maxPlayersCount, MAX_PLAYERS);
+	}
+
+	#[test]
+	fn validateListsEveryProblem()
+	{
+		assert!(Config::default().validate().is_ok());
+		assert!(Config { port: 0, ..Config::default() }.validate().is_ok());
+
+		let field = |config: Config| config.validate().unwrap_err()[0].split(':').next().unwrap().to_string();
+		assert_eq!(field(Config { port: 80, ..Config::default() }), "port");
+		assert_eq!(field(Config { tickRate: 0, ..Config::default() }), "tickRate");
+		assert_eq!(field(Config { tickRate: 101, ..Config::default() }), "tickRate");
+		assert_eq!(field(Config { maxPlayersCount: 0, ..Config::default() }), "maxPlayersCount");
+		assert_eq!(field(Config { maxPlayersCount: MAX_PLAYERS + 1, ..Config::default() }), "maxPlayersCount");
+		assert_eq!(field(Config { bindAddress: String::from("localhost"), ..Config::default() }), "bindAddress");
+
+		let config = Config { port: 80, tickRate: 0, maxPlayersCount: 0, ..Config::default() };
+		assert_eq!(config.validate().unwrap_err().len(), 3);
+	}
+
+	#[test]
+	fn cooldownsSurviveASave()
+	{
+		let mut config = Config::default();
+		config.cooldowns.insert(String::from("getposition"), Duration::from_millis(2500));
+		let saved = Config::load(json::stringify(config.toJson()));
+		assert_eq!(saved.cooldowns.get("getposition").map(|x| x.as_millis()), Some(2500));
+	}
+
+	#[test]
+	fn settingsOutOfRangeAreRejected()
+	{
+		let mut config = Config::default();
+		assert_eq!(config.applySetting("port", &json::from(70000)), Err(String::from("port: 70000 is not in 0-65535")));
+		assert_eq!(config.port, Config::default().port);
+		assert!(config.applySetting("tickRate", &json::from("101")).is_err());
+		assert!(config.applySetting("name", &json::from("x")).is_err());
+
+		// 0 picks any free port
+		assert_eq!(config.applySetting("port", &json::from("0")), Ok(()));
+		assert_eq!(config.port, 0);
+
+		// The web client is shown the same ranges
+		let schema = config.schema();
+		assert_eq!(schema["port"]["props"]["max"].as_u64(), Some(65535));
+		assert_eq!(schema["port"]["value"].as_u64(), Some(0));
+		assert_eq!(schema["maxPlayersCount"]["props"]["max"].as_u8(), Some(MAX_PLAYERS));
+		assert!(schema.entries().all(|(key, _)| Config::isSetting(key)));
+	}
+
+	#[test]
+	fn classesAreReadFromTheTable()
+	{
+		let _lock = super::super::testsupport::configFile();
+		let path = format!("{RES_DIR}/classes.json");
+		let table = "{ \"warrior\": { \"name\": \"Воин\", \"hp\": 150, \"mana\": 20 }, \"mage\": { \"hp\": 80 } }";
+		State::write(&path, String::from(table)).unwrap();
+		let classes = Config::loadClasses();
+		let _ = std::fs::remove_file(&path);
+
+		assert_eq!(classes["warrior"], ClassInfo { name: String::from("Воин"), hp: 150, mana: 20 });
+		// Missing fields fall back to the id and 100
+		assert_eq!(classes["mage"], ClassInfo { name: String::from("mage"), hp: 80, mana: 100 });
+		assert_ne!(classes["warrior"].hp, classes["mage"].hp);
+
+		// Without the file any class goes
+		assert!(Config::loadClasses().is_empty());
+	}
+}
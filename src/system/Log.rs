@@ -0,0 +1,137 @@
+use std::{fmt::Display, io::Write, sync::atomic::{AtomicU8, Ordering}};
+
+use super::RES_DIR;
+use super::State::State;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level
+{
+	Error,
+	Warn,
+	Info,
+	Debug
+}
+
+impl Level
+{
+	pub fn fromString(x: &str) -> Self
+	{
+		if x == "error" { return Level::Error; }
+		if x == "warn" { return Level::Warn; }
+		if x == "debug" { return Level::Debug; }
+		Level::Info
+	}
+
+	pub fn toString(&self) -> String
+	{
+		match self
+		{
+			Level::Error => String::from("error"),
+			Level::Warn => String::from("warn"),
+			Level::Info => String::from("info"),
+			Level::Debug => String::from("debug")
+		}
+	}
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn setLevel(lvl: Level)
+{
+	LEVEL.store(lvl as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level
+{
+	match LEVEL.load(Ordering::Relaxed)
+	{
+		0 => Level::Error,
+		1 => Level::Warn,
+		2 => Level::Info,
+		_ => Level::Debug
+	}
+}
+
+// The printed line, None if `lvl` is above `limit`
+fn line(lvl: Level, limit: Level, msg: impl Display) -> Option<String>
+{
+	if lvl > limit { return None; }
+	Some(format!("[{}] [{}] {msg}", lvl.toString().to_uppercase(), State::getDateTime()))
+}
+
+fn write(lvl: Level, msg: impl Display)
+{
+	if let Some(x) = line(lvl, level(), msg) { println!("{x}"); }
+}
+
+pub fn error(msg: impl Display) { write(Level::Error, msg); }
+pub fn warn(msg: impl Display) { write(Level::Warn, msg); }
+pub fn info(msg: impl Display) { write(Level::Info, msg); }
+pub fn debug(msg: impl Display) { write(Level::Debug, msg); }
+
+// Commands run through Server::cmd, one JSON object per line, never truncated
+fn auditPath() -> String
+{
+	format!("{RES_DIR}/audit.log")
+}
+
+pub fn audit(executor: &str, permission: &str, command: &str, outcome: &str)
+{
+	let entry = json::object!
+	{
+		time: State::getDateTime(),
+		executor: executor,
+		permission: permission,
+		command: command,
+		outcome: outcome
+	};
+	let _ = std::fs::create_dir_all(RES_DIR);
+	let file = std::fs::OpenOptions::new().create(true).append(true).open(auditPath());
+	let result = file.and_then(|mut f| writeln!(f, "{}", json::stringify(entry)));
+	if let Err(x) = result { error(format!("Failed to write the audit log: {x}")); }
+}
+
+// The last `count` audit entries, oldest first
+pub fn auditTail(count: usize) -> json::JsonValue
+{
+	let file = std::fs::read_to_string(auditPath()).unwrap_or_default();
+	let lines = file.lines().filter(|x| !x.is_empty()).collect::<Vec<&str>>();
+	let mut list = json::JsonValue::new_array();
+	for line in &lines[lines.len().saturating_sub(count)..]
+	{
+		if let Ok(entry) = json::parse(line) { let _ = list.push(entry); }
+	}
+	list
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn auditKeepsEveryCommand()
+	{
+		let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+		let command = format!("kick test{stamp}");
+		audit("Admin", "admin", &command, "ok");
+		audit("Guest", "guest", &command, "denied");
+		let tail = auditTail(1000);
+		let entries = tail.members().filter(|x| x["command"] == command.as_str()).collect::<Vec<_>>();
+		assert_eq!(entries.len(), 2);
+		assert_eq!(entries[0]["executor"], "Admin");
+		assert_eq!(entries[1]["outcome"], "denied");
+		assert_eq!(auditTail(1).len(), 1);
+	}
+
+	#[test]
+	fn warnLevelHidesInfo()
+	{
+		let limit = Level::fromString("warn");
+		assert_eq!(line(Level::Info, limit, "скрыто"), None);
+		assert_eq!(line(Level::Debug, limit, "скрыто"), None);
+		assert!(line(Level::Warn, limit, "видно").is_some_and(|x| x.starts_with("[WARN] [") && x.ends_with("] видно")));
+		assert!(line(Level::Error, limit, "видно").is_some_and(|x| x.starts_with("[ERROR]")));
+		assert_eq!(Level::fromString("что-то"), Level::Info);
+	}
+}
@@ -1,55 +1,176 @@
-use std::time::Instant;
-use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::time::{Duration, Instant};
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::{Mutex, MutexGuard, OnceLock};
 
 use super::WebClient::WebClient;
-use super::Transmission::{ClientMessage, ServerMessage, WebResponse};
-use super::State::State;
+use super::Transmission::{ClientMessage, PlayerStatePacket, ServerMessage, WebResponse, CAP_ACK, PROTOCOL_VERSION};
+use super::State::{ChatKind, State};
 use super::Config::{Config, Permission};
 use super::Client::Client;
+use super::Log;
+use super::RES_DIR;
 
 pub struct Server
 {
-	listener: TcpListener,
-	webListener: TcpListener,
+	// Sockets are dropped on shutdown, so the ports are free before the process exits
+	listener: Option<TcpListener>,
+	webListener: Option<TcpListener>,
+	// Shared port sockets not yet told apart, with the time they came in
+	pending: Vec<(TcpStream, Instant)>,
+	// Players whose connection dropped, by reconnection token
+	recoverable: HashMap<u64, (Client, Instant)>,
+	// Last call of a command with a cooldown, by executor name and command
+	commandTimes: HashMap<(String, String), Instant>,
 	webClient: WebClient,
 	clients: Vec<Client>,
 	config: Config,
 	state: State,
 	requests: Vec<(u8, ServerMessage)>,
 	broadcast: Vec<ClientMessage>,
-	udp: UdpSocket,
+	// Replies to the web client for the command being run
+	webReplies: Vec<String>,
+	udp: Option<UdpSocket>,
 	playersState: Vec<[u8; 9]>,
 	sendTimer: Instant,
-	recvTimer: Instant
+	autosaveTimer: Instant,
+	keyframeTimer: Instant,
+	recvTimer: Instant,
+	startTime: Instant,
+	// Durations of the last TICK_WINDOW updates
+	tickTimes: VecDeque<Duration>,
+	// UDP packets accepted in the current second and the last full second
+	udpCount: (u32, u32),
+	udpTimer: Instant,
+	rng: Rng,
+	running: bool
+}
+
+const TICK_WINDOW: usize = 100;
+
+// Shared port: a game client waits for the server to speak first, so a
+// socket silent this long is one. At most MAX_PENDING sockets wait at once
+const PEEK_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_PENDING: usize = 32;
+
+// Limits of /roll: dice count and sides
+const MAX_DICE: u32 = 20;
+const MAX_SIDES: u32 = 1000;
+
+// In characters, not bytes
+const MAX_NAME_LENGTH: usize = 24;
+
+// The web client acts under WEB_NAME with developer rights given by its ID 0,
+// so players can't register it or the name it had before
+const WEB_NAME: &str = "Центр мира";
+const RESERVED_NAMES: &[&str] = &[WEB_NAME, "WebClient"];
+
+// Commands which answer the web client with their own response
+const WEB_REPLIES: &[&str] = &["players", "help", "whois", "clearchat", "reload", "stop"];
+
+// Chat commands shown by /help: name, description and the group required
+const COMMANDS: &[(&str, &str, Permission)] = &[
+	("help", "список доступных команд", Permission::Guest),
+	("players", "список игроков онлайн", Permission::Guest),
+	("version", "версия сервера и протокола", Permission::Guest),
+	("gettime", "текущее время сервера", Permission::Player),
+	("roll <NdM>", "бросить кубики, например 2d6", Permission::Player),
+	("team <номер>", "вступить в команду, 0 - выйти", Permission::Player),
+	("resync [игрок]", "запросить полное состояние мира", Permission::Player),
+	("w <игрок> <текст>", "личное сообщение", Permission::Guest),
+	("getposition [игрок]", "координаты игрока", Permission::Admin),
+	("setposition <игрок> <x> <y>", "переместить игрока", Permission::Admin),
+	("tp <кого> <к кому>", "переместить игрока к другому", Permission::Admin),
+	("teamsay <команда> <текст>", "сообщение команде", Permission::Admin),
+	("conninfo [игрок]", "состояние подключения", Permission::Admin),
+	("whois <игрок>", "сведения об игроке", Permission::Admin),
+	("kick <игрок>", "выгнать игрока", Permission::Admin),
+	("kickid <номер>", "выгнать игрока по номеру", Permission::Admin),
+	("ban <игрок>", "заблокировать игрока", Permission::Admin),
+	("unban <ip или игрок>", "разблокировать игрока", Permission::Admin),
+	("mute <игрок> [секунды]", "запретить писать в чат", Permission::Admin),
+	("unmute <игрок>", "разрешить писать в чат", Permission::Admin),
+	("trail [игрок]", "последние перемещения", Permission::Admin),
+	("clearchat", "очистить чат", Permission::Admin),
+	("save [чекпоинт]", "сохранить игру", Permission::Admin),
+	("timing <отправка> <приём>", "частота обновлений", Permission::Developer),
+	("perm <игрок> [группа]", "узнать или изменить права игрока", Permission::Developer),
+	("reload", "перезагрузить настройки", Permission::Developer),
+	("reloadperms", "перезагрузить права", Permission::Developer),
+	("stop", "остановить сервер", Permission::Developer)
+];
+
+// xorshift64, seeded from Config.rngSeed for reproducible sessions
+pub struct Rng(u64);
+
+impl Rng
+{
+	pub fn new(seed: u64) -> Self
+	{
+		// Zero would stay zero forever
+		Self((seed ^ 0x9E37_79B9_7F4A_7C15).max(1))
+	}
+
+	pub fn next(&mut self) -> u64
+	{
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
 }
 
 impl Server
 {
-	pub fn getInstance() -> &'static mut Server
+	// The guard must not be held across another getInstance call,
+	// the lock is not reentrant
+	pub fn getInstance() -> MutexGuard<'static, Server>
 	{
-		static mut INSTANCE: Option<Server> = None;
-		
-		unsafe
-		{
-			if INSTANCE.is_none() { INSTANCE = Some(Self::init()); }
-			INSTANCE.as_mut().expect("Server singleton is not initialized")
-		}
+		static INSTANCE: OnceLock<Mutex<Server>> = OnceLock::new();
+
+		// A panic in another thread leaves the server usable
+		INSTANCE.get_or_init(|| Mutex::new(Self::init()))
+			.lock()
+			.unwrap_or_else(|x| x.into_inner())
 	}
 
 	pub fn init() -> Self
 	{
 		let config = Config::init();
+		Log::setLevel(config.logLevel);
 		let state = State::init();
 
-		let listener = TcpListener::bind(String::from("0.0.0.0:") + &config.port.to_string());
+		let mut server = Server::new(config, state);
+		server.preflight();
+		Log::info(server.banner());
+		server
+	}
+
+	// Binds the sockets of the config, nothing is read from or written to res/
+	pub fn new(config: Config, state: State) -> Self
+	{
+		let ip = match config.bindIP()
+		{
+			Ok(x) => x,
+			Err(x) => panic!("Invalid bindAddress: {x}")
+		};
+		let listener = TcpListener::bind(SocketAddr::new(ip, config.port));
 		if listener.is_err() { panic!("Failed to create listener: {:?}", listener.unwrap_err()); }
 		let listener = listener.unwrap();
 		let _ = listener.set_nonblocking(true);
 
-		let webListener = TcpListener::bind("0.0.0.0:8080");
-		if webListener.is_err() { panic!("Failed to create web listener: {:?}", webListener.unwrap_err()); }
-		let webListener = webListener.unwrap();
-		let _ = webListener.set_nonblocking(true);
+		let webListener =
+			if config.sharedPort { None }
+			else
+			{
+				let webListener = TcpListener::bind(SocketAddr::new(ip, config.webPort));
+				if webListener.is_err() { panic!("Failed to create web listener: {:?}", webListener.unwrap_err()); }
+				let webListener = webListener.unwrap();
+				let _ = webListener.set_nonblocking(true);
+				Some(webListener)
+			};
 
 		let mut clients = vec![];
 		clients.resize_with(config.maxPlayersCount as usize, || { Client::default() });
@@ -57,7 +178,7 @@ impl Server
 		let mut playersState = vec![];
 		playersState.resize(config.maxPlayersCount as usize, [0u8; 9]);
 
-		let udp = UdpSocket::bind("0.0.0.0:0");
+		let udp = UdpSocket::bind(SocketAddr::new(ip, 0));
 		if udp.is_err()
 		{
 			panic!("Failed to bind UDP: {:?}", udp.unwrap_err());
@@ -65,219 +186,667 @@ impl Server
 		let udp = udp.unwrap();
 		let _ = udp.set_nonblocking(true);
 
-		println!("TCP Listener: {}", listener.local_addr().unwrap());
-		println!("UDP Socket: {}", udp.local_addr().unwrap());
+		let rng = Rng::new(config.rngSeed.unwrap_or_else(Server::newToken));
 
 		Self
 		{
-			listener,
+			listener: Some(listener),
 			webListener,
+			pending: vec![],
+			recoverable: HashMap::new(),
+			commandTimes: HashMap::new(),
 			webClient: WebClient::new(),
 			clients,
 			config,
 			state,
 			requests: vec![],
 			broadcast: vec![],
-			udp,
+			webReplies: vec![],
+			udp: Some(udp),
 			playersState,
 			sendTimer: Instant::now(),
-			recvTimer: Instant::now()
+			autosaveTimer: Instant::now(),
+			keyframeTimer: Instant::now(),
+			recvTimer: Instant::now(),
+			startTime: Instant::now(),
+			tickTimes: VecDeque::new(),
+			udpCount: (0, 0),
+			udpTimer: Instant::now(),
+			rng,
+			running: true
 		}
 	}
 
-	pub fn listen(&mut self)
+	// Reports the files the server relies on as present, missing or created.
+	// A missing save is normal for a new world, a missing web root is not.
+	pub fn preflight(&mut self) -> Vec<(String, &'static str)>
 	{
-		if let Ok((tcp, addr)) = self.listener.accept()
+		let exists = |x: &str| std::path::Path::new(x).exists();
+		let mut report = vec![];
+
+		let config = format!("{RES_DIR}/config.json");
+		if exists(&config) { report.push((config, "present")); }
+		else
 		{
-			let id = self.getAvailablePlayerID();
-			println!("New client: {addr}. Trying ID {id}...");
-			if id != 0
+			self.config.save();
+			report.push((config.clone(), if exists(&config) { "created" } else { "missing" }));
+		}
+
+		for path in [State::path("default"), format!("{RES_DIR}/classes.json")]
+		{
+			let status = if exists(&path) { "present" } else { "missing" };
+			report.push((path, status));
+		}
+
+		let webRoot = self.config.webRoot.clone();
+		if std::path::Path::new(&webRoot).is_dir() { report.push((webRoot, "present")); }
+		else
+		{
+			Log::warn(format!("Web root {webRoot} is not a directory, web files can't be served"));
+			report.push((webRoot, "missing"));
+		}
+
+		// Ports, tick rate and player limit are in the banner
+		for (path, status) in &report { Log::info(format!("{path}: {status}")); }
+		report
+	}
+
+	// With port 0 in the config this is where the system put the listener
+	pub fn tcpAddr(&self) -> std::io::Result<SocketAddr>
+	{
+		match &self.listener
+		{
+			Some(x) => x.local_addr(),
+			None => Err(ErrorKind::NotConnected.into())
+		}
+	}
+
+	pub fn udpAddr(&self) -> std::io::Result<SocketAddr>
+	{
+		match &self.udp
+		{
+			Some(x) => x.local_addr(),
+			None => Err(ErrorKind::NotConnected.into())
+		}
+	}
+
+	// None in shared port mode, the web client uses tcpAddr then
+	pub fn webAddr(&self) -> Option<std::io::Result<SocketAddr>>
+	{
+		self.webListener.as_ref().map(|x| x.local_addr())
+	}
+
+	pub fn banner(&self) -> String
+	{
+		let addr = |x: std::io::Result<SocketAddr>|
+			x.map(|a| a.to_string()).unwrap_or(String::from("?"));
+		let mut features = vec!["web"];
+		if self.config.sharedPort { features.push("shared port"); }
+		if self.config.strictUdp { features.push("strict UDP"); }
+
+		format!("{} v{}\n\
+			TCP: {}, UDP: {}, web: {}\n\
+			Tick rate: {}, max players: {}\n\
+			Features: {}",
+			self.config.name, env!("CARGO_PKG_VERSION"),
+			addr(self.tcpAddr()),
+			addr(self.udpAddr()),
+			match self.webAddr()
+			{
+				Some(web) => addr(web),
+				None => String::from("shared")
+			},
+			self.config.tickRate, self.config.maxPlayersCount,
+			features.join(", ")
+		)
+	}
+
+	// Players accepted here count as heard from at `now`. The peek on
+	// the shared port waits for the socket itself, so it keeps real time
+	pub fn listen(&mut self, now: Instant)
+	{
+		if let Some(listener) = &self.listener && let Ok((tcp, addr)) = listener.accept()
+		{
+			if self.config.sharedPort && self.pending.len() >= MAX_PENDING
+			{
+				Log::warn(format!("Too many pending connections, {addr} was rejected."));
+			}
+			else if self.config.sharedPort
 			{
-				let (name, class) = self.state.getPlayerInfo(addr.ip());
-				if name == "noname" { println!("Unknown client."); }
-				else { println!("Player {name} connected as P{}.", id); }
+				let _ = tcp.set_nonblocking(true);
+				self.pending.push((tcp, Instant::now()));
+			}
+			else { self.acceptPlayer(tcp, addr, now); }
+		}
 
-				self.clients[(id - 1) as usize] = Client::connect(
-					tcp,
-					id,
-					name.clone(),
-					class
-				);
+		// In shared port mode the first bytes tell HTTP from the game protocol
+		for (tcp, since) in std::mem::take(&mut self.pending)
+		{
+			let buffer = &mut [0u8; 8];
+			let result = tcp.peek(buffer);
+			// Nothing to tell apart, a game client waits for its Login
+			if matches!(&result, Err(x) if x.kind() == ErrorKind::WouldBlock) && since.elapsed() >= PEEK_TIMEOUT
+			{
+				if let Ok(addr) = tcp.peer_addr() { self.acceptPlayer(tcp, addr, now); }
+				continue;
+			}
+			match result
+			{
+				Ok(0) => {},
+				Ok(size) =>
+				{
+					let head = String::from_utf8_lossy(&buffer[0..size]).to_string();
+					let methods = ["GET ", "POST ", "PUT ", "DELETE ", "HEAD ", "OPTIONS ", "PATCH "];
+					let http = methods.iter().any(|m| head.starts_with(m));
+					// Not enough bytes yet to tell a method from a frame
+					if !http && methods.iter().any(|m| m.len() > size && m.starts_with(&head))
+					{
+						if since.elapsed() < PEEK_TIMEOUT { self.pending.push((tcp, since)); }
+						continue;
+					}
+					match tcp.peer_addr()
+					{
+						Ok(_) if http => self.webClient.connect(tcp, &self.config),
+						Ok(addr) => self.acceptPlayer(tcp, addr, now),
+						Err(_) => {}
+					}
+				},
+				Err(x) if x.kind() == ErrorKind::WouldBlock => self.pending.push((tcp, since)),
+				Err(_) => {}
 			}
 		}
 
-		for client in self.webListener.incoming()
+		if let Some(web) = &self.webListener
 		{
-			match client
+			for client in web.incoming()
 			{
-				Ok(tcp) => self.webClient.connect(tcp),
-				Err(_) => break
+				match client
+				{
+					Ok(tcp) => self.webClient.connect(tcp, &self.config),
+					Err(_) => break
+				}
 			}
 		}
 	}
 
+	fn acceptPlayer(&mut self, tcp: TcpStream, addr: SocketAddr, now: Instant)
+	{
+		if self.state.banned.contains(&addr.ip())
+		{
+			Log::warn(format!("Banned client {addr} was rejected."));
+			return;
+		}
+		let id = self.getAvailablePlayerID();
+		Log::info(format!("New client: {addr}. Trying ID {id}..."));
+		if id != 0
+		{
+			let (name, class) = self.state.getPlayerInfo(addr.ip());
+			if name == "noname" { Log::info("Unknown client."); }
+			else { Log::info(format!("Player {name} connected as P{}.", id)); }
+
+			self.clients[(id - 1) as usize] = Client::connect(
+				tcp,
+				id,
+				name.clone(),
+				class.clone(),
+				self.config.recvTime
+			);
+			let c = &mut self.clients[(id - 1) as usize];
+			c.lastSeen = now;
+			c.connectedAt = now;
+			if let Some(info) = self.config.classes.get(&class) { c.setClass(class, info); }
+			c.inventory = self.state.getInventory(addr.ip());
+			if !c.inventory.is_empty() { c.sendTCP(ClientMessage::Inventory(c.inventory.clone())); }
+		}
+	}
+
 	pub fn update(&mut self)
 	{
-		if self.recvTimer.elapsed() > self.config.recvTime
+		self.step(Instant::now());
+	}
+
+	// One tick with the timers compared against `now`
+	pub fn step(&mut self, now: Instant)
+	{
+		let tickStart = Instant::now();
+		for c in &mut self.clients
 		{
-			for msg in self.webClient.update()
+			if let Err(x) = c.flushTCP()
 			{
-				self.requests.push((0, msg));
+				Log::debug(format!("Failed to send data to P{}: {x}", c.id));
+				self.requests.push((c.id, ServerMessage::Disconnected));
 			}
-	
-			for c in &mut self.clients
+		}
+
+		if now.saturating_duration_since(self.recvTimer) > self.config.recvTime
+		{
+			self.pollWeb();
+			self.pollTcp(now);
+			self.pollUdp(now);
+			self.recvTimer = now;
+		}
+		
+		self.handleRequests(now);
+		self.expireReconnects(now);
+		self.dropSilentClients(now);
+		self.broadcastTCP();
+
+		if now.saturating_duration_since(self.sendTimer) > self.config.sendTime
+		{
+			self.broadcastState(now);
+			self.sendTimer = now;
+		}
+
+		for i in 0..self.clients.len()
+		{
+			if self.clients[i].resync { self.sendKeyframe(i, now); }
+		}
+		self.sendKeepalives(now);
+		self.sendPings(now);
+
+		// Zero interval turns autosaves off
+		if !self.config.autosaveInterval.is_zero()
+			&& now.saturating_duration_since(self.autosaveTimer) > self.config.autosaveInterval
+		{
+			self.storePositions();
+			match self.state.autosave(self.config.maxBackups)
 			{
-				if c.tcp.is_none() { continue; }
-				if let Some(req) = c.receiveTCP()
-				{
-					self.requests.push((c.id, req));
-				}
+				Ok(_) => Log::debug("Autosaved."),
+				Err(x) => Log::error(format!("Failed to autosave: {x}"))
 			}
-	
-			'udp: loop
+			self.autosaveTimer = now;
+		}
+
+		if now.saturating_duration_since(self.udpTimer) >= Duration::from_secs(1)
+		{
+			self.udpCount = (0, self.udpCount.0);
+			self.udpTimer = now;
+		}
+		self.tickTimes.push_back(tickStart.elapsed());
+		if self.tickTimes.len() > TICK_WINDOW { self.tickTimes.pop_front(); }
+	}
+
+	pub fn pollWeb(&mut self)
+	{
+		for msg in self.webClient.update(&mut self.config)
+		{
+			self.requests.push((0, msg));
+		}
+	}
+
+	pub fn pollTcp(&mut self, now: Instant)
+	{
+		for c in &mut self.clients
+		{
+			if c.tcp.is_none() { continue; }
+			for req in c.receiveTCP(self.config.maxMessagesPerTick, now)
+			{
+				self.requests.push((c.id, req));
+			}
+		}
+	}
+
+	pub fn pollUdp(&mut self, now: Instant)
+	{
+		'udp: loop
+		{
+			let buffer = &mut [0u8; 128];
+			let Some(udp) = &self.udp else { return; };
+			match udp.recv_from(buffer)
 			{
-				let buffer = &mut [0u8; 128];
-				match self.udp.recv_from(buffer)
+				Ok((size, addr)) =>
 				{
-					Ok((size, addr)) =>
+					let packet = match PlayerStatePacket::decode(&buffer[..size])
 					{
-						if size != 9 { continue; }
-						let id = buffer[0] & 0b00_00_01_11;
-						if self.clients[(id - 1) as usize].udp.is_none()
+						Ok(x) => x,
+						Err(x) =>
 						{
-							self.clients[(id - 1) as usize].udp = Some(addr);
+							// Still proves the client's NAT mapping is alive
+							if let Some(c) = self.clients.iter_mut().find(|c| c.id != 0 && c.udp == Some(addr))
+							{
+								c.lastUdp = Some(now);
+								c.lastSeen = now;
+							}
+							Log::debug(format!("Dropped UDP packet from {addr}: {x}"));
+							continue;
 						}
-						self.playersState[(id - 1) as usize] = [buffer[0],
-							buffer[1], buffer[2],
-							buffer[3], buffer[4],
-							buffer[5], buffer[6],
-							buffer[7], buffer[8]
-						];
-					},
-					Err(_) => { break 'udp; }
-				}
+					};
+					let id = packet.id;
+					if id == 0 || id > self.config.maxPlayersCount || self.clients[(id - 1) as usize].id == 0
+					{
+						Log::debug(format!("Dropped UDP packet for empty slot P{id} from {addr}"));
+						continue;
+					}
+					if !self.checkUdpSource(id, addr)
+					{
+						Log::debug(format!("Dropped UDP packet for P{id} from unexpected {addr}"));
+						continue;
+					}
+					let c = &mut self.clients[(id - 1) as usize];
+					if !c.acceptSequence(packet.sequence) { continue; }
+					c.lastUdp = Some(now);
+					c.lastSeen = now;
+					self.udpCount.0 += 1;
+					c.addTrail(packet.x, packet.y);
+					self.playersState[(id - 1) as usize] = packet.state();
+					self.markState(id);
+				},
+				Err(_) => { break 'udp; }
 			}
-			self.recvTimer = Instant::now();
-		}
-		
-		self.handleRequests();
-		self.broadcastTCP();
-
-		if self.sendTimer.elapsed() > self.config.sendTime
-		{
-			self.broadcastState();
-			self.sendTimer = Instant::now();
 		}
 	}
 
-	fn handleRequests(&mut self)
+	fn handleRequests(&mut self, now: Instant)
 	{
 		for (id, msg) in self.requests.clone()
 		{
+			if id != 0
+			{
+				// The slot was freed by an earlier request of this batch. A failed
+				// read drops the socket too, but its Disconnected must be handled.
+				let c = &self.clients[(id - 1) as usize];
+				if c.id == 0 || (c.tcp.is_none() && !matches!(msg, ServerMessage::Disconnected)) { continue; }
+			}
 			match msg
 			{
-				ServerMessage::Invalid(web) =>
+				// Web requests are rejected by the web client with the reason
+				ServerMessage::Invalid => Log::debug(format!("P{id} sent an invalid request")),
+				ServerMessage::Register(name, caps, class) =>
 				{
-					if id == 0
+					let token = Server::newToken();
+					// Nulls are refused by fromRaw, other control characters are dropped
+					let mut name = name.chars().filter(|x| !x.is_control()).collect::<String>().trim().to_string();
+					let taken = |n: &str| self.clients.iter().chain(self.recoverable.values().map(|(c, _)| c))
+						.any(|c| c.id != 0 && c.id != id && c.name.to_lowercase() == n.to_lowercase());
+					if !name.is_empty() && taken(&name) && self.config.renameDuplicates
 					{
-						WebClient::sendResponse(web, WebResponse::Ok(
-							String::from("{ \"error\": \"Invalid or unknown request\" }"),
-							String::from("text/json")
-						));
+						let mut n = 2;
+						while taken(&format!("{name}{n}")) { n += 1; }
+						name = format!("{name}{n}");
 					}
-				},
-				ServerMessage::Register(name) =>
-				{
+					let class = class.chars().filter(|x| !x.is_control()).collect::<String>().trim().to_string();
+					let class = if class.is_empty() { self.fallbackClass(id) } else { class };
+					let info = self.config.classes.get(&class).cloned();
+					let error =
+						if name.is_empty() { Some(String::from("Имя не может быть пустым")) }
+						else if RESERVED_NAMES.iter().any(|x| x.to_lowercase() == name.to_lowercase())
+						{
+							Some(String::from("Это имя зарезервировано"))
+						}
+						else if taken(&name) { Some(String::from("Это имя уже занято")) }
+						else if name.chars().count() > MAX_NAME_LENGTH
+						{
+							Some(format!("Имя не может быть длиннее {MAX_NAME_LENGTH} символов"))
+						}
+						else if info.is_none() && !self.config.classes.is_empty()
+						{
+							Some(format!("Неизвестный класс: {class}"))
+						}
+						else { None };
 					let c = &mut self.clients[(id - 1) as usize];
+					c.acks = caps & CAP_ACK != 0;
+					if let Some(error) = error
+					{
+						Log::warn(format!("P{id} tried to register an invalid name or class."));
+						c.sendTCP(ClientMessage::Chat(error));
+						self.ack(id, 1, false);
+						continue;
+					}
 					c.name = name.clone();
+					match &info
+					{
+						Some(info) => c.setClass(class.clone(), info),
+						None => c.class = class.clone()
+					}
 
 					c.sendTCP(ClientMessage::Login(
-						id, name.clone(), String::from("unknown"),
+						id, name.clone(), class.clone(),
 					));
+					c.token = token;
+					c.sendTCP(ClientMessage::Token(token));
+
+					let ip = c.tcp.as_mut().unwrap().peer_addr().unwrap().ip();
+					self.state.setPlayerInfo(ip, name.clone(), class.clone());
+					if let Some(info) = &info
+					{
+						let c = &self.clients[(id - 1) as usize];
+						self.broadcast.push(ClientMessage::Stats(id, c.hp, c.mana));
+						Log::debug(format!("P{id} plays as {}.", info.name));
+					}
+
+					// Puts the player where they were at the last save
+					if let Some((x, y)) = self.state.getPosition(ip)
+					{
+						self.clients[(id - 1) as usize].sendTCP(ClientMessage::SetPosition(x, y));
+						let s = &mut self.playersState[(id - 1) as usize];
+						s[0] = (s[0] & 0b11_11_10_00) | id;
+						s[1..3].copy_from_slice(&x.to_le_bytes());
+						s[3..5].copy_from_slice(&y.to_le_bytes());
+						self.markState(id);
+					}
+
+					if !self.config.motd.is_empty()
+					{
+						let players = self.clients.iter().filter(|c| c.id != 0).count();
+						let motd = self.config.motd
+							.replace("{name}", &name)
+							.replace("{players}", &players.to_string());
+						self.clients[(id - 1) as usize].sendTCP(ClientMessage::Chat(motd));
+					}
 
-					self.state.setPlayerInfo(
-						c.tcp.as_mut().unwrap().peer_addr().unwrap().ip(),
-						name.clone(), String::from("unknown")
-					);
+					Log::info(format!("Welcome, {name}(P{id})!"));
 
-					println!("Welcome, {name}(P{id})!");
+					let class = self.clients[(id - 1) as usize].class.clone();
+					for c in &mut self.clients
+					{
+						if c.id == 0 || c.id == id { continue; }
+						c.sendTCP(ClientMessage::Joined(id, name.clone(), class.clone()));
+					}
+					let list = self.clients.iter()
+						.filter(|c| c.id != 0)
+						.map(|c| (c.id, c.name.clone(), c.class.clone()))
+						.collect();
+					self.clients[(id - 1) as usize].sendTCP(ClientMessage::PlayersList(list));
+					self.ack(id, 1, true);
 				},
 				ServerMessage::Disconnected =>
 				{
-					if id != 0
+					if id == 0 { continue; }
+					let c = &self.clients[(id - 1) as usize];
+					if c.token != 0 && !self.config.reconnectGrace.is_zero()
+					{
+						Log::info(format!("P{id} lost connection, the slot is kept for reconnection."));
+						let mut c = std::mem::replace(&mut self.clients[(id - 1) as usize], Client::default());
+						c.tcp = None;
+						self.recoverable.insert(c.token, (c, now));
+					}
+					else
 					{
-						println!("P{} disconnected.", id);
-						self.clients[(id - 1) as usize] = Client::default();
-						self.playersState[(id - 1) as usize][0] = id;
-						self.broadcast.push(ClientMessage::Disconnected(id));
+						Log::info(format!("P{} disconnected.", id));
+						self.disconnect(id);
 					}
 				},
+				ServerMessage::AddItem(item, count) =>
+				{
+					if id == 0 { continue; }
+					self.clients[(id - 1) as usize].addItem(item, count);
+					self.syncInventory(id);
+				},
+				ServerMessage::RemoveItem(item, count) =>
+				{
+					if id == 0 { continue; }
+					self.clients[(id - 1) as usize].removeItem(item, count);
+					self.syncInventory(id);
+				},
+				ServerMessage::Inventory(name, web) =>
+				{
+					let id = self.getPlayerID(&name);
+					let items =
+						if id != 0 { Some(self.clients[(id - 1) as usize].inventory.clone()) }
+						else { self.state.getPlayerIP(&name).map(|ip| self.state.getInventory(ip)) };
+					let msg = match items
+					{
+						Some(items) =>
+						{
+							let mut list = json::JsonValue::new_array();
+							for (item, count) in items
+							{
+								let _ = list.push(json::object! { item: item, count: count });
+							}
+							list
+						},
+						None => json::object! { error: format!("Игрок {name} не найден") }
+					};
+					self.respond(web, WebResponse::Ok(json::stringify(msg), "text/json".to_string()));
+				},
+				ServerMessage::Reconnect(token) =>
+				{
+					if id == 0 { continue; }
+					self.reconnect(id, token, now);
+				},
+				ServerMessage::Pong(stamp) =>
+				{
+					if id == 0 { continue; }
+					let rtt = self.stamp(now).wrapping_sub(stamp);
+					// An answer from the future is a broken or lying client
+					if rtt > u32::MAX / 2 { continue; }
+					self.clients[(id - 1) as usize].rtt = Some(Duration::from_millis(rtt as u64));
+				},
 				ServerMessage::Chat(msg, web) =>
 				{
-					println!("P{id}: {msg}");
-					let mut text = msg.clone();
-					let c = text.remove(0);
-					if c == '/' { self.cmd(id, web, text); }
+					if msg.trim().is_empty() || msg.trim() == "/"
+					{
+						if id == 0
+						{
+							self.respond(web, WebResponse::Ok(
+								String::from("{ \"error\": \"Empty message\" }"),
+								String::from("text/json")
+							));
+						}
+						self.ack(id, 2, false);
+						continue;
+					}
+					if id != 0
+					{
+						let c = &mut self.clients[(id - 1) as usize];
+						if c.lastChat.is_some_and(|t| now.saturating_duration_since(t) < self.config.chatCooldown)
+						{
+							c.sendTCP(ClientMessage::Chat(String::from("Вы отправляете сообщения слишком часто")));
+							self.ack(id, 2, false);
+							continue;
+						}
+						c.lastChat = Some(now);
+					}
+					Log::info(format!("P{id}: {msg}"));
+					// strip_prefix cuts on a char boundary, so Cyrillic or emoji right after
+					// the slash can't split a multibyte character
+					if let Some(text) = msg.strip_prefix('/')
+					{
+						self.cmd(id, web, text.to_string());
+						self.ack(id, 2, true);
+					}
+					else if id != 0 && self.clients[(id - 1) as usize].isMuted()
+					{
+						self.clients[(id - 1) as usize].sendTCP(ClientMessage::Chat(
+							String::from("Вы не можете писать в чат")
+						));
+						self.ack(id, 2, false);
+					}
 					else
 					{
-						let n =
-							if id == 0 { String::from("WebClient") }
-							else { self.clients[(id - 1) as usize].name.clone() };
+						let n = self.executor(id).0;
 						self.broadcast.push(ClientMessage::Chat(n.clone() + ": " + &msg));
-						self.state.chatHistory.push((n.clone(), msg.clone()));
+						self.addChat(n.clone(), msg.clone(), ChatKind::User);
 						if id == 0
 						{
-							WebClient::sendResponse(web, WebResponse::Ok(
+							self.respond(web, WebResponse::Ok(
 								String::from("{ \"msg\": \"") + &n + ": " + &msg + "\" }",
 								"text/json".to_string()
 							));
 						}
+						self.ack(id, 2, true);
 					}
 				},
 				ServerMessage::PlayersList(web) =>
 				{
-					let mut obj = json::JsonValue::new_array();
-
-					for c in &self.clients
+					if id != 0
 					{
-						if c.id == 0 { continue; }
-
-						let _ = obj.push(json::object!
-						{
-							id: c.id,
-							className: c.class.clone(),
-							name: c.name.clone(),
-							hp: { current: 100, max: 100 },
-							mana: { current: 100, max: 100 }
-						});
+						let list = self.clients.iter()
+							.filter(|c| c.id != 0)
+							.map(|c| (c.id, c.name.clone(), c.class.clone()))
+							.collect();
+						self.clients[(id - 1) as usize].sendTCP(ClientMessage::PlayersList(list));
+						continue;
 					}
 
-					WebClient::sendResponse(web, WebResponse::Ok(
-						json::stringify(obj), "text/json".to_string()
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.playersListJson()), "text/json".to_string()
 					));
 				},
-				ServerMessage::SaveGame(checkpoint) =>
+				ServerMessage::Health(verbose, web) =>
+				{
+					let mut health = self.health();
+					if verbose
+					{
+						let _ = health.insert("webConnections", self.webClient.connections.len());
+						let _ = health.insert("webSockets", self.webClient.sockets.len());
+					}
+					self.respond(web, WebResponse::Ok(json::stringify(health), String::from("text/json")));
+				},
+				ServerMessage::Metrics(web) =>
+				{
+					let metrics = self.metrics();
+					self.respond(web, WebResponse::Ok(metrics, String::from("text/plain; version=0.0.4")));
+				},
+				ServerMessage::SaveGame(mut checkpoint, mut slot, web) =>
 				{
-					println!("Game saved on {checkpoint}.");
-					self.save(checkpoint);
+					if checkpoint.is_empty() { checkpoint = self.state.checkpoint.clone(); }
+					if slot.is_empty() { slot = self.state.slot.clone(); }
+					let result =
+						if State::isValidSlot(&slot) { self.save(slot, checkpoint.clone()) }
+						else { Err(std::io::Error::new(ErrorKind::InvalidInput, "invalid slot name")) };
+					match &result
+					{
+						Ok(_) => Log::info(format!("Game saved on {checkpoint}.")),
+						Err(x) => Log::error(format!("Failed to save the game: {x}"))
+					}
+					self.ack(id, 3, result.is_ok());
+					if id == 0
+					{
+						self.respond(web, match result
+						{
+							Ok(_) => WebResponse::Ok(
+								json::stringify(json::object! { saved: true, checkpoint: checkpoint }),
+								"text/json".to_string()
+							),
+							Err(x) => WebResponse::ioError(&x)
+						});
+					}
 				},
-				ServerMessage::ChatHistory(mut start, web) =>
+				ServerMessage::ChatHistory(start, web) =>
 				{
-					if start > self.state.chatHistory.len() { start = 0; }
-					let count = self.state.chatHistory.len() - start;
+					// `start` is the number of messages the client already has,
+					// counted since the server start, trimmed ones included.
+					// The rest is returned newest first.
+					// Whispers are left out of both the count and the pages.
+					let shown = self.state.chatHistory.iter()
+						.filter(|x| x.kind != ChatKind::Whisper)
+						.collect::<Vec<_>>();
+					let trimmed = self.state.chatTotal.saturating_sub(shown.len());
 					let mut buf = json::JsonValue::new_array();
-					for i in start..self.state.chatHistory.len()
-					{
-						let (user, msg) = &self.state.chatHistory[
-							if count > 1 { self.state.chatHistory.len() - 1 - i }
-							else { i }
-						];
-						let mut obj = json::JsonValue::new_object();
-						let _ = obj.insert("user", user.clone());
-						let _ = obj.insert("msg", msg.clone());
-						let _ = buf.push(obj);
+					if start <= self.state.chatTotal
+					{
+						for entry in shown.iter().skip(start.saturating_sub(trimmed)).rev()
+						{
+							let mut obj = json::JsonValue::new_object();
+							let _ = obj.insert("user", entry.user.clone());
+							let _ = obj.insert("msg", entry.msg.clone());
+							let _ = obj.insert("type", entry.kind.toString());
+							let _ = obj.insert("time", entry.time.clone());
+							let _ = buf.push(obj);
+						}
 					}
-					WebClient::sendResponse(web, WebResponse::Ok(
+					self.respond(web, WebResponse::Ok(
 						json::stringify(buf), "text/json".to_string()
 					));
 				},
@@ -295,44 +864,21 @@ impl Server
 						}
 					});
 
-					WebClient::sendResponse(web, WebResponse::Ok(
+					self.respond(web, WebResponse::Ok(
 						json::stringify(msg), "text/json".to_string()
 					));
 				},
 				ServerMessage::ChatLength(web) =>
 				{
-					WebClient::sendResponse(web, WebResponse::Ok(
-						self.state.chatHistory.len().to_string(), "text/json".to_string()
+					self.respond(web, WebResponse::Ok(
+						self.state.chatTotal.to_string(), "text/json".to_string()
 					));
 				},
 				ServerMessage::GetSettings(web) =>
 				{
 					let mut msg = json::JsonValue::new_object();
 
-					let _ = msg.insert("Сервер", json::object!
-					{
-						maxPlayersCount: json::object!
-						{
-							type: "range",
-							name: "Количество игроков",
-							value: self.config.maxPlayersCount,
-							props: json::object! { min: 1, max: 10 }
-						},
-						port: json::object!
-						{
-							type: "range",
-							name: "Игровой порт",
-							value: self.config.port,
-							props: json::object! { min: 1024, max: u16::MAX }
-						},
-						tickRate: json::object!
-						{
-							type: "range",
-							name: "Частота обновления",
-							value: self.config.tickRate,
-							props: json::object! { min: 1, max: 100 }
-						}
-					});
+					let _ = msg.insert("Сервер", self.config.schema());
 
 					let mut perms = json::JsonValue::new_object();
 					
@@ -342,83 +888,600 @@ impl Server
 						{
 							Permission::Player => "Игрок",
 							Permission::Admin => "Администратор",
-							Permission::Developer => "Разработчик"
+							Permission::Developer => "Разработчик",
+							Permission::Guest => "Гость"
 						};
 						let _ = perms.insert(&name, json::object!
 						{
 							type: "list",
 							name: name.clone(),
 							value: p,
-							props: json::array![ "Игрок", "Администратор", "Разработчик" ]
+							props: json::array![ "Гость", "Игрок", "Администратор", "Разработчик" ]
 						});
 					}
 
 					let _ = msg.insert("Разрешения игроков", perms);
 
-					WebClient::sendResponse(web, WebResponse::Ok(
+					self.respond(web, WebResponse::Ok(
 						json::stringify(msg), "text/json".to_string()
 					));
 				},
-				ServerMessage::SaveSettings(web) =>
+				ServerMessage::SaveSettings(cfg, web) =>
 				{
-					println!("Настройки сервера были изменены.");
-					WebClient::sendResponse(web, WebResponse::Ok(
+					// Players are never dropped to fit a smaller limit
+					let highestID = self.highestID();
+					if cfg.maxPlayersCount < highestID
+					{
+						let error = format!("P{highestID} is connected, maxPlayersCount can't be less than {highestID}");
+						Log::warn(format!("Настройки сервера не были изменены: {error}"));
+						self.respond(web, WebResponse::BadRequest(error));
+						continue;
+					}
+					cfg.save();
+					Log::setLevel(cfg.logLevel);
+					self.config = *cfg;
+					self.resizeSlots();
+					Log::info("Настройки сервера были изменены.");
+					self.respond(web, WebResponse::Ok(
 						"{}".to_string(), "text/json".to_string()
 					));
-				}
-			}
-		}
-		self.requests.clear();
-	}
-
-	fn broadcastTCP(&mut self)
-	{
-		for msg in &self.broadcast
-		{
-			for c in &mut self.clients
-			{
-				c.sendTCP(msg.clone());
-			}
-		}
-		self.broadcast.clear();
-	}
-
-	fn broadcastState(&mut self)
-	{
-		for i in 0..self.config.maxPlayersCount as usize
-		{
-			if i >= self.clients.len() { break; }
-			let addr = self.clients[i].udp;
-			if addr.is_none() { continue; }
-			let addr = addr.unwrap();
-
-			let mut buffer: Vec<u8> = vec![];
-			for id in 0..self.config.maxPlayersCount as usize
-			{
-				if self.playersState[id][0] == 0 || id == i { continue; }
-				buffer.append(&mut self.playersState[id].to_vec());
-			}
-			if buffer.len() == 0 { continue; }
-
-			let _ = self.udp.send_to(&buffer, addr);
-		}
+				},
+				ServerMessage::SettingsRejected(errors, web) =>
+				{
+					Log::warn(format!("Настройки сервера не были изменены: {}", errors.join("; ")));
+					self.respond(web, WebResponse::BadRequest(errors.join("; ")));
+				},
+				ServerMessage::Shutdown(web) => self.shutdown(id, web),
+				ServerMessage::ReloadConfig(web) => self.reloadConfig(id, web),
+				ServerMessage::LoadGame(slot, web) =>
+				{
+					let result =
+						if State::isValidSlot(&slot) { State::open(&slot) }
+						else { Err(std::io::Error::new(ErrorKind::InvalidInput, "invalid slot name")) };
+					let response = match result
+					{
+						Ok(mut state) =>
+						{
+							Log::info(format!("Loaded save slot {slot}."));
+							state.chatHistory = std::mem::take(&mut self.state.chatHistory);
+							state.chatTotal = self.state.chatTotal;
+							self.state = state;
+							WebResponse::Ok(
+								json::stringify(json::object! { loaded: true, slot: slot, checkpoint: self.state.checkpoint.as_str() }),
+								"text/json".to_string()
+							)
+						},
+						Err(x) =>
+						{
+							Log::error(format!("Failed to load save slot {slot}: {x}"));
+							WebResponse::ioError(&x)
+						}
+					};
+					if id == 0 { self.respond(web, response); }
+				},
+				ServerMessage::ListSaves(web) =>
+				{
+					let mut list = json::JsonValue::new_array();
+					for slot in State::listSlots() { let _ = list.push(slot); }
+					self.respond(web, WebResponse::Ok(
+						json::stringify(list), "text/json".to_string()
+					));
+				},
+				ServerMessage::ListBans(web) =>
+				{
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.bansJson()), "text/json".to_string()
+					));
+				},
+				ServerMessage::Positions(web) =>
+				{
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.positionsJson()), "text/json".to_string()
+					));
+				},
+				ServerMessage::ListMutes(web) =>
+				{
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.mutesJson()), "text/json".to_string()
+					));
+				},
+				ServerMessage::Unban(ip, web) =>
+				{
+					if let Ok(ip) = ip.parse::<IpAddr>() && self.state.banned.remove(&ip)
+					{
+						Log::info(format!("{ip} was unbanned from the web."));
+					}
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.bansJson()), "text/json".to_string()
+					));
+				},
+				ServerMessage::Unmute(name, web) =>
+				{
+					let id = self.getPlayerID(&name);
+					if id != 0
+					{
+						let c = &mut self.clients[(id - 1) as usize];
+						c.muted = false;
+						c.mutedUntil = None;
+						c.sendTCP(ClientMessage::Chat(String::from("Вам снова можно писать в чат")));
+					}
+					self.respond(web, WebResponse::Ok(
+						json::stringify(self.mutesJson()), "text/json".to_string()
+					));
+				},
+				ServerMessage::ChangeStats(hp, mana) =>
+				{
+					if id == 0 { continue; }
+					let c = &mut self.clients[(id - 1) as usize];
+					c.changeStats(hp, mana);
+					self.broadcast.push(ClientMessage::Stats(id, c.hp, c.mana));
+				},
+				ServerMessage::SetPosition(x, y) =>
+				{
+					if id == 0 { continue; }
+					let c = &mut self.clients[(id - 1) as usize];
+					// Once UDP works, its sequenced packets take over
+					if c.udp.is_some() { continue; }
+					c.addTrail(x, y);
+					let s = &mut self.playersState[(id - 1) as usize];
+					s[0] = (s[0] & 0b11_11_10_00) | id;
+					s[1..3].copy_from_slice(&x.to_le_bytes());
+					s[3..5].copy_from_slice(&y.to_le_bytes());
+					self.markState(id);
+				}
+			}
+		}
+		self.requests.clear();
+	}
+
+	fn bansJson(&self) -> json::JsonValue
+	{
+		let mut list = json::JsonValue::new_array();
+		for ip in &self.state.banned
+		{
+			let name = self.state.playersList.get(ip)
+				.map(|(name, _, _)| name.clone())
+				.unwrap_or_default();
+			let _ = list.push(json::object! { ip: ip.to_string(), name: name });
+		}
+		list
+	}
+
+	fn mutesJson(&self) -> json::JsonValue
+	{
+		let mut list = json::JsonValue::new_array();
+		for c in &self.clients
+		{
+			if c.id == 0 || !c.isMuted() { continue; }
+			let _ = list.push(json::object!
+			{
+				id: c.id,
+				name: c.name.clone(),
+				seconds: c.mutedUntil.map(|t| t.saturating_duration_since(Instant::now()).as_secs())
+			});
+		}
+		list
+	}
+
+	pub fn playersListJson(&self) -> json::JsonValue
+	{
+		let mut list = json::JsonValue::new_array();
+		for c in &self.clients
+		{
+			if c.id == 0 { continue; }
+			let _ = list.push(json::object!
+			{
+				id: c.id,
+				className: c.class.clone(),
+				name: c.name.clone(),
+				hp: { current: c.hp.0, max: c.hp.1 },
+				mana: { current: c.mana.0, max: c.mana.1 }
+			});
+		}
+		list
+	}
+
+	// Positions of the players which have sent at least one state packet
+	fn positionsJson(&self) -> json::JsonValue
+	{
+		let mut list = json::JsonValue::new_array();
+		for c in &self.clients
+		{
+			if c.id == 0 || self.playersState[(c.id - 1) as usize][0] == 0 { continue; }
+			let (x, y) = self.getPosition(c.id);
+			let _ = list.push(json::object!
+			{
+				id: c.id,
+				name: c.name.clone(),
+				x: x,
+				y: y
+			});
+		}
+		list
+	}
+
+	fn broadcastTCP(&mut self)
+	{
+		for msg in &self.broadcast
+		{
+			if let ClientMessage::Chat(text) = msg { self.webClient.pushChat(text); }
+			for c in &mut self.clients
+			{
+				c.sendTCP(msg.clone());
+			}
+		}
+		self.broadcast.clear();
+	}
+
+	// Sends only the changed states, with a full snapshot every keyframeInterval
+	// so lost packets and late joiners catch up
+	fn broadcastState(&mut self, now: Instant)
+	{
+		let full = now.saturating_duration_since(self.keyframeTimer) > self.config.keyframeInterval;
+		if full { self.keyframeTimer = now; }
+		for i in 0..self.config.maxPlayersCount as usize
+		{
+			if i >= self.clients.len() { break; }
+			self.sendState(i, full, now);
+		}
+	}
+
+	// Sends the full state of every other player to the client in slot `i`
+	fn sendKeyframe(&mut self, i: usize, now: Instant)
+	{
+		self.sendState(i, true, now);
+	}
+
+	// Sends the state of every other player to the client in slot `i`,
+	// or only of those it hasn't got since the last send
+	fn sendState(&mut self, i: usize, full: bool, now: Instant)
+	{
+		// Clients without UDP get the same state over TCP
+		let addr = self.clients[i].udp;
+		if addr.is_none() && self.clients[i].tcp.is_none() { return; }
+		if full { self.clients[i].resync = false; }
+		let stale = std::mem::take(&mut self.clients[i].stale);
+
+		let mut buffer: Vec<u8> = vec![];
+		for id in 0..self.config.maxPlayersCount as usize
+		{
+			if self.playersState[id][0] == 0 || id == i { continue; }
+			if !full && stale & (1 << id) == 0 { continue; }
+			buffer.append(&mut self.playersState[id].to_vec());
+		}
+		if buffer.is_empty() { return; }
+
+		Log::debug(format!("State of {} players sent to P{}", buffer.len() / 9, i + 1));
+		match addr
+		{
+			Some(addr) =>
+			{
+				if let Some(udp) = &self.udp { let _ = udp.send_to(&PlayerStatePacket::relay(&buffer), addr); }
+				self.clients[i].lastUdpSent = Some(now);
+			},
+			None => self.clients[i].sendTCP(ClientMessage::State(buffer))
+		}
+	}
+
+	// A state datagram without states goes to every client nothing was sent to lately
+	fn sendKeepalives(&mut self, now: Instant)
+	{
+		if self.config.udpKeepalive.is_zero() { return; }
+		for c in &mut self.clients
+		{
+			let addr = match c.udp
+			{
+				Some(x) if c.id != 0 => x,
+				_ => continue
+			};
+			if c.lastUdpSent.is_some_and(|t| now.saturating_duration_since(t) < self.config.udpKeepalive) { continue; }
+			if let Some(udp) = &self.udp { let _ = udp.send_to(&PlayerStatePacket::relay(&[]), addr); }
+			c.lastUdpSent = Some(now);
+		}
+	}
+
+	// Milliseconds since the start, wrapping. Pings carry it and Pongs bring it back
+	fn stamp(&self, now: Instant) -> u32
+	{
+		now.saturating_duration_since(self.startTime).as_millis() as u32
+	}
+
+	// Registered players are pinged every pingInterval, see Pong for the answer
+	fn sendPings(&mut self, now: Instant)
+	{
+		if self.config.pingInterval.is_zero() { return; }
+		let stamp = self.stamp(now);
+		for c in &mut self.clients
+		{
+			if c.id == 0 || c.token == 0 || c.tcp.is_none() { continue; }
+			if c.lastPing.is_some_and(|t| now.saturating_duration_since(t) < self.config.pingInterval) { continue; }
+			c.sendTCP(ClientMessage::Ping(stamp));
+			c.lastPing = Some(now);
+		}
+	}
+
+	// Every client has to get the new state of player `id`
+	fn markState(&mut self, id: u8)
+	{
+		for c in &mut self.clients { c.stale |= 1 << (id - 1); }
+	}
+
+	// Packets must come from the IP of the player's TCP connection.
+	// Strict mode also pins the source port to the first packet's one,
+	// lenient mode follows port changes made by NAT.
+	fn checkUdpSource(&mut self, id: u8, addr: SocketAddr) -> bool
+	{
+		let c = &mut self.clients[(id - 1) as usize];
+		let ip = match c.tcp.as_ref().and_then(|tcp| tcp.peer_addr().ok())
+		{
+			Some(peer) => peer.ip(),
+			None => return false
+		};
+		if addr.ip() != ip { return false; }
+
+		match c.udp
+		{
+			Some(udp) if self.config.strictUdp => udp == addr,
+			_ => { c.udp = Some(addr); true }
+		}
+	}
+
+	// Frees the player's slot and tells everyone they left
+	fn disconnect(&mut self, id: u8)
+	{
+		self.clients[(id - 1) as usize] = Client::default();
+		self.playersState[(id - 1) as usize][0] = id;
+		self.markState(id);
+		self.broadcast.push(ClientMessage::Disconnected(id));
+	}
+
+	fn shutdown(&mut self, id: u8, web: SocketAddr)
+	{
+		let (name, p) = self.executor(id);
+		if !p.check(Permission::Developer)
+		{
+			Log::warn(format!("P{id} ({name}) tried to stop the server."));
+			self.reply(id, String::from("[Недостаточно прав]"));
+			return;
+		}
+
+		Log::info(format!("Stopping the server on request of {name}..."));
+		if let Err(x) = self.save(self.state.slot.clone(), self.state.checkpoint.clone())
+		{
+			Log::error(format!("Failed to save the game: {x}"));
+		}
+		if id == 0
+		{
+			self.respond(web, WebResponse::Ok(
+				"{}".to_string(), "text/json".to_string()
+			));
+		}
+
+		for c in &mut self.clients
+		{
+			if c.id != 0 { c.sendTCP(ClientMessage::Disconnected(c.id)); }
+		}
+		self.clients.fill_with(Client::default);
+		self.webClient.close();
+		self.pending.clear();
+		self.listener = None;
+		self.webListener = None;
+		self.udp = None;
+		self.running = false;
+	}
+
+	fn reloadConfig(&mut self, id: u8, web: SocketAddr)
+	{
+		let (name, p) = self.executor(id);
+		if !p.check(Permission::Developer)
+		{
+			Log::warn(format!("P{id} ({name}) tried to reload the config."));
+			return;
+		}
+
+		let result = self.config.reload(self.highestID());
+		match &result
+		{
+			Ok(_) =>
+			{
+				self.resizeSlots();
+				Log::setLevel(self.config.logLevel);
+				Log::info(format!("Config is reloaded by {name}."));
+			},
+			Err(x) => Log::error(format!("Failed to reload config: {x}"))
+		}
+
+		let msg = match result
+		{
+			Ok(_) => String::from("[Настройки перезагружены]"),
+			Err(x) => format!("[Настройки не перезагружены: {x}]")
+		};
+		if id == 0
+		{
+			self.respond(web, WebResponse::Ok(
+				json::stringify(json::object! { msg: msg }), "text/json".to_string()
+			));
+		}
+		else { self.reply(id, msg); }
+	}
+
+	// Highest ID in use, slots reserved for reconnection included
+	fn highestID(&self) -> u8
+	{
+		self.clients.iter().chain(self.recoverable.values().map(|(c, _)| c))
+			.map(|c| c.id).max().unwrap_or(0)
+	}
+
+	// Keeps the slot vectors in sync with maxPlayersCount
+	fn resizeSlots(&mut self)
+	{
+		let count = self.config.maxPlayersCount as usize;
+		self.clients.resize_with(count, Client::default);
+		self.playersState.resize(count, [0u8; 9]);
 	}
 
-	fn save(&mut self, checkpoint: String)
+	fn save(&mut self, slot: String, checkpoint: String) -> std::io::Result<()>
 	{
+		self.storePositions();
 		self.config.save();
-		self.state.save(checkpoint);
+		self.state.slot = slot;
+		self.state.checkpoint = checkpoint.clone();
+		self.state.save(checkpoint, self.config.maxBackups)
 	}
 	
+	// Copies positions of the players which have sent their state into the save
+	fn storePositions(&mut self)
+	{
+		for c in &self.clients
+		{
+			if c.id == 0 || self.playersState[(c.id - 1) as usize][0] == 0 { continue; }
+			let ip = match c.tcp.as_ref().and_then(|tcp| tcp.peer_addr().ok())
+			{
+				Some(addr) => addr.ip(),
+				None => continue
+			};
+			let (x, y) = self.getPosition(c.id);
+			self.state.setPosition(ip, x, y);
+		}
+	}
+
 	fn getAvailablePlayerID(&self) -> u8
 	{
 		for i in 0..self.config.maxPlayersCount as usize
 		{
-			if self.clients[i].id == 0 { return (i + 1) as u8; }
+			let reserved = self.recoverable.values().any(|(c, _)| c.id as usize == i + 1);
+			if self.clients[i].id == 0 && !reserved { return (i + 1) as u8; }
 		}
 		0
 	}
 
+	// Confirms a request with the given incoming message code
+	// to clients which asked for it
+	fn ack(&mut self, id: u8, code: u8, ok: bool)
+	{
+		if id == 0 { return; }
+		let c = &mut self.clients[(id - 1) as usize];
+		if c.acks { c.sendTCP(ClientMessage::Ack(code, ok)); }
+	}
+
+	// Sends the inventory to its owner and keeps the save in sync
+	fn syncInventory(&mut self, id: u8)
+	{
+		let c = &mut self.clients[(id - 1) as usize];
+		c.sendTCP(ClientMessage::Inventory(c.inventory.clone()));
+		if let Some(ip) = c.tcp.as_ref().and_then(|tcp| tcp.peer_addr().ok()).map(|x| x.ip())
+		{
+			self.state.setInventory(ip, c.inventory.clone());
+		}
+	}
+
+	// Moves the connection of the player in slot `id` back into its old slot
+	fn reconnect(&mut self, id: u8, token: u64, now: Instant)
+	{
+		let old = self.recoverable.remove(&token)
+			.filter(|(_, t)| now.saturating_duration_since(*t) <= self.config.reconnectGrace);
+		let mut old = match old
+		{
+			Some((c, _)) => c,
+			None =>
+			{
+				Log::warn(format!("P{id} sent an unknown or expired reconnection token."));
+				self.clients[(id - 1) as usize].sendTCP(ClientMessage::Chat(
+					String::from("Не удалось восстановить подключение")
+				));
+				return;
+			}
+		};
+
+		let new = std::mem::replace(&mut self.clients[(id - 1) as usize], Client::default());
+		old.tcp = new.tcp;
+		old.buffer = new.buffer;
+		old.outgoing = new.outgoing;
+		old.udp = None;
+		old.udpSequence = None;
+		old.resync = true;
+		old.lastSeen = now;
+		let (oldID, name, class) = (old.id, old.name.clone(), old.class.clone());
+		old.sendTCP(ClientMessage::Login(oldID, name.clone(), class));
+		old.sendTCP(ClientMessage::Token(token));
+		self.clients[(oldID - 1) as usize] = old;
+		Log::info(format!("{name} reconnected as P{oldID}."));
+	}
+
+	// Frees the slots of players who did not come back in time
+	fn expireReconnects(&mut self, now: Instant)
+	{
+		let grace = self.config.reconnectGrace;
+		let expired = self.recoverable.iter()
+			.filter(|(_, (_, t))| now.saturating_duration_since(*t) > grace)
+			.map(|(token, _)| *token)
+			.collect::<Vec<u64>>();
+		for token in expired
+		{
+			if let Some((c, _)) = self.recoverable.remove(&token)
+			{
+				Log::info(format!("P{} disconnected.", c.id));
+				self.disconnect(c.id);
+			}
+		}
+	}
+
+	// Zero timeout keeps silent clients forever
+	fn dropSilentClients(&mut self, now: Instant)
+	{
+		if self.config.clientTimeout.is_zero() { return; }
+		let silent = self.clients.iter()
+			.filter(|c| c.id != 0 && now.saturating_duration_since(c.lastSeen) > self.config.clientTimeout)
+			.map(|c| c.id)
+			.collect::<Vec<u8>>();
+		for id in silent
+		{
+			Log::info(format!("P{id} timed out."));
+			self.disconnect(id);
+		}
+	}
+
+	// Class of a player who registers without one: the saved one, then the
+	// configured default, then the first known. Any goes while there are no classes
+	fn fallbackClass(&self, id: u8) -> String
+	{
+		let saved = self.clients[(id - 1) as usize].tcp.as_ref()
+			.and_then(|x| x.peer_addr().ok())
+			.and_then(|addr| self.state.playersList.get(&addr.ip()))
+			.map(|x| x.1.clone())
+			.unwrap_or_default();
+		let known = |x: &String| !x.is_empty() && (self.config.classes.is_empty() || self.config.classes.contains_key(x));
+		if known(&saved) { return saved; }
+		if known(&self.config.defaultClass) { return self.config.defaultClass.clone(); }
+		self.config.classes.keys().min().cloned().unwrap_or(String::from("unknown"))
+	}
+
+	fn newToken() -> u64
+	{
+		let mut hasher = RandomState::new().build_hasher();
+		hasher.write_u128(std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map(|t| t.as_nanos())
+			.unwrap_or(0));
+		hasher.finish().max(1)
+	}
+
+	// `2d6` is two dice with six sides, `d20` is one die
+	fn parseDice(expr: &str) -> Option<(u32, u32)>
+	{
+		let (count, sides) = expr.split_once('d')?;
+		let count = if count.is_empty() { 1 } else { count.parse::<u32>().ok()? };
+		let sides = sides.parse::<u32>().ok()?;
+		if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) { return None; }
+		Some((count, sides))
+	}
+
+	fn getPosition(&self, id: u8) -> (u16, u16)
+	{
+		let s = &self.playersState[(id - 1) as usize];
+		(u16::from_le_bytes([s[1], s[2]]), u16::from_le_bytes([s[3], s[4]]))
+	}
+
 	fn getPlayerID(&self, name: &str) -> u8
 	{
 		for i in 0..self.config.maxPlayersCount as usize
@@ -431,26 +1494,71 @@ impl Server
 		0
 	}
 
+	// Name and rights of the player in slot `id`, or of the web client
+	fn executor(&mut self, id: u8) -> (String, Permission)
+	{
+		if id == 0 { return (String::from(WEB_NAME), Permission::Developer); }
+		let name = self.clients[(id - 1) as usize].name.clone();
+		let p = self.config.getPermission(&name);
+		(name, p)
+	}
+
+	// The web client gets the command back with the replies to it
 	pub fn cmd(&mut self, executor: u8, webID: SocketAddr, txt: String)
 	{
-		let txt = txt.to_lowercase();
-		let mut args = txt.split(" ");
-		if executor == 0
+		let lower = txt.to_lowercase();
+		let c = lower.split(' ').nth(0).unwrap_or("");
+		self.runCommand(executor, webID, txt.clone());
+		let replies = std::mem::take(&mut self.webReplies);
+		if executor == 0 && !WEB_REPLIES.contains(&c)
 		{
-			println!("Центр мира вызвал команду: {txt}");
-			WebClient::sendResponse(webID, WebResponse::Ok(
-				String::from("{ \"msg\": \"") + &txt + "\" }",
+			Log::info(format!("{WEB_NAME} вызвал команду: {lower}"));
+			self.respond(webID, WebResponse::Ok(
+				json::stringify(json::object! { msg: lower.as_str(), replies: replies }),
 				"text/json".to_string()
 			));
 		}
-		let name =
-			if executor == 0 { &String::from("Центр мира") }
-			else { &self.clients[(executor - 1) as usize].name };
-		let p = self.config.getPermission(&name);
-		println!("P{executor} ({name}, {}) вызвал '{txt}'", p.toString());
+	}
+
+	fn runCommand(&mut self, executor: u8, webID: SocketAddr, txt: String)
+	{
+		let original = txt.clone();
+		let txt = txt.to_lowercase();
+		let mut args = txt.split(" ");
+		let (name, p) = self.executor(executor);
+		Log::info(format!("P{executor} ({name}, {}) вызвал '{txt}'", p.toString()));
 		
 		let c = args.nth(0).unwrap_or(" ");
 
+		// Commands missing from the table are unknown to /help too
+		let outcome = match COMMANDS.iter().find(|(usage, _, _)| usage.split(' ').next() == Some(c))
+		{
+			None => "unknown",
+			Some((_, _, group)) if !p.check(group.clone()) => "denied",
+			Some(_) => "allowed"
+		};
+
+		if !p.check(Permission::Developer) && let Some(cooldown) = self.config.cooldowns.get(c).cloned()
+		{
+			let key = (name.clone(), c.to_string());
+			if let Some(left) = self.commandTimes.get(&key)
+				.and_then(|t| cooldown.checked_sub(t.elapsed()))
+			{
+				let msg = format!("[Команда /{c} будет доступна через {:.1} с]", left.as_secs_f32());
+				if executor == 0 && WEB_REPLIES.contains(&c)
+				{
+					self.respond(webID, WebResponse::Ok(
+						json::stringify(json::object! { msg: msg.clone() }), "text/json".to_string()
+					));
+				}
+				self.reply(executor, msg);
+				Log::audit(&name, &p.toString(), &original, "cooldown");
+				return;
+			}
+			self.commandTimes.insert(key, Instant::now());
+		}
+		Log::audit(&name, &p.toString(), &original, outcome);
+
 		if c == "getposition" && p.check(Permission::Admin)
 		{
 			let n = args.nth(0).unwrap_or(&name);
@@ -458,16 +1566,14 @@ impl Server
 
 			let pos = if id == 0 { "Не найден" } else
 			{
-				let s = &self.playersState[(id - 1) as usize];
-				let x = u16::from_le_bytes([s[1], s[2]]);
-				let y = u16::from_le_bytes([s[3], s[4]]);
+				let (x, y) = self.getPosition(id);
 				&(x.to_string() + " " + &y.to_string())
 			};
 			
 			let msg = format!("[Игрок {name} запросил координаты {n}] {pos}");
 
 			self.broadcast.push(ClientMessage::Chat(msg.clone()));
-			self.state.chatHistory.push((name.to_string(), msg));
+			self.addChat(name.to_string(), msg, ChatKind::System);
 		}
 		else if c == "setposition" && p.check(Permission::Admin)
 		{
@@ -475,28 +1581,2019 @@ impl Server
 			let id = self.getPlayerID(n);
 			if id == 0
 			{
-				self.state.chatHistory.push((name.clone(),
-					format!("[Игрок {n} не был перемещён: НЕ НАЙДЕН]")
-				));
+				self.reply(executor, format!("[Игрок {n} не был перемещён: НЕ НАЙДЕН]"));
 				return;
 			}
-			let x = args.nth(0).unwrap_or("0").parse::<u16>().unwrap();
-			let y = args.nth(0).unwrap_or("0").parse::<u16>().unwrap();
-			println!("P{id}({n}) перемещён в ({x};{y})");
+			let (x, y) = match (args.nth(0).unwrap_or("0").parse::<u16>(), args.nth(0).unwrap_or("0").parse::<u16>())
+			{
+				(Ok(x), Ok(y)) => (x, y),
+				_ => { self.reply(executor, String::from("[Использование: /setposition <игрок> <x> <y>]")); return; }
+			};
+			Log::info(format!("P{id}({n}) перемещён в ({x};{y})"));
 			
-			self.state.chatHistory.push((name.clone(),
-				format!("[Игрок {n} перемещён в ({x};{y})]")
-			));
+			self.addChat(name.clone(),
+				format!("[Игрок {n} перемещён в ({x};{y})]"),
+				ChatKind::System
+			);
+			self.clients[(id - 1) as usize].sendTCP(ClientMessage::SetPosition(x, y));
+		}
+		else if c == "help"
+		{
+			let list = COMMANDS.iter()
+				.filter(|(_, _, group)| p.check(group.clone()))
+				.map(|(cmd, desc, _)| format!("/{cmd} - {desc}"))
+				.collect::<Vec<String>>();
+			if executor == 0
+			{
+				self.respond(webID, WebResponse::Ok(
+					json::stringify(json::object! { commands: list }), "text/json".to_string()
+				));
+			}
+			else { self.reply(executor, format!("[Доступные команды]\n{}", list.join("\n"))); }
+		}
+		else if c == "players"
+		{
+			let mut list = self.clients.iter()
+				.filter(|c| c.id != 0)
+				.map(|c| c.name.clone())
+				.collect::<Vec<String>>();
+			list.sort_by_key(|x| x.to_lowercase());
+			let list = list.into_iter()
+				.map(|x| { let group = self.config.getPermission(&x).toString(); format!("{x} ({group})") })
+				.collect::<Vec<String>>();
+			let msg =
+				if list.is_empty() { String::from("[Игроков нет]") }
+				else { format!("[Игроки: {}]", list.join(", ")) };
+			if executor == 0
+			{
+				self.respond(webID, WebResponse::Ok(
+					json::stringify(json::object! { msg: msg }), "text/json".to_string()
+				));
+			}
+			else { self.reply(executor, msg); }
+		}
+		else if c == "tp" && p.check(Permission::Admin)
+		{
+			let (who, target) = match (args.nth(0), args.nth(0))
+			{
+				(Some(who), Some(target)) => (who, target),
+				_ => { self.reply(executor, String::from("[Использование: /tp <кого> <к кому>]")); return; }
+			};
+			let (id, targetID) = (self.getPlayerID(who), self.getPlayerID(target));
+			if id == 0 || targetID == 0
+			{
+				let n = if id == 0 { who } else { target };
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let (x, y) = self.getPosition(targetID);
+			Log::info(format!("P{id}({who}) перемещён к P{targetID}({target}) в ({x};{y})"));
+			self.reply(executor, format!("[Игрок {who} перемещён к {target}]"));
 			self.clients[(id - 1) as usize].sendTCP(ClientMessage::SetPosition(x, y));
 		}
-		else if c == "gettime"
+		else if c == "team" && p.check(Permission::Player)
 		{
-			self.state.chatHistory.push((name.clone(),
-				format!("Текущее время сервера: {}", State::getDateTime())
+			let team = args.nth(0).unwrap_or("").parse::<u8>().unwrap_or(u8::MAX);
+			if executor == 0 || team > self.config.teamsCount { return; }
+			self.clients[(executor - 1) as usize].team = team;
+			self.clients[(executor - 1) as usize].sendTCP(ClientMessage::Chat(
+				if team == 0 { String::from("Вы покинули команду") }
+				else { format!("Вы вступили в команду {team}") }
 			));
 		}
-	}
-
-	pub fn getWebClient(&mut self) -> &mut WebClient { &mut self.webClient }
-	pub fn getConfig(&mut self) -> &mut Config { &mut self.config }
-}
\ No newline at end of file
+		else if c == "teamsay" && p.check(Permission::Admin)
+		{
+			let team = args.nth(0).unwrap_or("").parse::<u8>().unwrap_or(0);
+			if team == 0 || team > self.config.teamsCount
+			{
+				self.reply(executor, format!("[Команда не найдена, доступны 1-{}]", self.config.teamsCount));
+				return;
+			}
+			let text = args.collect::<Vec<&str>>().join(" ");
+			let msg = format!("[Команде {team}] {name}: {text}");
+			for c in &mut self.clients
+			{
+				if c.team == team { c.sendTCP(ClientMessage::Chat(msg.clone())); }
+			}
+			self.addChat(name.clone(), msg, ChatKind::Broadcast);
+		}
+		else if c == "conninfo" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or(&name);
+			let id = self.getPlayerID(n);
+			if id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let c = &self.clients[(id - 1) as usize];
+			let ago = |t: Option<Instant>| match t
+			{
+				Some(t) => format!("{} мс назад", t.elapsed().as_millis()),
+				None => String::from("нет данных")
+			};
+			let loss =
+				if c.udpReceived == 0 { String::from("нет данных") }
+				else { format!("{:.1}%", c.udpLost as f32 * 100.0 / (c.udpReceived + c.udpLost) as f32) };
+			let rtt = match c.rtt
+			{
+				Some(x) => format!("{} мс", x.as_millis()),
+				None => String::from("нет данных")
+			};
+			let msg = format!("[Соединение {n}] RTT: {rtt}, потери: {loss}, TCP: {}, UDP: {}",
+				ago(c.lastTcp), ago(c.lastUdp)
+			);
+			self.reply(executor, msg);
+		}
+		else if c == "whois"
+		{
+			let n = args.nth(0).unwrap_or("");
+			let id = self.getPlayerID(n);
+			let msg =
+				if !p.check(Permission::Admin) { Some(String::from("[Недостаточно прав]")) }
+				else if id == 0 { Some(format!("[Игрок {n} не найден]")) }
+				else { None };
+			if let Some(msg) = msg
+			{
+				if executor == 0
+				{
+					self.respond(webID, WebResponse::Ok(
+						json::stringify(json::object! { msg: msg }), "text/json".to_string()
+					));
+				}
+				else if p.check(Permission::Admin) { self.reply(executor, msg); }
+				return;
+			}
+
+			let (x, y) = self.getPosition(id);
+			let c = &self.clients[(id - 1) as usize];
+			// Only developers see addresses
+			let ip = c.tcp.as_ref()
+				.and_then(|x| x.peer_addr().ok())
+				.map(|x| x.ip().to_string())
+				.filter(|_| p.check(Permission::Developer));
+			let (who, class, online) = (c.name.clone(), c.class.clone(), c.connectedAt.elapsed().as_secs());
+			let group = self.config.getPermission(&who).toString();
+			if executor == 0
+			{
+				self.respond(webID, WebResponse::Ok(json::stringify(json::object!
+				{
+					id: id, name: who, class: class, group: group,
+					ip: ip, x: x, y: y, online: online
+				}), "text/json".to_string()));
+			}
+			else
+			{
+				self.reply(executor, format!("[{who}] P{id}, класс: {class}, группа: {group}, IP: {}, \
+					позиция: ({x};{y}), в сети: {online} с",
+					ip.unwrap_or(String::from("скрыт"))
+				));
+			}
+		}
+		else if c == "clearchat"
+		{
+			if !p.check(Permission::Admin)
+			{
+				if executor == 0
+				{
+					self.respond(webID, WebResponse::Ok(
+						json::stringify(json::object! { msg: "[Недостаточно прав]" }), "text/json".to_string()
+					));
+				}
+				return;
+			}
+			// chatTotal stays, so web clients don't fetch old messages again
+			let removed = self.state.chatHistory.len();
+			self.state.chatHistory.clear();
+			self.broadcast.push(ClientMessage::ClearChat);
+			Log::info(format!("Chat was cleared by {name}, {removed} messages removed"));
+
+			let msg = format!("[Чат очищен, удалено сообщений: {removed}]");
+			if executor == 0
+			{
+				self.respond(webID, WebResponse::Ok(
+					json::stringify(json::object! { msg: msg, removed: removed }), "text/json".to_string()
+				));
+			}
+			else { self.clients[(executor - 1) as usize].sendTCP(ClientMessage::Chat(msg)); }
+		}
+		else if c == "timing" && p.check(Permission::Developer)
+		{
+			let send = args.nth(0).unwrap_or("").parse::<u8>().unwrap_or(self.config.tickRate);
+			let recv = args.nth(0).unwrap_or("").parse::<u8>().unwrap_or(send.saturating_mul(2));
+			self.config.setTiming(send, recv);
+			let msg = format!("[Отправка: {:?}, приём: {:?}]",
+				self.config.sendTime, self.config.recvTime
+			);
+			self.reply(executor, msg);
+		}
+		else if (c == "kick" || c == "kickid") && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let id =
+				if c == "kick" { self.getPlayerID(n) }
+				else
+				{
+					match n.parse::<u8>()
+					{
+						Ok(id) if (1..=self.config.maxPlayersCount).contains(&id) => id,
+						_ => 0
+					}
+				};
+			if id == 0 || self.clients[(id - 1) as usize].id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let target = self.clients[(id - 1) as usize].name.clone();
+			if self.config.getPermission(&target).check(Permission::Developer) && !p.check(Permission::Developer)
+			{
+				self.reply(executor, format!("[Игрока {target} нельзя выгнать]"));
+				return;
+			}
+			self.clients[(id - 1) as usize].sendTCP(ClientMessage::Disconnected(id));
+			self.disconnect(id);
+			Log::info(format!("P{id} ({target}) was kicked by {name}."));
+			self.reply(executor, format!("[Игрок {target} выгнан]"));
+		}
+		else if c == "ban" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let id = self.getPlayerID(n);
+			let ip =
+				if id == 0 { None }
+				else
+				{
+					self.clients[(id - 1) as usize].tcp.as_ref()
+						.and_then(|tcp| tcp.peer_addr().ok())
+						.map(|addr| addr.ip())
+				};
+			match ip
+			{
+				Some(ip) =>
+				{
+					self.state.banned.insert(ip);
+					self.clients[(id - 1) as usize].sendTCP(ClientMessage::Disconnected(id));
+					self.disconnect(id);
+					Log::info(format!("P{id} ({n}, {ip}) was banned by {name}."));
+					self.reply(executor, format!("[Игрок {n} заблокирован]"));
+				},
+				None => self.reply(executor, format!("[Игрок {n} не найден]"))
+			}
+		}
+		else if c == "unban" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let ip = match n.parse::<IpAddr>()
+			{
+				Ok(ip) => Some(ip),
+				Err(_) => self.state.getPlayerIP(n)
+			};
+			match ip
+			{
+				Some(ip) if self.state.banned.remove(&ip) =>
+				{
+					Log::info(format!("{ip} was unbanned by {name}."));
+					self.reply(executor, format!("[Игрок {n} разблокирован]"));
+				},
+				_ => self.reply(executor, format!("[Игрок {n} не заблокирован]"))
+			}
+		}
+		else if c == "mute" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let id = self.getPlayerID(n);
+			if id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let seconds = args.nth(0).unwrap_or("0").parse::<u64>().unwrap_or(0);
+			let c = &mut self.clients[(id - 1) as usize];
+			c.muted = true;
+			c.mutedUntil =
+				if seconds == 0 { None }
+				else { Some(Instant::now() + Duration::from_secs(seconds)) };
+			c.sendTCP(ClientMessage::Chat(String::from("Вам запрещено писать в чат")));
+			Log::info(format!("P{id} ({n}) was muted by {name} for {seconds}s."));
+			self.reply(executor,
+				if seconds == 0 { format!("[Игроку {n} запрещено писать в чат]") }
+				else { format!("[Игроку {n} запрещено писать в чат на {seconds} с]") }
+			);
+		}
+		else if c == "unmute" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let id = self.getPlayerID(n);
+			if id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let c = &mut self.clients[(id - 1) as usize];
+			c.muted = false;
+			c.mutedUntil = None;
+			c.sendTCP(ClientMessage::Chat(String::from("Вам снова можно писать в чат")));
+			self.reply(executor, format!("[Игроку {n} снова можно писать в чат]"));
+		}
+		else if c == "save" && p.check(Permission::Admin)
+		{
+			// Checkpoint names keep their case
+			let checkpoint = original.split(' ').nth(1).unwrap_or("").trim().to_string();
+			let checkpoint =
+				if checkpoint.is_empty() { self.state.checkpoint.clone() }
+				else { checkpoint };
+			match self.save(self.state.slot.clone(), checkpoint.clone())
+			{
+				Ok(_) =>
+				{
+					Log::info(format!("Game saved on {checkpoint} by {name}."));
+					let msg = format!("[Игра сохранена на {checkpoint}: {}]", self.state.date);
+					self.addChat(name.clone(), msg.clone(), ChatKind::System);
+					self.reply(executor, msg);
+				},
+				Err(x) =>
+				{
+					Log::error(format!("Failed to save the game: {x}"));
+					self.reply(executor, format!("[Игра не сохранена: {x}]"));
+				}
+			}
+		}
+		else if c == "perm" && p.check(Permission::Developer)
+		{
+			// Permissions are keyed by the exact name, so the case is kept
+			let n = original.split(' ').nth(1).unwrap_or("").trim().to_string();
+			if n.is_empty()
+			{
+				self.reply(executor, String::from("[Использование: /perm <игрок> [guest|player|admin|dev]]"));
+				return;
+			}
+			let id = self.getPlayerID(&n);
+			let n = if id == 0 { n } else { self.clients[(id - 1) as usize].name.clone() };
+			match args.nth(1)
+			{
+				None =>
+				{
+					let group = self.config.getPermission(&n).toString();
+					self.reply(executor, format!("[Права игрока {n}: {group}]"));
+				},
+				Some(group) if ["guest", "player", "admin", "dev"].contains(&group) =>
+				{
+					self.config.setPermission(n.clone(), Permission::fromString(group));
+					self.config.save();
+					Log::info(format!("{name} set the permission of {n} to {group}."));
+					self.reply(executor, format!("[Права игрока {n} изменены на {group}]"));
+				},
+				Some(group) => self.reply(executor, format!("[Неизвестная группа: {group}]"))
+			}
+		}
+		else if c == "reload" { self.reloadConfig(executor, webID); }
+		else if c == "reloadperms" && p.check(Permission::Developer)
+		{
+			match self.config.reloadPermissions()
+			{
+				Ok(count) =>
+				{
+					Log::info(format!("Permissions are reloaded by {name}."));
+					self.reply(executor, format!("[Права перезагружены: {count}]"));
+				},
+				Err(x) =>
+				{
+					Log::error(format!("Failed to reload permissions: {x}"));
+					self.reply(executor, format!("[Права не перезагружены: {x}]"));
+				}
+			}
+		}
+		else if c == "trail" && p.check(Permission::Admin)
+		{
+			let n = args.nth(0).unwrap_or(&name);
+			let id = self.getPlayerID(n);
+			if id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			let trail = self.clients[(id - 1) as usize].trail.iter().rev()
+				.map(|(x, y, t)| format!("({x};{y}) {} мс назад", t.elapsed().as_millis()))
+				.collect::<Vec<String>>();
+			let msg =
+				if trail.is_empty() { format!("[Перемещения {n}] нет данных") }
+				else { format!("[Перемещения {n}] {}", trail.join(", ")) };
+			self.reply(executor, msg);
+		}
+		else if c == "resync" && p.check(Permission::Player)
+		{
+			let n = args.nth(0).unwrap_or(&name);
+			let id = self.getPlayerID(n);
+			if id == 0 || (id != executor && !p.check(Permission::Admin))
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			self.clients[(id - 1) as usize].resync = true;
+		}
+		else if c == "w" && p.check(Permission::Guest)
+		{
+			let n = args.nth(0).unwrap_or("");
+			let text = original.splitn(3, ' ').nth(2).unwrap_or("").trim().to_string();
+			let id = self.getPlayerID(n);
+			if id == 0
+			{
+				self.reply(executor, format!("[Игрок {n} не найден]"));
+				return;
+			}
+			if text.is_empty() { return; }
+			let target = self.clients[(id - 1) as usize].name.clone();
+			let msg = format!("[шёпот] {name} -> {target}: {text}");
+			self.clients[(id - 1) as usize].sendTCP(ClientMessage::Chat(msg.clone()));
+			if executor != 0 && executor != id
+			{
+				self.clients[(executor - 1) as usize].sendTCP(ClientMessage::Chat(msg.clone()));
+			}
+			self.addChat(name.clone(), msg, ChatKind::Whisper);
+		}
+		else if c == "stop" { self.shutdown(executor, webID); }
+		else if c == "version"
+		{
+			self.reply(executor, format!("[Версия сервера: {}, протокол: {PROTOCOL_VERSION}]", env!("CARGO_PKG_VERSION")));
+		}
+		else if c == "roll" && p.check(Permission::Player)
+		{
+			let expr = args.nth(0).unwrap_or("");
+			let (count, sides) = match Server::parseDice(expr)
+			{
+				Some(x) => x,
+				None =>
+				{
+					self.reply(executor, format!("[Кубики задаются как NdM, N до {MAX_DICE}, M от 2 до {MAX_SIDES}]"));
+					return;
+				}
+			};
+			let rolls = (0..count)
+				.map(|_| (self.rng().next() % sides as u64) as u32 + 1)
+				.collect::<Vec<u32>>();
+			let total = rolls.iter().sum::<u32>();
+			let list = rolls.iter().map(|x| x.to_string()).collect::<Vec<String>>().join(", ");
+			let msg = format!("{name} бросает {count}d{sides}: [{list}] = {total}");
+			self.broadcast.push(ClientMessage::Chat(msg.clone()));
+			self.addChat(name.clone(), msg, ChatKind::System);
+		}
+		else if c == "gettime" && p.check(Permission::Player)
+		{
+			self.reply(executor, format!("Текущее время сервера: {}", State::getDateTime()));
+		}
+	}
+
+	fn addChat(&mut self, user: String, msg: String, kind: ChatKind)
+	{
+		self.state.addChat(user, msg, kind,
+			self.config.maxChatHistory, self.config.maxChatPerPlayer
+		);
+	}
+
+	fn respond(&mut self, web: SocketAddr, response: WebResponse)
+	{
+		self.webClient.sendResponse(web, response, &self.config.corsOrigin);
+	}
+
+	// Sends a system message to the command executor only, the public
+	// chat history never gets it as it may hold addresses or rights
+	fn reply(&mut self, executor: u8, msg: String)
+	{
+		if executor == 0 { self.webReplies.push(msg); }
+		else { self.clients[(executor - 1) as usize].sendTCP(ClientMessage::Chat(msg)); }
+	}
+
+	// Prometheus text format
+	pub fn metrics(&self) -> String
+	{
+		let ticks = self.tickTimes.iter().map(|x| x.as_secs_f64());
+		let min = ticks.clone().reduce(f64::min).unwrap_or(0.0);
+		let max = ticks.clone().reduce(f64::max).unwrap_or(0.0);
+		let avg = ticks.sum::<f64>() / self.tickTimes.len().max(1) as f64;
+
+		format!("# HELP players_connected Connected players.\n\
+			# TYPE players_connected gauge\n\
+			players_connected {}\n\
+			# HELP chat_history_len Messages kept in the chat history.\n\
+			# TYPE chat_history_len gauge\n\
+			chat_history_len {}\n\
+			# HELP tick_duration_seconds Duration of a server update over the last {TICK_WINDOW} updates.\n\
+			# TYPE tick_duration_seconds gauge\n\
+			tick_duration_seconds{{stat=\"min\"}} {min}\n\
+			tick_duration_seconds{{stat=\"avg\"}} {avg}\n\
+			tick_duration_seconds{{stat=\"max\"}} {max}\n\
+			# HELP udp_packets_per_second Player state packets accepted during the last second.\n\
+			# TYPE udp_packets_per_second gauge\n\
+			udp_packets_per_second {}\n",
+			self.clients.iter().filter(|c| c.id != 0).count(),
+			self.state.chatHistory.len(),
+			self.udpCount.1
+		)
+	}
+
+	pub fn health(&self) -> json::JsonValue
+	{
+		json::object!
+		{
+			status: "ok",
+			uptime: self.startTime.elapsed().as_secs(),
+			players: self.clients.iter().filter(|c| c.id != 0).count(),
+			maxPlayersCount: self.config.maxPlayersCount,
+			tickRate: self.config.tickRate,
+			chatLength: self.state.chatHistory.len(),
+			version: env!("CARGO_PKG_VERSION"),
+			protocol: PROTOCOL_VERSION
+		}
+	}
+
+	pub fn isRunning(&self) -> bool { self.running }
+	pub fn rng(&mut self) -> &mut Rng { &mut self.rng }
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::time::Duration;
+
+	use super::{MAX_PENDING, PEEK_TIMEOUT, RES_DIR};
+	use super::super::testsupport::{configFile, TestClient, TestServer};
+	use super::super::Config::{ClassInfo, Config, Permission};
+	use super::super::State::{ChatKind, State};
+	use super::super::Transmission::{ClientMessage, PlayerStatePacket, ServerMessage, CAP_ACK, PROTOCOL_VERSION};
+
+	fn web() -> std::net::SocketAddr
+	{
+		"0.0.0.0:0".parse().unwrap()
+	}
+
+	fn chat(x: &str) -> ClientMessage
+	{
+		ClientMessage::Chat(String::from(x))
+	}
+
+	// Connects and registers the players, they get IDs in the given order
+	fn players(names: &[&str]) -> (TestServer, Vec<TestClient>)
+	{
+		let mut server = TestServer::start();
+		let mut clients = vec![];
+		for name in names
+		{
+			let mut c = server.connect();
+			c.register(name, "");
+			c.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+			clients.push(c);
+		}
+		(server, clients)
+	}
+
+	// Handles `msg` as if player `id` sent it
+	fn request(server: &mut TestServer, id: u8, msg: ServerMessage)
+	{
+		server.server.requests.push((id, msg));
+		server.step(Duration::from_millis(1));
+	}
+
+	#[test]
+	fn chatReachesEveryPlayer()
+	{
+		let mut server = TestServer::start();
+		assert_ne!(server.port(), 0);
+
+		// Login comes first, before the client says anything
+		let mut alice = server.connect();
+		let login = alice.expect(&mut server, |x| matches!(x, ClientMessage::Login(..)));
+		assert_eq!(login, ClientMessage::Login(1, String::from("noname"), String::from("unknown")));
+		let mut bob = server.connect();
+		bob.expect(&mut server, |x| matches!(x, ClientMessage::Login(2, ..)));
+
+		alice.register("Alice", "");
+		alice.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		bob.register("Bob", "");
+		let joined = alice.expect(&mut server, |x| matches!(x, ClientMessage::Joined(..)));
+		assert_eq!(joined, ClientMessage::Joined(2, String::from("Bob"), String::from("unknown")));
+
+		alice.chat("Привет");
+		server.step(Duration::from_millis(10));
+		let text = ClientMessage::Chat(String::from("Alice: Привет"));
+		assert_eq!(bob.expect(&mut server, |x| *x == text), text);
+		assert_eq!(alice.expect(&mut server, |x| *x == text), text);
+	}
+
+	#[test]
+	fn nullInNameIsRefused()
+	{
+		let mut server = TestServer::start();
+		let mut c = server.connect();
+		c.drain(&mut server);
+
+		// A second separator, a separator without the flags byte, a null in the class
+		for frame in [&b"Al\0x\0ice"[..], b"Alice\0", b"Alice\0\0a\0b"]
+		{
+			c.send(1, frame);
+			assert!(!c.drain(&mut server).iter().any(|x| matches!(x, ClientMessage::Token(_))));
+			assert_eq!(server.server.clients[0].name, "noname");
+		}
+
+		c.send(1, b"Alice\0\0");
+		c.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		assert_eq!(server.server.clients[0].name, "Alice");
+	}
+
+	#[test]
+	fn teamsayReachesOnlyTheTeam()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob", "Carol"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.cmd(2, web(), String::from("team 1"));
+		server.server.cmd(3, web(), String::from("team 2"));
+		server.server.cmd(1, web(), String::from("teamsay 1 сбор у ворот"));
+
+		let msg = chat("[Команде 1] Alice: сбор у ворот");
+		c[1].expect(&mut server, |x| *x == msg);
+		assert!(!c[2].drain(&mut server).contains(&msg));
+
+		server.server.cmd(1, web(), String::from("teamsay 9 никому"));
+		c[0].expect(&mut server, |x| *x == chat("[Команда не найдена, доступны 1-2]"));
+	}
+
+	#[test]
+	fn udpSourceFollowsPortOnlyWhenLenient()
+	{
+		let (mut server, _c) = players(&["Alice"]);
+		let s = &mut server.server;
+		let first = "127.0.0.1:40000".parse().unwrap();
+		let moved = "127.0.0.1:40001".parse().unwrap();
+		assert!(!s.checkUdpSource(1, "10.0.0.1:40000".parse().unwrap()));
+
+		assert!(s.checkUdpSource(1, first));
+		assert!(s.checkUdpSource(1, moved));
+		assert_eq!(s.clients[0].udp, Some(moved));
+
+		s.config.strictUdp = true;
+		assert!(!s.checkUdpSource(1, first));
+		assert_eq!(s.clients[0].udp, Some(moved));
+	}
+
+	#[test]
+	fn webSaveWritesTheSlot()
+	{
+		let _lock = configFile();
+		let mut server = TestServer::start();
+		let path = State::path("test503");
+		request(&mut server, 0, ServerMessage::SaveGame(String::from("мост"), String::from("test503"), web()));
+		let saved = json::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+		let _ = std::fs::remove_file(&path);
+		assert_eq!(saved["checkpoint"], "мост");
+		assert_eq!(server.server.state.slot, "test503");
+
+		request(&mut server, 0, ServerMessage::SaveGame(String::new(), String::from("../x"), web()));
+		assert_eq!(server.server.state.slot, "test503");
+	}
+
+	#[test]
+	fn historyTagsEveryKind()
+	{
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		request(&mut server, 1, ServerMessage::Chat(String::from("привет"), web()));
+		server.server.cmd(1, web(), String::from("teamsay 1 сбор"));
+		server.server.cmd(1, web(), String::from("roll 1d6"));
+		server.server.cmd(1, web(), String::from("w bob секрет"));
+		let kinds = server.server.state.chatHistory.iter().map(|x| x.kind).collect::<Vec<ChatKind>>();
+		assert_eq!(kinds, vec![ChatKind::User, ChatKind::Broadcast, ChatKind::System, ChatKind::Whisper]);
+
+		// Whispers stay out of the dashboard
+		let (status, body) = server.post("{ \"getChat\": { \"messagesLength\": 0 } }", None);
+		assert_eq!(status, 200);
+		let types = json::parse(&body).unwrap().members()
+			.map(|x| x["type"].as_str().unwrap().to_string())
+			.collect::<Vec<String>>();
+		assert_eq!(types, vec!["system", "broadcast", "user"]);
+	}
+
+	#[test]
+	fn shutdownNeedsRightsAndSaves()
+	{
+		let _lock = configFile();
+		let (mut server, mut c) = players(&["Alice"]);
+		server.server.config.webToken = String::from("secret");
+		server.server.state.slot = String::from("test504");
+		server.server.cmd(1, web(), String::from("stop"));
+		assert!(server.server.isRunning());
+		c[0].expect(&mut server, |x| *x == chat("[Недостаточно прав]"));
+		assert_eq!(server.post("{ \"shutdown\": {} }", None).0, 401);
+		assert!(server.server.isRunning());
+
+		let tcp = server.server.tcpAddr().unwrap();
+		let udp = server.server.udpAddr().unwrap();
+		let webPort = server.server.webAddr().unwrap().unwrap();
+		assert_eq!(server.post("{ \"shutdown\": {} }", Some("secret")).0, 200);
+		assert!(!server.server.isRunning());
+		assert!(c[0].receive().contains(&ClientMessage::Disconnected(1)));
+		assert!(std::fs::remove_file(State::path("test504")).is_ok());
+
+		// Every port is free again
+		assert!(server.server.tcpAddr().is_err() && server.server.udpAddr().is_err());
+		assert!(std::net::TcpListener::bind(tcp).is_ok());
+		assert!(std::net::TcpListener::bind(webPort).is_ok());
+		assert!(std::net::UdpSocket::bind(udp).is_ok());
+	}
+
+	#[test]
+	fn damageShowsInPlayersList()
+	{
+		let mut server = TestServer::start();
+		let warrior = ClassInfo { name: String::from("Воин"), hp: 150, mana: 20 };
+		server.server.config.classes.insert(String::from("warrior"), warrior);
+		let mut c = server.connect();
+		c.register("Alice", "warrior");
+		c.expect(&mut server, |x| *x == ClientMessage::Stats(1, (150, 150), (20, 20)));
+
+		request(&mut server, 1, ServerMessage::ChangeStats(-40, -5));
+		let list = server.server.playersListJson();
+		assert_eq!(list[0]["hp"]["current"], 110);
+		assert_eq!(list[0]["hp"]["max"], 150);
+		assert_eq!(list[0]["mana"]["current"], 15);
+
+		// Clamped to 0 and the maximum
+		request(&mut server, 1, ServerMessage::ChangeStats(-500, 100));
+		c.expect(&mut server, |x| *x == ClientMessage::Stats(1, (0, 150), (20, 20)));
+	}
+
+	#[test]
+	fn conninfoReportsLoss()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.cmd(1, web(), String::from("conninfo bob"));
+		let report = |x: &ClientMessage| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Соединение bob]"));
+		let msg = c[0].expect(&mut server, report);
+		assert!(matches!(&msg, ClientMessage::Chat(t) if t.contains("потери: нет данных") && t.ends_with("UDP: нет данных")));
+
+		assert!(matches!(&msg, ClientMessage::Chat(t) if t.contains("RTT: нет данных")));
+
+		server.server.clients[1].udpReceived = 9;
+		server.server.clients[1].udpLost = 1;
+		server.server.cmd(1, web(), String::from("conninfo bob"));
+		let msg = c[0].expect(&mut server, report);
+		assert!(matches!(&msg, ClientMessage::Chat(t) if t.contains("потери: 10.0%")));
+
+		// Bob answers a Ping sent 120 ms before the Pong is handled
+		let stamp = server.server.stamp(server.now);
+		server.server.requests.push((2, ServerMessage::Pong(stamp)));
+		server.step(Duration::from_millis(120));
+		assert_eq!(server.server.clients[1].rtt, Some(Duration::from_millis(120)));
+		server.server.cmd(1, web(), String::from("conninfo bob"));
+		let msg = c[0].expect(&mut server, report);
+		assert!(matches!(&msg, ClientMessage::Chat(t) if t.contains("RTT: 120 мс,")));
+
+		// Stamps from the future are ignored
+		let future = server.server.stamp(server.now) + 5000;
+		request(&mut server, 2, ServerMessage::Pong(future));
+		assert_eq!(server.server.clients[1].rtt, Some(Duration::from_millis(120)));
+
+		// Over the wire: the Ping comes with the Token, its stamp goes back as is
+		let mut carol = server.connect();
+		carol.register("Carol", "");
+		let ping = carol.expect(&mut server, |x| matches!(x, ClientMessage::Ping(_)));
+		let ClientMessage::Ping(stamp) = ping else { unreachable!() };
+		server.step(Duration::from_millis(40));
+		carol.send(10, &stamp.to_le_bytes());
+		carol.drain(&mut server);
+		assert!(server.server.clients[2].rtt.is_some_and(|x| x >= Duration::from_millis(40)));
+	}
+
+	#[test]
+	fn defaultGroupAppliesToUnknownPlayers()
+	{
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		server.server.config.defaultPermission = Permission::Guest;
+		server.server.config.permissions.insert(String::from("Bob"), Permission::Player);
+		server.server.cmd(1, web(), String::from("team 1"));
+		server.server.cmd(2, web(), String::from("team 1"));
+		assert_eq!(server.server.clients[0].team, 0);
+		assert_eq!(server.server.clients[1].team, 1);
+	}
+
+	#[test]
+	fn emptyChatIsIgnored()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		c[1].drain(&mut server);
+		for msg in ["", "/", "   ", " / "]
+		{
+			c[0].chat(msg);
+			request(&mut server, 1, ServerMessage::Chat(String::from(msg), web()));
+		}
+		assert!(!c[1].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::Chat(_))));
+		assert!(server.server.state.chatHistory.is_empty());
+	}
+
+	#[test]
+	fn chatCooldownFollowsTheClock()
+	{
+		let (mut server, mut c) = players(&["Alice"]);
+		server.server.config.chatCooldown = Duration::from_secs(1);
+		let say = |server: &mut TestServer, x: &str| request(server, 1, ServerMessage::Chat(String::from(x), web()));
+		say(&mut server, "раз");
+		say(&mut server, "два");
+		c[0].expect(&mut server, |x| *x == chat("Вы отправляете сообщения слишком часто"));
+		server.step(Duration::from_secs(2));
+		say(&mut server, "три");
+		let history = server.server.state.chatHistory.iter().map(|x| x.msg.clone()).collect::<Vec<String>>();
+		assert_eq!(history, vec!["раз", "три"]);
+	}
+
+	#[test]
+	fn historyPagesAreNewestFirst()
+	{
+		let mut server = TestServer::start();
+		for i in 0..5 { server.server.addChat(String::from("Alice"), i.to_string(), ChatKind::User); }
+		let mut page = |start: usize|
+		{
+			let (_, body) = server.post(&format!("{{ \"getChat\": {{ \"messagesLength\": {start} }} }}"), None);
+			json::parse(&body).unwrap().members().map(|x| x["msg"].to_string()).collect::<Vec<String>>()
+		};
+		assert_eq!(page(0), vec!["4", "3", "2", "1", "0"]);
+		assert_eq!(page(3), vec!["4", "3"]);
+		assert!(page(5).is_empty());
+		assert!(page(9).is_empty());
+	}
+
+	#[test]
+	fn banPersistsAndRefusesConnections()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.cmd(1, web(), String::from("ban bob"));
+		c[1].expect(&mut server, |x| *x == ClientMessage::Disconnected(2));
+		assert_eq!(server.server.clients[1].id, 0);
+		let ip = "127.0.0.1".parse().unwrap();
+		assert!(server.server.state.banned.contains(&ip));
+
+		server.server.state.slot = String::from("test510");
+		server.server.state.save(String::new(), 0).unwrap();
+		let saved = State::open("test510").unwrap();
+		let _ = std::fs::remove_file(State::path("test510"));
+		assert!(saved.banned.contains(&ip));
+
+		let mut late = server.connect();
+		assert!(late.drain(&mut server).is_empty());
+		server.server.cmd(1, web(), String::from("unban 127.0.0.1"));
+		assert!(server.server.state.banned.is_empty());
+		let mut late = server.connect();
+		late.expect(&mut server, |x| matches!(x, ClientMessage::Login(2, ..)));
+	}
+
+	#[test]
+	fn bannerShowsTheEffectiveConfig()
+	{
+		let server = TestServer::start();
+		let banner = server.server.banner();
+		let lines = banner.lines().collect::<Vec<&str>>();
+		assert_eq!(lines[0], format!("Envell v{}", env!("CARGO_PKG_VERSION")));
+		// Port 0 in the config, the real ports are shown
+		assert!(lines[1].starts_with(&format!("TCP: 127.0.0.1:{}, UDP: 127.0.0.1:", server.port())));
+		assert!(!lines[1].contains(":0,"));
+		assert_eq!(lines[2], "Tick rate: 1, max players: 5");
+		assert_eq!(lines[3], "Features: web");
+	}
+
+	#[test]
+	fn mutedPlayersCantChat()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.cmd(1, web(), String::from("mute bob"));
+		c[1].expect(&mut server, |x| *x == chat("Вам запрещено писать в чат"));
+		c[1].chat("спам");
+		c[1].expect(&mut server, |x| *x == chat("Вы не можете писать в чат"));
+		assert!(!c[0].drain(&mut server).contains(&chat("Bob: спам")));
+
+		server.server.cmd(1, web(), String::from("unmute bob"));
+		c[1].chat("извините");
+		c[0].expect(&mut server, |x| *x == chat("Bob: извините"));
+	}
+
+	#[test]
+	fn sharedPortTellsWebFromGame()
+	{
+		let mut server = TestServer::with(Config { sharedPort: true, ..TestServer::config() });
+		assert!(server.server.webAddr().is_none());
+		let (status, body) = server.post("{ \"players\": {} }", None);
+		assert_eq!((status, body.as_str()), (200, "[]"));
+
+		// The game client waits for Login and says nothing first
+		let mut c = server.connect();
+		assert!(c.receive().is_empty());
+		std::thread::sleep(PEEK_TIMEOUT);
+		c.expect(&mut server, |x| matches!(x, ClientMessage::Login(1, ..)));
+		assert!(server.server.pending.is_empty());
+	}
+
+	#[test]
+	fn sharedPortCapsPendingSockets()
+	{
+		let mut server = TestServer::with(Config { sharedPort: true, ..TestServer::config() });
+		let sockets = (0..MAX_PENDING + 3).map(|_| server.connect()).collect::<Vec<TestClient>>();
+		assert_eq!(server.server.pending.len(), MAX_PENDING);
+		drop(sockets);
+	}
+
+	#[test]
+	fn dashboardListsAndLiftsBansAndMutes()
+	{
+		let (mut server, _c) = players(&["Alice"]);
+		server.server.config.webToken = String::from("secret");
+		server.server.state.banned.insert("10.0.0.7".parse().unwrap());
+		server.server.clients[0].muted = true;
+		let token = Some("secret");
+
+		for cmd in ["listBans", "listMutes", "unban", "unmute"]
+		{
+			assert_eq!(server.post(&format!("{{ \"{cmd}\": {{}} }}"), None).0, 401);
+		}
+		let (_, bans) = server.post("{ \"listBans\": {} }", token);
+		assert_eq!(json::parse(&bans).unwrap()[0]["ip"], "10.0.0.7");
+		let (_, mutes) = server.post("{ \"listMutes\": {} }", token);
+		assert_eq!(json::parse(&mutes).unwrap()[0]["name"], "Alice");
+
+		let (_, bans) = server.post("{ \"unban\": { \"ip\": \"10.0.0.7\" } }", token);
+		assert_eq!(bans, "[]");
+		let (_, mutes) = server.post("{ \"unmute\": { \"name\": \"Alice\" } }", token);
+		assert_eq!(mutes, "[]");
+		assert!(!server.server.clients[0].isMuted());
+	}
+
+	#[test]
+	fn reloadAppliesTheFileUnlessPlayersDontFit()
+	{
+		let _lock = configFile();
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		let write = |tickRate: u8, maxPlayersCount: u8|
+		{
+			let config = Config { tickRate, maxPlayersCount, ..Config::default() };
+			config.save();
+		};
+
+		write(20, 4);
+		request(&mut server, 0, ServerMessage::ReloadConfig(web()));
+		assert_eq!(server.server.config.sendTime.as_millis(), 50);
+		assert_eq!(server.server.clients.len(), 4);
+		assert_eq!(server.server.tcpAddr().unwrap().port(), server.port());
+
+		// P2 doesn't fit into one slot
+		write(10, 1);
+		request(&mut server, 0, ServerMessage::ReloadConfig(web()));
+		let _ = std::fs::remove_file(format!("{RES_DIR}/config.json"));
+		assert_eq!(server.server.config.tickRate, 20);
+		assert_eq!(server.server.clients.len(), 4);
+	}
+
+	#[test]
+	fn trailFollowsReportedPositions()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		for (x, y) in [(10, 20), (10, 20), (30, 40), (50, 60)]
+		{
+			request(&mut server, 2, ServerMessage::SetPosition(x, y));
+		}
+		server.server.cmd(1, web(), String::from("trail bob"));
+		let msg = c[0].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Перемещения bob]")));
+		let ClientMessage::Chat(text) = msg else { unreachable!() };
+		let points = text.matches('(').count();
+		let (a, b, d) = (text.find("(50;60)"), text.find("(30;40)"), text.find("(10;20)"));
+		assert_eq!(points, 3);
+		assert!(a < b && b < d && a.is_some());
+
+		// Players can't look
+		server.server.cmd(2, web(), String::from("trail Alice"));
+		assert!(!c[1].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Перемещения"))));
+	}
+
+	#[test]
+	fn healthNeedsNoToken()
+	{
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		server.server.config.webToken = String::from("secret");
+		let (status, body) = server.get("/health");
+		let health = json::parse(&body).unwrap();
+		assert_eq!(status, 200);
+		assert_eq!(health["players"].as_usize(), Some(2));
+		assert!(health["uptime"].is_number() && !health.has_key("webConnections"));
+
+		let health = json::parse(&server.get("/health?verbose=1").1).unwrap();
+		assert!(health["webConnections"].is_number());
+	}
+
+	#[test]
+	fn playersListGoesOnlyToTheRequester()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		c[0].drain(&mut server);
+		c[1].send(5, &[]);
+		let list = c[1].expect(&mut server, |x| matches!(x, ClientMessage::PlayersList(_)));
+		let ClientMessage::PlayersList(list) = list else { unreachable!() };
+		let names = list.into_iter().map(|(_, name, _)| name).collect::<Vec<String>>();
+		assert_eq!(names, ["Alice", "Bob"]);
+		assert!(!c[0].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::PlayersList(_))));
+	}
+
+	#[test]
+	fn resyncSendsTheFullState()
+	{
+		let config = Config { keyframeInterval: Duration::from_secs(3600), ..TestServer::config() };
+		let mut server = TestServer::with(config);
+		let mut c = vec![];
+		for name in ["Alice", "Bob", "Carol"]
+		{
+			let mut client = server.connect();
+			client.register(name, "");
+			client.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+			c.push(client);
+		}
+		request(&mut server, 1, ServerMessage::SetPosition(10, 20));
+		request(&mut server, 2, ServerMessage::SetPosition(30, 40));
+		c[2].drain(&mut server);
+
+		// Nothing moved since, so there is nothing to send
+		assert!(!c[2].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::State(_))));
+
+		server.server.cmd(3, web(), String::from("resync"));
+		let state = c[2].expect(&mut server, |x| matches!(x, ClientMessage::State(_)));
+		let ClientMessage::State(state) = state else { unreachable!() };
+		assert_eq!(state.len(), 18);
+		assert_eq!((state[0] & 0b111, state[9] & 0b111), (1, 2));
+	}
+
+	#[test]
+	fn whisperReachesOnlyTwoPlayers()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob", "Carol"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Guest);
+		for x in &mut c { x.drain(&mut server); }
+
+		c[0].chat("/w bob тихо");
+		let whisper = chat("[шёпот] Alice -> Bob: тихо");
+		c[0].expect(&mut server, |x| *x == whisper);
+		c[1].expect(&mut server, |x| *x == whisper);
+		assert!(!c[2].drain(&mut server).contains(&whisper));
+
+		c[0].chat("/w dave эй");
+		c[0].expect(&mut server, |x| *x == chat("[Игрок dave не найден]"));
+		assert!(c[1].drain(&mut server).is_empty() && c[2].drain(&mut server).is_empty());
+
+		// Kept in the history, but not shown on the dashboard
+		assert_eq!(server.server.state.chatHistory.back().unwrap().kind, ChatKind::Whisper);
+		let (status, body) = server.post("{ \"getChat\": { \"messagesLength\": 0 } }", None);
+		assert_eq!(status, 200);
+		assert!(!body.contains("тихо"));
+	}
+
+	#[test]
+	fn reloadpermsLeavesTheRestAlone()
+	{
+		let _lock = configFile();
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Developer);
+		server.server.config.tickRate = 42;
+		let mut file = Config { tickRate: 10, ..Config::default() };
+		file.setPermission(String::from("Bob"), Permission::Admin);
+		file.save();
+
+		server.server.cmd(1, web(), String::from("reloadperms"));
+		let _ = std::fs::remove_file(format!("{RES_DIR}/config.json"));
+		c[0].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Права перезагружены")));
+		assert_eq!(server.server.config.getPermission(&String::from("Bob")), Permission::Admin);
+		assert_eq!(server.server.config.getPermission(&String::from("Alice")), Permission::Developer);
+		assert_eq!(server.server.config.tickRate, 42);
+	}
+
+	#[test]
+	fn positionsSkipPlayersThatNeverMoved()
+	{
+		let (mut server, _c) = players(&["Alice", "Bob", "Carol"]);
+		request(&mut server, 1, ServerMessage::SetPosition(10, 20));
+		request(&mut server, 3, ServerMessage::SetPosition(300, 400));
+		let (status, body) = server.post("{ \"positions\": {} }", None);
+		let list = json::parse(&body).unwrap();
+		assert_eq!(status, 200);
+		assert_eq!(list.len(), 2);
+		assert_eq!(list[0], json::object! { id: 1, name: "Alice", x: 10, y: 20 });
+		assert_eq!(list[1], json::object! { id: 3, name: "Carol", x: 300, y: 400 });
+	}
+
+	#[test]
+	fn tcpPositionsReachOthers()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		c[0].send(6, &[&300u16.to_le_bytes() as &[u8], &400u16.to_le_bytes()].concat());
+		let state = c[1].expect(&mut server, |x| matches!(x, ClientMessage::State(s) if s.len() == 9 && s[0] & 0b111 == 1));
+		let ClientMessage::State(state) = state else { unreachable!() };
+		assert_eq!(u16::from_le_bytes([state[1], state[2]]), 300);
+		assert_eq!(u16::from_le_bytes([state[3], state[4]]), 400);
+		assert!(server.server.clients[0].udp.is_none());
+	}
+
+	#[test]
+	fn versionMatchesTheConstants()
+	{
+		let (mut server, mut c) = players(&["Alice"]);
+		let version = env!("CARGO_PKG_VERSION");
+		assert!(!version.is_empty());
+		server.server.cmd(1, web(), String::from("version"));
+		let reply = chat(&format!("[Версия сервера: {version}, протокол: {PROTOCOL_VERSION}]"));
+		c[0].expect(&mut server, |x| *x == reply);
+
+		let stats = json::parse(&server.get("/api/stats").1).unwrap();
+		assert_eq!(stats["version"], version);
+		assert_eq!(stats["protocol"].as_u8(), Some(PROTOCOL_VERSION));
+	}
+
+	#[test]
+	fn reconnectRestoresTheSlot()
+	{
+		let mut server = TestServer::start();
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		let token = alice.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		let ClientMessage::Token(token) = token else { unreachable!() };
+		let mut bob = server.connect();
+		bob.register("Bob", "");
+		bob.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		request(&mut server, 1, ServerMessage::SetPosition(70, 80));
+
+		drop(alice);
+		for _ in 0..5 { server.step(Duration::from_millis(10)); }
+		assert_eq!(server.server.clients[0].id, 0);
+
+		// The new connection gets a free slot first and moves into the old one
+		let mut alice = server.connect();
+		alice.expect(&mut server, |x| matches!(x, ClientMessage::Login(3, ..)));
+		alice.send(7, &token.to_le_bytes());
+		let msgs = alice.drain(&mut server);
+		assert!(msgs.iter().any(|x| matches!(x, ClientMessage::Login(1, name, _) if name == "Alice")));
+		assert!(msgs.contains(&ClientMessage::Token(token)));
+		assert_eq!(server.server.getPosition(1), (70, 80));
+		assert_eq!(server.server.clients[2].id, 0);
+
+		// Tokens work once
+		let mut other = server.connect();
+		other.send(7, &token.to_le_bytes());
+		other.expect(&mut server, |x| *x == chat("Не удалось восстановить подключение"));
+	}
+
+	#[test]
+	fn tpSendsTheTargetsPosition()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob", "Carol"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		request(&mut server, 3, ServerMessage::SetPosition(123, 456));
+		for x in &mut c { x.drain(&mut server); }
+
+		server.server.cmd(1, web(), String::from("tp bob carol"));
+		c[1].expect(&mut server, |x| *x == ClientMessage::SetPosition(123, 456));
+		c[0].expect(&mut server, |x| *x == chat("[Игрок bob перемещён к carol]"));
+		assert!(!c[2].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::SetPosition(..))));
+
+		server.server.cmd(1, web(), String::from("tp bob dave"));
+		c[0].expect(&mut server, |x| *x == chat("[Игрок dave не найден]"));
+		server.server.cmd(1, web(), String::from("tp bob"));
+		c[0].expect(&mut server, |x| *x == chat("[Использование: /tp <кого> <к кому>]"));
+		assert!(c[1].drain(&mut server).is_empty() && c[2].drain(&mut server).is_empty());
+	}
+
+	#[test]
+	fn joinIsAnnouncedAndTheRosterSent()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		for x in &mut c { x.drain(&mut server); }
+
+		let mut carol = server.connect();
+		carol.register("Carol", "mage");
+		let joined = ClientMessage::Joined(3, String::from("Carol"), String::from("mage"));
+		c[0].expect(&mut server, |x| *x == joined);
+		c[1].expect(&mut server, |x| *x == joined);
+		let roster = carol.expect(&mut server, |x| matches!(x, ClientMessage::PlayersList(_)));
+		let ClientMessage::PlayersList(roster) = roster else { unreachable!() };
+		let ids = roster.iter().map(|(id, name, _)| (*id, name.as_str())).collect::<Vec<(u8, &str)>>();
+		assert_eq!(ids, [(1, "Alice"), (2, "Bob"), (3, "Carol")]);
+		assert!(!carol.drain(&mut server).contains(&joined));
+	}
+
+	#[test]
+	fn socketsBindToTheConfiguredAddress()
+	{
+		let server = TestServer::start();
+		let loopback = "127.0.0.1".parse::<std::net::IpAddr>().unwrap();
+		assert_eq!(server.addr().ip(), loopback);
+		assert_eq!(server.server.udpAddr().unwrap().ip(), loopback);
+		assert_eq!(server.server.webAddr().unwrap().unwrap().ip(), loopback);
+
+		assert!(Config { bindAddress: String::from("localhost"), ..Config::default() }.bindIP().is_err());
+		assert!(Config { bindAddress: String::from("::1"), ..Config::default() }.bindIP().is_ok());
+	}
+
+	#[test]
+	fn silentClientsTimeOut()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		let timeout = server.server.config.clientTimeout;
+		for x in &mut c { x.drain(&mut server); }
+
+		// Bob is heard from halfway, Alice never again
+		server.step(timeout / 2);
+		server.server.clients[1].lastSeen = server.now;
+		server.step(timeout / 2 + Duration::from_secs(1));
+		assert_eq!(server.server.clients[0].id, 0);
+		assert_eq!(server.server.clients[1].id, 2);
+		c[1].expect(&mut server, |x| *x == ClientMessage::Disconnected(1));
+	}
+
+	#[test]
+	fn playersGetMatchesThePost()
+	{
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		request(&mut server, 2, ServerMessage::ChangeStats(-10, 0));
+		let (status, get) = server.get("/api/players");
+		let (_, post) = server.post("{ \"players\": {} }", None);
+		assert_eq!(status, 200);
+		assert_eq!(json::parse(&get).unwrap(), json::parse(&post).unwrap());
+		assert_eq!(json::parse(&get).unwrap(), server.server.playersListJson());
+		assert_eq!(json::parse(&get).unwrap().len(), 2);
+	}
+
+	#[test]
+	fn playersCommandIsSortedByName()
+	{
+		let (mut server, mut c) = players(&["Carol", "alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Bob"), Permission::Admin);
+		c[0].chat("/players");
+		c[0].expect(&mut server, |x| *x == chat("[Игроки: alice (player), Bob (admin), Carol (player)]"));
+		assert!(!c[1].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Игроки"))));
+	}
+
+	#[test]
+	fn udpForEmptyOrInvalidSlotsIsDropped()
+	{
+		let (mut server, _c) = players(&["Alice"]);
+		let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		let target = server.server.udpAddr().unwrap();
+		let send = |id: u8, sequence: u16|
+		{
+			let packet = PlayerStatePacket { flags: 0, id, x: 500, y: 600, extra: [0; 4], sequence };
+			udp.send_to(&packet.encode(), target).unwrap();
+		};
+
+		// 0, an empty slot and one past maxPlayersCount
+		assert_eq!(server.server.config.maxPlayersCount, 5);
+		for id in [0, 2, 6] { send(id, 1); }
+		for _ in 0..5 { server.step(Duration::from_millis(10)); }
+		assert!(server.server.playersState.iter().all(|s| s[1..5] == [0; 4]));
+		assert_eq!(server.server.clients[0].udp, None);
+
+		send(1, 2);
+		for _ in 0..5 { server.step(Duration::from_millis(10)); }
+		assert_eq!(server.server.getPosition(1), (500, 600));
+	}
+
+	#[test]
+	fn metricsArePrometheusText()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		c[0].chat("привет");
+		c[1].expect(&mut server, |x| *x == chat("Alice: привет"));
+		let (status, body) = server.get("/metrics");
+		assert_eq!(status, 200);
+		assert!(body.lines().any(|x| x == "players_connected 2"));
+		assert!(body.lines().any(|x| x == "chat_history_len 1"));
+		for stat in ["min", "avg", "max"]
+		{
+			let line = body.lines().find(|x| x.starts_with(&format!("tick_duration_seconds{{stat=\"{stat}\"}} ")));
+			assert!(line.is_some_and(|x| x.rsplit(' ').next().unwrap().parse::<f64>().is_ok()), "{stat}");
+		}
+		assert!(body.contains("# TYPE udp_packets_per_second gauge\nudp_packets_per_second 0\n"));
+	}
+
+	#[test]
+	fn motdIsFilledIn()
+	{
+		let config = Config { motd: String::from("Привет, {name}! Онлайн: {players}"), ..TestServer::config() };
+		let mut server = TestServer::with(config);
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		alice.expect(&mut server, |x| *x == chat("Привет, Alice! Онлайн: 1"));
+		let mut bob = server.connect();
+		bob.register("Bob", "");
+		bob.expect(&mut server, |x| *x == chat("Привет, Bob! Онлайн: 2"));
+		assert!(!alice.drain(&mut server).contains(&chat("Привет, Bob! Онлайн: 2")));
+
+		// Empty MOTD sends nothing
+		let mut server = TestServer::start();
+		let mut carol = server.connect();
+		carol.register("Carol", "");
+		let msgs = carol.drain(&mut server);
+		assert!(msgs.iter().any(|x| matches!(x, ClientMessage::Token(_))));
+		assert!(!msgs.iter().any(|x| matches!(x, ClientMessage::Chat(_))));
+	}
+
+	#[test]
+	fn helpListsOnlyAllowedCommands()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		let help = |c: &mut TestClient, server: &mut TestServer|
+		{
+			c.chat("/help");
+			let msg = c.expect(server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Доступные команды]")));
+			let ClientMessage::Chat(text) = msg else { unreachable!() };
+			text
+		};
+		let (admin, player) = (help(&mut c[0], &mut server), help(&mut c[1], &mut server));
+		for text in [&admin, &player]
+		{
+			assert!(text.contains("\n/help - ") && text.contains("\n/players - "));
+		}
+		assert!(admin.contains("\n/ban ") && admin.contains("\n/trail "));
+		assert!(!player.contains("\n/ban ") && !player.contains("\n/trail "));
+	}
+
+	#[test]
+	fn multibyteChatDoesntPanic()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		for text in ["/тест", "/😀", "😀", "ё"]
+		{
+			c[0].chat(text);
+		}
+		let msgs = c[1].drain(&mut server);
+		assert!(msgs.contains(&chat("Alice: 😀")) && msgs.contains(&chat("Alice: ё")));
+		assert!(!msgs.iter().any(|x| matches!(x, ClientMessage::Chat(t) if t.contains("тест"))));
+		assert!(server.server.isRunning());
+	}
+
+	#[test]
+	fn ackFollowsRegisterOnlyWhenAsked()
+	{
+		let mut server = TestServer::start();
+		let mut alice = server.connect();
+		alice.send(1, &["Alice".as_bytes(), &[0, CAP_ACK]].concat());
+		let msgs = alice.drain(&mut server);
+		let token = msgs.iter().position(|x| matches!(x, ClientMessage::Token(_)));
+		let ack = msgs.iter().position(|x| *x == ClientMessage::Ack(1, true));
+		assert!(token.is_some() && ack > token);
+		alice.chat("");
+		alice.expect(&mut server, |x| *x == ClientMessage::Ack(2, false));
+
+		let mut bob = server.connect();
+		bob.register("Bob", "");
+		let msgs = bob.drain(&mut server);
+		assert!(msgs.iter().any(|x| matches!(x, ClientMessage::Token(_))));
+		assert!(!msgs.iter().any(|x| matches!(x, ClientMessage::Ack(..))));
+	}
+
+	#[test]
+	fn commandCooldownRejectsTheSecondCall()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.cooldowns.insert(String::from("version"), Duration::from_secs(3600));
+		server.server.config.permissions.insert(String::from("Bob"), Permission::Developer);
+		let isVersion = |x: &ClientMessage| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Версия сервера"));
+
+		c[0].chat("/version");
+		c[0].expect(&mut server, isVersion);
+		c[0].chat("/version");
+		c[0].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Команда /version будет доступна через")));
+		for _ in 0..2
+		{
+			c[1].chat("/version");
+			c[1].expect(&mut server, isVersion);
+		}
+	}
+
+	#[test]
+	fn saveCommandWritesTheCheckpoint()
+	{
+		let _lock = configFile();
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.state.slot = String::from("test546");
+
+		c[1].chat("/save обход");
+		c[0].chat("/save Camp1");
+		c[0].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Игра сохранена на Camp1: ")));
+		let saved = State::open("test546");
+		let _ = std::fs::remove_file(State::path("test546"));
+		assert_eq!(saved.unwrap().checkpoint, "Camp1");
+		assert_eq!(server.server.state.checkpoint, "Camp1");
+		let entry = server.server.state.chatHistory.back().unwrap();
+		assert!(entry.kind == ChatKind::System && entry.msg.contains("Camp1") && !entry.time.is_empty());
+		assert!(!c[1].drain(&mut server).iter().any(|x| matches!(x, ClientMessage::Chat(t) if t.contains("сохранена"))));
+	}
+
+	#[test]
+	fn chatHistoryIsSharedBetweenThreads()
+	{
+		// Same shape as getInstance, without the ports of the real config
+		let server = std::sync::Mutex::new(TestServer::start().server);
+		std::thread::scope(|s|
+		{
+			for t in 0..2
+			{
+				let server = &server;
+				s.spawn(move ||
+				{
+					for i in 0..100
+					{
+						let mut guard = server.lock().unwrap();
+						guard.addChat(format!("T{t}"), i.to_string(), ChatKind::User);
+					}
+				});
+			}
+		});
+		let server = server.into_inner().unwrap();
+		assert_eq!(server.state.chatTotal, 200);
+		assert!(server.state.chatHistory.iter().all(|x| x.user == "T0" || x.user == "T1"));
+	}
+
+	#[test]
+	fn deltaOmitsPlayersThatDidntMove()
+	{
+		let config = Config
+		{
+			keyframeInterval: Duration::from_secs(3600),
+			// Nobody times out while the clock jumps to the keyframe
+			clientTimeout: Duration::ZERO,
+			..TestServer::config()
+		};
+		let mut server = TestServer::with(config);
+		let mut c = vec![];
+		for name in ["Alice", "Bob", "Carol"]
+		{
+			let mut client = server.connect();
+			client.register(name, "");
+			client.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+			c.push(client);
+		}
+		request(&mut server, 1, ServerMessage::SetPosition(10, 20));
+		request(&mut server, 2, ServerMessage::SetPosition(30, 40));
+		c[2].drain(&mut server);
+
+		request(&mut server, 1, ServerMessage::SetPosition(11, 20));
+		let states = c[2].drain(&mut server).into_iter()
+			.filter_map(|x| match x { ClientMessage::State(s) => Some(s), _ => None })
+			.collect::<Vec<Vec<u8>>>();
+		assert_eq!(states.len(), 1);
+		assert_eq!((states[0].len(), states[0][0] & 0b111, states[0][1]), (9, 1, 11));
+
+		// The keyframe brings everyone again
+		server.step(Duration::from_secs(3601));
+		let state = c[2].expect(&mut server, |x| matches!(x, ClientMessage::State(_)));
+		assert!(matches!(state, ClientMessage::State(s) if s.len() == 18));
+	}
+
+	#[test]
+	fn filesComeFromTheConfiguredRoot()
+	{
+		let root = format!("{RES_DIR}/web549");
+		std::fs::create_dir_all(&root).unwrap();
+		std::fs::write(format!("{root}/index.html"), "<h1>другой фронт</h1>").unwrap();
+		let mut server = TestServer::with(Config { webRoot: root.clone(), ..TestServer::config() });
+		let index = server.get("/index.html");
+		let missing = server.get("/app.js");
+		let _ = std::fs::remove_dir_all(&root);
+		assert_eq!(index, (200, String::from("<h1>другой фронт</h1>")));
+		assert_eq!(missing.0, 404);
+
+		let _lock = configFile();
+		let report = server.server.preflight();
+		assert!(report.contains(&(root, "missing")));
+	}
+
+	#[test]
+	fn kickByNameAndByID()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob", "Carol", "Dave"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.config.permissions.insert(String::from("Dave"), Permission::Developer);
+
+		server.server.cmd(1, web(), String::from("kick bob"));
+		c[1].expect(&mut server, |x| *x == ClientMessage::Disconnected(2));
+		c[2].expect(&mut server, |x| *x == ClientMessage::Disconnected(2));
+		c[0].expect(&mut server, |x| *x == chat("[Игрок Bob выгнан]"));
+		server.server.cmd(1, web(), String::from("kickid 3"));
+		c[0].expect(&mut server, |x| *x == chat("[Игрок Carol выгнан]"));
+		assert_eq!((server.server.clients[1].id, server.server.clients[2].id), (0, 0));
+
+		for (cmd, reply) in [("kick eve", "[Игрок eve не найден]"), ("kickid 2", "[Игрок 2 не найден]"),
+			("kickid 9", "[Игрок 9 не найден]"), ("kick dave", "[Игрока Dave нельзя выгнать]")]
+		{
+			server.server.cmd(1, web(), String::from(cmd));
+			c[0].expect(&mut server, |x| *x == chat(reply));
+		}
+		assert_eq!(server.server.clients[3].id, 4);
+	}
+
+	#[test]
+	fn webErrorsTellWhatWentWrong()
+	{
+		let mut server = TestServer::start();
+		let cases =
+		[
+			("не json", 400, "malformed"),
+			("{ \"chat\": 5 }", 400, "notObject"),
+			("{ \"chat\": {} }", 400, "missingField"),
+			("{ \"dance\": {} }", 404, "unknownCommand")
+		];
+		for (body, status, kind) in cases
+		{
+			let (code, response) = server.post(body, None);
+			assert_eq!((code, json::parse(&response).unwrap()["error"].as_str()), (status, Some(kind)), "{body}");
+		}
+	}
+
+	#[test]
+	fn positionsSurviveASaveAndComeBack()
+	{
+		let _lock = configFile();
+		let (mut server, _c) = players(&["Alice"]);
+		request(&mut server, 1, ServerMessage::SetPosition(70, 80));
+		request(&mut server, 0, ServerMessage::SaveGame(String::from("лагерь"), String::from("test555"), web()));
+		let saved = State::open("test555");
+		let _ = std::fs::remove_file(State::path("test555"));
+		let saved = saved.unwrap();
+		let ip = "127.0.0.1".parse().unwrap();
+		assert_eq!(saved.getPosition(ip), Some((70, 80)));
+
+		let mut server = TestServer::with(TestServer::config());
+		server.server.state = saved;
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		alice.expect(&mut server, |x| *x == ClientMessage::SetPosition(70, 80));
+
+		// Nothing saved, nothing sent
+		let (mut server, c) = players(&[]);
+		let mut bob = server.connect();
+		bob.register("Bob", "");
+		assert!(!bob.drain(&mut server).iter().any(|x| matches!(x, ClientMessage::SetPosition(..))));
+		assert!(c.is_empty());
+	}
+
+	#[test]
+	fn permPromotesAndIsSaved()
+	{
+		let _lock = configFile();
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Developer);
+		for x in &mut c { x.drain(&mut server); }
+
+		c[0].chat("/perm bob admin");
+		c[0].expect(&mut server, |x| *x == chat("[Права игрока Bob изменены на admin]"));
+		assert_eq!(server.server.config.getPermission(&String::from("Bob")), Permission::Admin);
+		let saved = std::fs::read_to_string(format!("{RES_DIR}/config.json")).unwrap();
+		assert!(json::parse(&saved).unwrap()["permissions"]["Bob"] == "admin");
+
+		// Applies to the very next command: an admin is told why the kick failed
+		c[1].chat("/kick alice");
+		c[1].expect(&mut server, |x| *x == chat("[Игрока Alice нельзя выгнать]"));
+
+		c[0].chat("/perm bob");
+		c[0].expect(&mut server, |x| *x == chat("[Права игрока Bob: admin]"));
+		c[0].chat("/perm bob king");
+		c[0].expect(&mut server, |x| *x == chat("[Неизвестная группа: king]"));
+		assert_eq!(server.server.config.getPermission(&String::from("Bob")), Permission::Admin);
+	}
+
+	#[test]
+	fn badNamesAreRefusedAndOthersCleaned()
+	{
+		let mut server = TestServer::start();
+		let mut c = server.connect();
+		c.drain(&mut server);
+		for (name, error) in [
+			("   ", "Имя не может быть пустым"),
+			("\n\t", "Имя не может быть пустым"),
+			(&"я".repeat(25), "Имя не может быть длиннее 24 символов")
+		]
+		{
+			request(&mut server, 1, ServerMessage::Register(String::from(name), 0, String::new()));
+			c.expect(&mut server, |x| *x == chat(error));
+			assert_eq!(server.server.clients[0].name, "noname");
+		}
+
+		// 24 characters are fine, whitespace around and control characters go away
+		request(&mut server, 1, ServerMessage::Register(" Ал\nиса".to_string() + &"я".repeat(19) + " ", 0, String::new()));
+		c.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		assert_eq!(server.server.clients[0].name, "Алиса".to_string() + &"я".repeat(19));
+	}
+
+	#[test]
+	fn duplicateNamesAreRenamedOrRefused()
+	{
+		let (mut server, mut c) = players(&["Alice", "Alice2"]);
+		let mut third = server.connect();
+		third.register("ALICE", "");
+		third.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		assert_eq!(server.server.clients[2].name, "ALICE3");
+		c[0].chat("/w alice3 привет");
+		third.expect(&mut server, |x| *x == chat("[шёпот] Alice -> ALICE3: привет"));
+
+		let mut config = TestServer::config();
+		config.renameDuplicates = false;
+		let mut server = TestServer::with(config);
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		alice.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		let mut other = server.connect();
+		other.register("alice", "");
+		let got = other.drain(&mut server);
+		assert!(got.contains(&chat("Это имя уже занято")));
+		assert!(!got.iter().any(|x| matches!(x, ClientMessage::Token(_))));
+	}
+
+	#[test]
+	fn keepaliveFollowsIdlePeriods()
+	{
+		let mut config = TestServer::config();
+		config.clientTimeout = Duration::ZERO;
+		let mut server = TestServer::with(config);
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		alice.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+
+		let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+		udp.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+		server.server.clients[0].udp = Some(udp.local_addr().unwrap());
+		let received = ||
+		{
+			let mut buffer = [0; 64];
+			udp.recv(&mut buffer).ok().map(|n| buffer[..n].to_vec())
+		};
+
+		// Alone on the server there are no states to send, only keepalives
+		server.step(Duration::from_millis(1));
+		assert_eq!(received(), Some(PlayerStatePacket::relay(&[])));
+		server.step(Duration::from_secs(4));
+		assert_eq!(received(), None);
+		server.step(Duration::from_secs(2));
+		assert_eq!(received(), Some(PlayerStatePacket::relay(&[])));
+
+		server.server.config.udpKeepalive = Duration::ZERO;
+		server.step(Duration::from_secs(10));
+		assert_eq!(received(), None);
+	}
+
+	#[test]
+	fn diceExpressionsAreParsed()
+	{
+		assert_eq!(super::Server::parseDice("2d6"), Some((2, 6)));
+		assert_eq!(super::Server::parseDice("d20"), Some((1, 20)));
+		for bad in ["", "2x6", "2d", "d1", "0d6", "21d6", "1d1001", "-1d6", "2d6d6"]
+		{
+			assert_eq!(super::Server::parseDice(bad), None, "{bad}");
+		}
+
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		for x in &mut c { x.drain(&mut server); }
+		c[0].chat("/roll 3d1");
+		c[0].expect(&mut server, |x| *x == chat("[Кубики задаются как NdM, N до 20, M от 2 до 1000]"));
+		assert!(c[1].drain(&mut server).is_empty());
+
+		c[0].chat("/roll 2d6");
+		let prefix = "Alice бросает 2d6: [";
+		let rolled = c[1].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with(prefix)));
+		let ClientMessage::Chat(text) = rolled else { unreachable!() };
+		let (rolls, total) = text[prefix.len()..].split_once("] = ").unwrap();
+		let rolls = rolls.split(", ").map(|x| x.parse::<u32>().unwrap()).collect::<Vec<u32>>();
+		assert!(rolls.len() == 2 && rolls.iter().all(|x| (1..=6).contains(x)));
+		assert_eq!(rolls.iter().sum::<u32>().to_string(), total);
+	}
+
+	#[test]
+	fn sameSeedRollsTheSame()
+	{
+		let rolls = |seed: Option<u64>|
+		{
+			let mut config = TestServer::config();
+			config.rngSeed = seed;
+			let mut server = TestServer::with(config);
+			let _alice = server.connect();
+			request(&mut server, 1, ServerMessage::Register(String::from("Alice"), 0, String::new()));
+			for _ in 0..5 { server.server.cmd(1, web(), String::from("roll 20d1000")); }
+			server.server.state.chatHistory.iter().map(|x| x.msg.clone()).collect::<Vec<String>>()
+		};
+		assert_eq!(rolls(Some(42)).len(), 5);
+		assert_eq!(rolls(Some(42)), rolls(Some(42)));
+		assert_ne!(rolls(Some(42)), rolls(Some(43)));
+		assert_ne!(rolls(None), rolls(None));
+	}
+
+	#[test]
+	fn deniedCommandsAreAudited()
+	{
+		let (mut server, mut c) = players(&["Alice"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Guest);
+		let stamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos();
+		let command = format!("kick test{stamp}");
+		c[0].chat(&format!("/{command}"));
+		c[0].chat(&format!("/players {stamp}"));
+		c[0].drain(&mut server);
+
+		let tail = super::Log::auditTail(1000);
+		let entry = |x: &str| tail.members().find(|e| e["command"] == x).cloned().unwrap();
+		assert_eq!(entry(&command)["outcome"], "denied");
+		assert_eq!(entry(&command)["executor"], "Alice");
+		assert_eq!(entry(&command)["permission"], "guest");
+		assert_eq!(entry(&format!("players {stamp}"))["outcome"], "allowed");
+
+		// Only with the token
+		assert_eq!(server.get("/audit").0, 401);
+	}
+
+	#[test]
+	fn lowerPlayerLimitMustFitThePlayers()
+	{
+		let _lock = configFile();
+		let (mut server, _c) = players(&["Alice", "Bob"]);
+		server.server.config.webToken = String::from("secret");
+		let limit = |x: u8| format!("{{ \"saveSettings\": {{ \"maxPlayersCount\": {x} }} }}");
+
+		let (status, body) = server.post(&limit(1), Some("secret"));
+		assert_eq!(status, 400);
+		assert!(body.contains("P2 is connected"));
+		assert_eq!(server.server.config.maxPlayersCount, 5);
+		assert_eq!(server.server.clients.len(), 5);
+
+		assert_eq!(server.post(&limit(2), Some("secret")).0, 200);
+		assert_eq!(server.server.clients.len(), 2);
+		assert_eq!(server.server.playersState.len(), 2);
+		assert_eq!(server.server.clients[1].name, "Bob");
+
+		// Growing adds empty slots
+		assert_eq!(server.post(&limit(4), Some("secret")).0, 200);
+		assert_eq!(server.server.clients.len(), 4);
+		assert_eq!(server.server.playersState.len(), 4);
+		let mut carol = server.connect();
+		carol.register("Carol", "");
+		carol.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		assert_eq!(server.server.clients[2].name, "Carol");
+
+		// Without players any limit fits
+		let mut server = TestServer::start();
+		server.server.config.webToken = String::from("secret");
+		assert_eq!(server.post(&limit(1), Some("secret")).0, 200);
+		assert_eq!(server.server.clients.len(), 1);
+	}
+
+	#[test]
+	fn fixedClockDrivesEveryPhase()
+	{
+		let mut config = TestServer::config();
+		config.reconnectGrace = Duration::from_secs(10);
+		let mut server = TestServer::with(config);
+		let mut alice = server.connect();
+		alice.register("Alice", "");
+		let token = alice.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		let ClientMessage::Token(token) = token else { unreachable!() };
+		let mut bob = server.connect();
+		bob.register("Bob", "");
+		bob.expect(&mut server, |x| matches!(x, ClientMessage::Token(_)));
+		alice.drain(&mut server);
+
+		// One cycle by hand, 20 seconds later
+		let now = server.now + Duration::from_secs(20);
+		alice.chat("привет");
+		std::thread::sleep(Duration::from_millis(20));
+		server.server.pollTcp(now);
+		assert_eq!(server.server.clients[0].lastSeen, now);
+		server.server.handleRequests(now);
+		server.server.broadcastTCP();
+		for c in &mut server.server.clients { c.flushTCP().unwrap(); }
+		std::thread::sleep(Duration::from_millis(20));
+		assert!(bob.receive().contains(&chat("Alice: привет")));
+		server.now = now;
+
+		// Bob was last heard 35 seconds ago, Alice 15
+		server.step(Duration::from_secs(15));
+		assert_eq!(server.server.clients[1].id, 0);
+		assert_eq!(server.server.clients[0].id, 1);
+
+		// The grace period is counted on the same clock
+		for wait in [9, 11]
+		{
+			drop(alice);
+			for _ in 0..5 { server.step(Duration::from_millis(10)); }
+			assert_eq!(server.server.recoverable.len(), 1);
+			alice = server.connect();
+			alice.send(7, &token.to_le_bytes());
+			std::thread::sleep(Duration::from_millis(20));
+			server.step(Duration::from_secs(wait));
+			let restored = alice.drain(&mut server).contains(&ClientMessage::Token(token));
+			assert_eq!(restored, wait < 10, "{wait}");
+		}
+		assert!(server.server.recoverable.is_empty());
+		assert_eq!(server.server.clients[0].id, 0);
+	}
+
+	#[test]
+	fn whoisAnswersOnlyTheAsker()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob", "Carol"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		server.server.config.permissions.insert(String::from("Carol"), Permission::Developer);
+		request(&mut server, 2, ServerMessage::SetPosition(70, 80));
+		for x in &mut c { x.drain(&mut server); }
+
+		c[0].chat("/whois bob");
+		let reply = c[0].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Bob]")));
+		let ClientMessage::Chat(text) = reply else { unreachable!() };
+		assert!(text.starts_with("[Bob] P2, класс: unknown, группа: player, IP: скрыт, позиция: (70;80), в сети: "));
+		assert!(!c[1].drain(&mut server).iter().chain(c[2].drain(&mut server).iter())
+			.any(|x| matches!(x, ClientMessage::Chat(t) if t.starts_with("[Bob]"))));
+		assert!(!server.server.state.chatHistory.iter().any(|x| x.msg.starts_with("[Bob]")));
+
+		// Developers see the address, players get nothing
+		c[2].chat("/whois bob");
+		c[2].expect(&mut server, |x| matches!(x, ClientMessage::Chat(t) if t.contains("IP: 127.0.0.1,")));
+		c[1].chat("/whois alice");
+		assert!(c[1].drain(&mut server).is_empty());
+
+		server.server.config.webToken = String::from("secret");
+		let (status, body) = server.post("{ \"chat\": { \"msg\": \"/whois bob\" } }", Some("secret"));
+		assert_eq!(status, 200);
+		let info = json::parse(&body).unwrap();
+		assert_eq!((info["id"].as_u8(), info["name"].as_str(), info["group"].as_str()), (Some(2), Some("Bob"), Some("player")));
+		assert_eq!((info["x"].as_u16(), info["y"].as_u16(), info["ip"].as_str()), (Some(70), Some(80), Some("127.0.0.1")));
+	}
+
+	#[test]
+	fn classesSetTheBaseStats()
+	{
+		let mut config = TestServer::config();
+		config.classes.insert(String::from("warrior"), ClassInfo { name: String::from("Воин"), hp: 150, mana: 20 });
+		config.classes.insert(String::from("mage"), ClassInfo { name: String::from("Маг"), hp: 80, mana: 200 });
+		config.defaultClass = String::from("mage");
+		let mut server = TestServer::with(config);
+		let mut c = (0..3).map(|_| server.connect()).collect::<Vec<TestClient>>();
+
+		c[0].register("Alice", "warrior");
+		c[0].expect(&mut server, |x| *x == ClientMessage::Stats(1, (150, 150), (20, 20)));
+		c[1].register("Bob", "paladin");
+		c[1].expect(&mut server, |x| *x == chat("Неизвестный класс: paladin"));
+		assert!(server.server.clients[1].hp == (0, 0) && server.server.clients[1].class != "paladin");
+
+		// No class picks the saved one, then the default one. Everybody here shares an IP
+		c[2].register("Carol", "");
+		c[2].expect(&mut server, |x| *x == ClientMessage::Stats(3, (150, 150), (20, 20)));
+		server.server.state.playersList.clear();
+		c[1].register("Bob", "");
+		c[1].expect(&mut server, |x| *x == ClientMessage::Stats(2, (80, 80), (200, 200)));
+		assert_eq!(server.server.clients[1].class, "mage");
+		assert_ne!(server.server.clients[0].hp, server.server.clients[1].hp);
+	}
+
+	#[test]
+	fn preflightReportsEveryPath()
+	{
+		let _lock = configFile();
+		let config = format!("{RES_DIR}/config.json");
+		let _ = std::fs::remove_file(&config);
+		let root = format!("{RES_DIR}/web573");
+		let mut server = TestServer::with(Config { webRoot: root.clone(), ..TestServer::config() });
+
+		// A missing save is fine, the config is written out
+		let report = server.server.preflight();
+		let status = |path: &str| report.iter().find(|(x, _)| x == path).map(|x| x.1);
+		assert_eq!(status(&config), Some("created"));
+		assert!(status(&State::path("default")).is_some());
+		assert_eq!(status(&root), Some("missing"));
+		assert!(std::path::Path::new(&config).exists());
+
+		std::fs::create_dir_all(&root).unwrap();
+		let report = server.server.preflight();
+		let _ = std::fs::remove_dir_all(&root);
+		assert!(report.contains(&(config, "present")));
+		assert!(report.contains(&(root, "present")));
+	}
+
+	#[test]
+	fn historyEntriesCarryTheirTime()
+	{
+		let seconds = || std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+		let before = seconds();
+		let (mut server, mut c) = players(&["Alice"]);
+		c[0].chat("привет");
+		c[0].chat("/roll d20");
+		c[0].drain(&mut server);
+		server.server.addChat(String::from("Центр мира"), String::from("объявление"), ChatKind::Broadcast);
+		let after = seconds();
+
+		let (status, body) = server.post("{ \"getChat\": { \"messagesLength\": 0 } }", None);
+		assert_eq!(status, 200);
+		let history = json::parse(&body).unwrap();
+		assert_eq!(history.len(), 3);
+		let valid = (before..=after).map(State::formatDateTime).collect::<Vec<String>>();
+		for entry in history.members()
+		{
+			let time = entry["time"].as_str().unwrap();
+			assert!(!time.is_empty() && valid.iter().any(|x| x == time), "{time}");
+		}
+	}
+
+	#[test]
+	fn clearchatEmptiesTheHistoryEverywhere()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		c[1].chat("раз");
+		c[1].chat("два");
+		for x in &mut c { x.drain(&mut server); }
+		assert_eq!(server.server.state.chatHistory.len(), 2);
+
+		// Players can't, and nothing is cleared
+		c[1].chat("/clearchat");
+		assert!(!c[1].drain(&mut server).contains(&ClientMessage::ClearChat));
+		assert_eq!(server.server.state.chatHistory.len(), 2);
+
+		c[0].chat("/clearchat");
+		let got = c[0].drain(&mut server);
+		assert!(got.contains(&ClientMessage::ClearChat) && got.contains(&chat("[Чат очищен, удалено сообщений: 2]")));
+		assert!(c[1].drain(&mut server).contains(&ClientMessage::ClearChat));
+		assert!(server.server.state.chatHistory.is_empty());
+		let (_, body) = server.post("{ \"getChat\": { \"messagesLength\": 0 } }", None);
+		assert_eq!(body, "[]");
+
+		server.server.addChat(String::from("Bob"), String::from("снова"), ChatKind::User);
+		server.server.config.webToken = String::from("secret");
+		let (status, body) = server.post("{ \"chat\": { \"msg\": \"/clearchat\" } }", Some("secret"));
+		assert_eq!(status, 200);
+		assert_eq!(json::parse(&body).unwrap()["removed"].as_usize(), Some(1));
+		assert!(c[1].drain(&mut server).contains(&ClientMessage::ClearChat));
+		assert!(server.server.state.chatHistory.is_empty());
+	}
+
+	#[test]
+	fn webNamesAreReserved()
+	{
+		let mut server = TestServer::start();
+		let mut c = server.connect();
+		c.drain(&mut server);
+		for name in ["WebClient", " webclient ", "ЦЕНТР МИРА"]
+		{
+			c.register(name, "");
+			let got = c.drain(&mut server);
+			assert!(got.contains(&chat("Это имя зарезервировано")), "{name}");
+			assert!(!got.iter().any(|x| matches!(x, ClientMessage::Token(_))), "{name}");
+		}
+
+		// Unregistered, the client still has a player's rights only
+		c.chat("/stop");
+		c.drain(&mut server);
+		assert!(server.server.isRunning());
+	}
+
+	#[test]
+	fn setpositionRefusesBadCoordinates()
+	{
+		let (mut server, mut c) = players(&["Alice", "Bob"]);
+		server.server.config.permissions.insert(String::from("Alice"), Permission::Admin);
+		for x in &mut c { x.drain(&mut server); }
+
+		for bad in ["setposition bob a b", "setposition bob -1 5", "setposition bob 5 70000"]
+		{
+			server.server.cmd(1, web(), String::from(bad));
+			c[0].expect(&mut server, |x| *x == chat("[Использование: /setposition <игрок> <x> <y>]"));
+		}
+		assert!(c[1].drain(&mut server).is_empty());
+
+		// Nothing panicked, the server still moves players
+		server.server.cmd(1, web(), String::from("setposition bob 12 34"));
+		c[1].expect(&mut server, |x| *x == ClientMessage::SetPosition(12, 34));
+	}
+
+	#[test]
+	fn whispersDontShiftHistoryPages()
+	{
+		let mut server = TestServer::start();
+		server.server.config.maxChatHistory = 4;
+		for i in 0..6
+		{
+			server.server.addChat(String::from("Alice"), i.to_string(), ChatKind::User);
+			server.server.addChat(String::from("Alice"), format!("w{i}"), ChatKind::Whisper);
+		}
+		let (_, length) = server.post("{ \"chatLength\": {} }", None);
+		assert_eq!(length, "6");
+
+		let mut page = |start: usize|
+		{
+			let (_, body) = server.post(&format!("{{ \"getChat\": {{ \"messagesLength\": {start} }} }}"), None);
+			json::parse(&body).unwrap().members().map(|x| x["msg"].to_string()).collect::<Vec<String>>()
+		};
+		// Only "4" and "5" are left besides the whispers
+		assert_eq!(page(0), vec!["5", "4"]);
+		assert_eq!(page(5), vec!["5"]);
+		assert!(page(6).is_empty());
+	}
+}
@@ -1,29 +1,80 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{collections::{HashMap, HashSet, VecDeque}, net::IpAddr};
+
+use super::Log;
+use super::RES_DIR;
+
+// Periodic saves go here and never overwrite a named slot
+pub const AUTOSAVE_SLOT: &str = "autosave";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatKind
+{
+	User,
+	System,
+	Broadcast,
+	Whisper
+}
+
+impl ChatKind
+{
+	pub fn toString(&self) -> String
+	{
+		match self
+		{
+			ChatKind::User => String::from("user"),
+			ChatKind::System => String::from("system"),
+			ChatKind::Broadcast => String::from("broadcast"),
+			ChatKind::Whisper => String::from("whisper")
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatEntry
+{
+	pub user: String,
+	pub msg: String,
+	pub kind: ChatKind,
+	// Server time the message was added at, see State::getDateTime
+	pub time: String
+}
+
+// Name, class and the last known position if the player ever moved
+pub type PlayerInfo = (String, String, Option<(u16, u16)>);
 
 pub struct State
 {
-	pub playersList: HashMap<IpAddr, (String, String)>,
+	pub playersList: HashMap<IpAddr, PlayerInfo>,
+	pub banned: HashSet<IpAddr>,
+	pub inventories: HashMap<IpAddr, Vec<(String, u16)>>,
 	pub checkpoint: String,
 	pub date: String,
-	pub chatHistory: Vec<(String, String)>
+	pub slot: String,
+	pub chatHistory: VecDeque<ChatEntry>,
+	// Messages the dashboard got since the server start, whispers aren't shown there
+	pub chatTotal: usize
 }
 
 impl State
 {
-	fn new() -> Self
+	pub fn new() -> Self
 	{
 		Self
 		{
 			playersList: HashMap::new(),
+			banned: HashSet::new(),
+			inventories: HashMap::new(),
 			checkpoint: String::new(),
 			date: String::new(),
-			chatHistory: vec![]
+			slot: String::from("default"),
+			chatHistory: VecDeque::new(),
+			chatTotal: 0
 		}
 	}
 	fn load(file: String) -> Self
 	{
 		let doc = json::parse(&file);
-		if doc.is_err() { println!("Failed to load save."); return Self::new(); }
+		if doc.is_err() { Log::warn("Failed to load save."); return Self::new(); }
 		let doc = doc.unwrap();
 		let mut state = Self::new();
 
@@ -35,8 +86,21 @@ impl State
 				{
 					let mut name = String::new();
 					let mut class = String::new();
+					let mut inventory = vec![];
+					let mut x = None;
+					let mut y = None;
 					for arg in player.entries()
 					{
+						if arg.0 == "x" { x = arg.1.as_u16(); }
+						if arg.0 == "y" { y = arg.1.as_u16(); }
+						if arg.0 == "inventory"
+						{
+							for (item, count) in arg.1.entries()
+							{
+								let count = count.as_u16().unwrap_or(0);
+								if count > 0 { inventory.push((item.to_string(), count)); }
+							}
+						}
 						if arg.0 == "name"
 						{
 							name = arg.1.as_str().unwrap_or("").to_string();
@@ -47,10 +111,19 @@ impl State
 						}
 					}
 
-					state.playersList.insert(
-						ip.parse().unwrap(),
-						(name, class)
-					);
+					let ip = ip.parse().unwrap();
+					if !inventory.is_empty() { state.inventories.insert(ip, inventory); }
+					state.playersList.insert(ip, (name, class, x.zip(y)));
+				}
+			}
+			if section.0 == "banned"
+			{
+				for ip in section.1.members()
+				{
+					if let Ok(ip) = ip.as_str().unwrap_or("").parse()
+					{
+						state.banned.insert(ip);
+					}
 				}
 			}
 			if section.0 == "checkpoint"
@@ -66,16 +139,122 @@ impl State
 		state
 	}
 
+	// Saves from before the slots were a single save.json,
+	// it becomes the default slot unless that one already exists
 	pub fn init() -> Self
 	{
-		match std::fs::read_to_string("res/system/save.json")
+		let legacy = format!("{RES_DIR}/save.json");
+		let path = State::path("default");
+		if !std::path::Path::new(&path).exists() && std::path::Path::new(&legacy).exists()
+		{
+			match std::fs::rename(&legacy, &path)
+			{
+				Ok(_) => Log::info(format!("Moved {legacy} to {path}")),
+				Err(x) =>
+				{
+					Log::error(format!("Failed to move {legacy} to {path}: {x}"));
+					// Still load it, the next save writes the default slot
+					if let Ok(file) = std::fs::read_to_string(&legacy) { return Self::load(file); }
+				}
+			}
+		}
+		Self::open("default").unwrap_or_else(|_| Self::new())
+	}
+
+	pub fn open(slot: &str) -> std::io::Result<Self>
+	{
+		let file = std::fs::read_to_string(State::path(slot))?;
+		let mut state = Self::load(file);
+		state.slot = slot.to_string();
+		Ok(state)
+	}
+
+	pub fn path(slot: &str) -> String
+	{
+		format!("{RES_DIR}/save_{slot}.json")
+	}
+
+	// Fresh checkouts have no res/system yet, so the missing directories are created first
+	pub fn write(path: &str, data: String) -> std::io::Result<()>
+	{
+		if let Some(dir) = std::path::Path::new(path).parent() { std::fs::create_dir_all(dir)?; }
+		std::fs::write(path, data)
+	}
+
+	// Slot names end up in file names, so only simple ones are allowed
+	pub fn isValidSlot(slot: &str) -> bool
+	{
+		!slot.is_empty() && slot.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+	}
+
+	pub fn listSlots() -> Vec<String>
+	{
+		let mut slots = vec![];
+		if let Ok(dir) = std::fs::read_dir(RES_DIR)
+		{
+			for entry in dir.flatten()
+			{
+				let name = entry.file_name().to_string_lossy().to_string();
+				if let Some(slot) = name.strip_prefix("save_").and_then(|x| x.strip_suffix(".json"))
+					&& State::isValidSlot(slot)
+				{
+					slots.push(slot.to_string());
+				}
+			}
+		}
+		slots.sort();
+		slots
+	}
+
+	// Backup file names of all slots, oldest first
+	pub fn listBackups() -> Vec<String>
+	{
+		let mut backups = vec![];
+		if let Ok(dir) = std::fs::read_dir(RES_DIR)
 		{
-			Ok(file) => Self::load(file),
-			Err(_) => Self::new()
+			for entry in dir.flatten()
+			{
+				let name = entry.file_name().to_string_lossy().to_string();
+				if name.starts_with("save_") && name.contains(".bak.") && name.ends_with(".json")
+				{
+					backups.push(name);
+				}
+			}
 		}
+		let stamp = |x: &String| x.rsplit('.').nth(1).and_then(|t| t.parse::<u128>().ok()).unwrap_or(0);
+		backups.sort_by_key(stamp);
+		backups
 	}
 
-	pub fn save(&mut self, checkpoint: String)
+	// Moves the current save of the slot aside
+	fn backup(&self) -> std::io::Result<Option<String>>
+	{
+		let path = State::path(&self.slot);
+		if !std::path::Path::new(&path).exists() { return Ok(None); }
+
+		let stamp = std::time::SystemTime::now()
+			.duration_since(std::time::UNIX_EPOCH)
+			.map(|t| t.as_millis())
+			.unwrap_or(0);
+		let backup = format!("{RES_DIR}/save_{}.bak.{stamp}.json", self.slot);
+		std::fs::rename(&path, &backup)?;
+		Ok(Some(backup))
+	}
+
+	// Keeps at most `keep` newest backups of the slot
+	fn prune(&self, keep: usize)
+	{
+		let prefix = format!("save_{}.bak.", self.slot);
+		let old = State::listBackups().into_iter()
+			.filter(|x| x.starts_with(&prefix))
+			.collect::<Vec<String>>();
+		for name in old.iter().take(old.len().saturating_sub(keep))
+		{
+			let _ = std::fs::remove_file(format!("{RES_DIR}/{name}"));
+		}
+	}
+
+	pub fn save(&mut self, checkpoint: String, backups: usize) -> std::io::Result<()>
 	{
 		self.date = State::getDateTime();
 
@@ -86,86 +265,374 @@ impl State
 			let name = data.0.clone();
 			let _ = info.insert("name", name.clone());
 			let _ = info.insert("class", data.1.clone());
+			if let Some((x, y)) = data.2
+			{
+				let _ = info.insert("x", x);
+				let _ = info.insert("y", y);
+			}
+			let mut inventory = json::JsonValue::new_object();
+			for (item, count) in self.inventories.get(ip).into_iter().flatten()
+			{
+				let _ = inventory.insert(item, *count);
+			}
+			let _ = info.insert("inventory", inventory);
 			let _ = players.insert(&ip.to_string(), info);
 		}
 
+		let mut banned = json::JsonValue::new_array();
+		for ip in &self.banned
+		{
+			let _ = banned.push(ip.to_string());
+		}
+
 		let mut state = json::JsonValue::new_object();
 		let _ = state.insert("players", players);
+		let _ = state.insert("banned", banned);
 		let _ = state.insert("checkpoint", checkpoint);
 		let _ = state.insert("date", self.date.clone());
 
-		let _ = std::fs::write(
-			"res/system/save.json",
-			json::stringify_pretty(state, 4)
-		);
+		self.replace(json::stringify_pretty(state, 4), backups, State::write)
+	}
+
+	// Backs the slot up and writes `data` in its place. The backup comes back if
+	// `write` fails, old backups are pruned only once the new save is there
+	fn replace(
+		&self,
+		data: String,
+		backups: usize,
+		write: impl FnOnce(&str, String) -> std::io::Result<()>
+	) -> std::io::Result<()>
+	{
+		let backup = self.backup()?;
+		let result = write(&State::path(&self.slot), data);
+		match (&result, backup)
+		{
+			(Ok(_), _) => self.prune(backups),
+			(Err(_), Some(backup)) => { let _ = std::fs::rename(backup, State::path(&self.slot)); },
+			(Err(_), None) => {}
+		}
+		result
+	}
+
+	// Writes the current state to the autosave slot, the active slot and
+	// its checkpoint stay as they are
+	pub fn autosave(&mut self, backups: usize) -> std::io::Result<()>
+	{
+		let slot = std::mem::replace(&mut self.slot, String::from(AUTOSAVE_SLOT));
+		let date = self.date.clone();
+		let result = self.save(self.checkpoint.clone(), backups);
+		self.slot = slot;
+		self.date = date;
+		result
+	}
+
+	// Keeps at most `limit` newest messages and at most `perPlayer`
+	// newest messages of each player
+	pub fn addChat(&mut self, user: String, msg: String, kind: ChatKind, limit: usize, perPlayer: usize)
+	{
+		if kind == ChatKind::User
+		{
+			let own = self.chatHistory.iter()
+				.filter(|x| x.kind == ChatKind::User && x.user == user)
+				.count();
+			if own >= perPlayer
+			{
+				let oldest = self.chatHistory.iter()
+					.position(|x| x.kind == ChatKind::User && x.user == user);
+				if let Some(i) = oldest { self.chatHistory.remove(i); }
+			}
+		}
+		self.chatHistory.push_back(ChatEntry { user, msg, kind, time: State::getDateTime() });
+		if kind != ChatKind::Whisper { self.chatTotal += 1; }
+		while self.chatHistory.len() > limit { self.chatHistory.pop_front(); }
 	}
 
 	pub fn getPlayerInfo(&mut self, ip: IpAddr) -> (String, String)
 	{
 		match self.playersList.get(&ip)
 		{
-			Some(data) => data.clone(),
+			Some((name, class, _)) => (name.clone(), class.clone()),
 			None => (String::from("noname"), String::from("unknown"))
 		}
 	}
 	
 	pub fn setPlayerInfo(&mut self, ip: IpAddr, name: String, class: String)
 	{
-		self.playersList.insert(ip, (name, class));
+		let position = self.getPosition(ip);
+		self.playersList.insert(ip, (name, class, position));
+	}
+
+	pub fn getPosition(&self, ip: IpAddr) -> Option<(u16, u16)>
+	{
+		self.playersList.get(&ip).and_then(|(_, _, position)| *position)
+	}
+
+	// Only known players get a position
+	pub fn setPosition(&mut self, ip: IpAddr, x: u16, y: u16)
+	{
+		if let Some(data) = self.playersList.get_mut(&ip) { data.2 = Some((x, y)); }
+	}
+
+	pub fn getInventory(&self, ip: IpAddr) -> Vec<(String, u16)>
+	{
+		self.inventories.get(&ip).cloned().unwrap_or_default()
+	}
+
+	pub fn setInventory(&mut self, ip: IpAddr, items: Vec<(String, u16)>)
+	{
+		if items.is_empty() { self.inventories.remove(&ip); }
+		else { self.inventories.insert(ip, items); }
+	}
+
+	pub fn getPlayerIP(&self, name: &str) -> Option<IpAddr>
+	{
+		self.playersList.iter()
+			.find(|(_, (n, _, _))| n.to_lowercase() == name.to_lowercase())
+			.map(|(ip, _)| *ip)
 	}
 
 	pub fn getDateTime() -> String
 	{
 		match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
 		{
-			Ok(t) =>
-			{
-				let seconds = t.as_secs();
-				let minutes = seconds / 60; let seconds = seconds % 60;
-				let hours = minutes / 60; let minutes = minutes % 60;
-				let mut days = hours / 24; let hours = hours % 24;
+			Ok(t) => State::formatDateTime(t.as_secs()),
+			Err(_) => String::new()
+		}
+	}
 
-				let mut years = 1970 + days / 1461 * 4; days = days % 1461;
-				while days > 365 { years = years + 1; days = days - 365; }
+	// Unix seconds to civil date, see "days_from_civil" by Howard Hinnant
+	pub fn formatDateTime(time: u64) -> String
+	{
+		let seconds = time % 60;
+		let minutes = time / 60 % 60;
+		let hours = time / 3600 % 24;
 
-				let mut month = 1;
-				'getMonth: loop
-				{
-					if (month == 0 || month == 2 || month == 4 ||
-						month == 6 || month == 7 || month == 9 ||
-						month == 11 || month == 12) && days > 31 { month += 1; days -= 31; }
-					else if month == 1
-					{
-						if years % 4 == 0 && days > 29 { month += 1; days -= 29; }
-						else if years % 4 != 0 && days > 28 { month += 1; days -= 28; }
-					}
-					else if (month == 3 || month == 5 || month == 8 || month == 10) && days > 30
-					{
-						month += 1; days -= 30;
-					}
-					else { break 'getMonth; }
-				}
+		let z = time / 86400 + 719468;
+		let era = z / 146097;
+		let doe = z - era * 146097;
+		let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+		let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+		let mp = (5 * doy + 2) / 153;
+		let day = doy - (153 * mp + 2) / 5 + 1;
+		let month = if mp < 10 { mp + 3 } else { mp - 9 };
+		let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
 
-				let m = String::from(match month
-				{
-					1 => "Января",
-					2 => "Февраля",
-					3 => "Марта",
-					4 => "Апреля",
-					5 => "Мая",
-					6 => "Июня",
-					7 => "Июля",
-					8 => "Августа",
-					9 => "Сентября",
-					10 => "Октября",
-					11 => "Ноября",
-					12 => "Декабря",
-					_ => "???"
-				});
-				
-				return format!("{days} {m} {years} - {hours}:{minutes}:{seconds}");
-			},
-			Err(_) => { return String::new(); }
-		}
-	}
-}
\ No newline at end of file
+		let m = match month
+		{
+			1 => "Января",
+			2 => "Февраля",
+			3 => "Марта",
+			4 => "Апреля",
+			5 => "Мая",
+			6 => "Июня",
+			7 => "Июля",
+			8 => "Августа",
+			9 => "Сентября",
+			10 => "Октября",
+			11 => "Ноября",
+			12 => "Декабря",
+			_ => "???"
+		};
+
+		format!("{day} {m} {year} - {hours}:{minutes}:{seconds}")
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn centuryBoundaries()
+	{
+		assert_eq!(State::formatDateTime(0), "1 Января 1970 - 0:0:0");
+		assert_eq!(State::formatDateTime(946684799), "31 Декабря 1999 - 23:59:59");
+		assert_eq!(State::formatDateTime(946684800), "1 Января 2000 - 0:0:0");
+		assert_eq!(State::formatDateTime(4102444800), "1 Января 2100 - 0:0:0");
+	}
+
+	#[test]
+	fn leapDays()
+	{
+		// 2000 is a leap year, 2100 is not
+		assert_eq!(State::formatDateTime(951782400), "29 Февраля 2000 - 0:0:0");
+		assert_eq!(State::formatDateTime(4107542400), "1 Марта 2100 - 0:0:0");
+		assert_eq!(State::formatDateTime(1709210096), "29 Февраля 2024 - 12:34:56");
+	}
+
+	#[test]
+	fn historyKeepsTheNewest()
+	{
+		let mut state = State::new();
+		for i in 0..1500
+		{
+			state.addChat(format!("P{}", i % 10), i.to_string(), ChatKind::User, 1000, usize::MAX);
+		}
+		assert_eq!(state.chatHistory.len(), 1000);
+		assert_eq!(state.chatTotal, 1500);
+		assert_eq!(state.chatHistory.front().unwrap().msg, "500");
+		assert_eq!(state.chatHistory.back().unwrap().msg, "1499");
+	}
+
+	#[test]
+	fn floodEvictsOnlyTheFlooder()
+	{
+		let mut state = State::new();
+		state.addChat(String::from("Bob"), String::from("привет"), ChatKind::User, 100, 3);
+		state.addChat(String::from("Server"), String::from("сбор"), ChatKind::System, 100, 3);
+		for i in 0..5
+		{
+			state.addChat(String::from("Alice"), i.to_string(), ChatKind::User, 100, 3);
+		}
+		let history = state.chatHistory.iter().map(|x| x.msg.as_str()).collect::<Vec<&str>>();
+		assert_eq!(history, vec!["привет", "сбор", "2", "3", "4"]);
+	}
+
+	#[test]
+	fn slotsAreSavedApart()
+	{
+		let mut state = State::new();
+		state.banned.insert("10.0.0.1".parse().unwrap());
+		state.slot = String::from("test512a");
+		state.save(String::from("лес"), 0).unwrap();
+		state.banned.clear();
+		state.slot = String::from("test512b");
+		state.save(String::from("мост"), 0).unwrap();
+
+		let slots = State::listSlots();
+		let (a, b) = (State::open("test512a").unwrap(), State::open("test512b").unwrap());
+		let _ = std::fs::remove_file(State::path("test512a"));
+		let _ = std::fs::remove_file(State::path("test512b"));
+		assert!(slots.contains(&String::from("test512a")) && slots.contains(&String::from("test512b")));
+		assert_eq!((a.checkpoint.as_str(), a.banned.len(), a.slot.as_str()), ("лес", 1, "test512a"));
+		assert_eq!((b.checkpoint.as_str(), b.banned.len()), ("мост", 0));
+		assert!(!State::isValidSlot("../config") && !State::isValidSlot(""));
+	}
+
+	#[test]
+	fn legacySaveBecomesTheDefaultSlot()
+	{
+		let legacy = format!("{RES_DIR}/save.json");
+		std::fs::create_dir_all(RES_DIR).unwrap();
+		let _ = std::fs::remove_file(State::path("default"));
+		std::fs::write(&legacy, "{ \"checkpoint\": \"старт\", \"banned\": [\"10.0.0.2\"] }").unwrap();
+
+		let state = State::init();
+		let migrated = std::path::Path::new(&State::path("default")).exists();
+		let _ = std::fs::remove_file(State::path("default"));
+		assert_eq!((state.slot.as_str(), state.checkpoint.as_str()), ("default", "старт"));
+		assert_eq!(state.banned.len(), 1);
+		assert!(migrated && !std::path::Path::new(&legacy).exists());
+	}
+
+	#[test]
+	fn saveKeepsOneBackupAndRestoresIt()
+	{
+		let mut state = State::new();
+		state.slot = String::from("test513");
+		state.save(String::from("первый"), 5).unwrap();
+		std::thread::sleep(std::time::Duration::from_millis(2));
+		state.save(String::from("второй"), 5).unwrap();
+		let backups = State::listBackups().into_iter()
+			.filter(|x| x.starts_with("save_test513.bak."))
+			.collect::<Vec<String>>();
+
+		let failed = state.replace(String::from("{}"), 5, |_, _| Err(std::io::Error::other("disk full")));
+		let kept = State::open("test513").ok().map(|x| x.checkpoint);
+		for name in State::listBackups().into_iter().filter(|x| x.starts_with("save_test513.bak."))
+		{
+			let _ = std::fs::remove_file(format!("{RES_DIR}/{name}"));
+		}
+		let _ = std::fs::remove_file(State::path("test513"));
+
+		assert_eq!(backups.len(), 1);
+		assert!(failed.is_err());
+		assert_eq!(kept.as_deref(), Some("второй"));
+	}
+
+	#[test]
+	fn noBackupsStillRestoreAFailedSave()
+	{
+		let mut state = State::new();
+		state.slot = String::from("test513b");
+		state.save(String::from("первый"), 0).unwrap();
+		std::thread::sleep(std::time::Duration::from_millis(2));
+		state.save(String::from("второй"), 0).unwrap();
+		let backups = || State::listBackups().into_iter()
+			.filter(|x| x.starts_with("save_test513b.bak."))
+			.collect::<Vec<String>>();
+		let pruned = backups();
+
+		let failed = state.replace(String::from("{}"), 0, |_, _| Err(std::io::Error::other("disk full")));
+		let kept = State::open("test513b").ok().map(|x| x.checkpoint);
+		let left = backups();
+		for name in &left { let _ = std::fs::remove_file(format!("{RES_DIR}/{name}")); }
+		let _ = std::fs::remove_file(State::path("test513b"));
+
+		assert!(pruned.is_empty());
+		assert!(failed.is_err());
+		assert_eq!(kept.as_deref(), Some("второй"));
+		assert!(left.is_empty());
+	}
+
+	#[test]
+	fn writeCreatesMissingDirectories()
+	{
+		let root = format!("{RES_DIR}/fresh519");
+		let _ = std::fs::remove_dir_all(&root);
+		let path = format!("{root}/res/system/save_default.json");
+		State::write(&path, String::from("{}")).unwrap();
+		let written = std::fs::read_to_string(&path);
+		let _ = std::fs::remove_dir_all(&root);
+		assert_eq!(written.unwrap(), "{}");
+	}
+
+	#[test]
+	fn autosaveLeavesTheCheckpointAlone()
+	{
+		let mut state = State::new();
+		state.slot = String::from("test523");
+		state.checkpoint = String::from("мост");
+		state.save(String::from("мост"), 0).unwrap();
+		state.banned.insert("10.0.0.3".parse().unwrap());
+		for _ in 0..3
+		{
+			state.autosave(1).unwrap();
+			std::thread::sleep(std::time::Duration::from_millis(2));
+		}
+
+		let (named, auto) = (State::open("test523"), State::open(AUTOSAVE_SLOT));
+		let backups = State::listBackups().into_iter()
+			.filter(|x| x.starts_with(&format!("save_{AUTOSAVE_SLOT}.bak.")))
+			.collect::<Vec<String>>();
+		for name in &backups { let _ = std::fs::remove_file(format!("{RES_DIR}/{name}")); }
+		let _ = std::fs::remove_file(State::path("test523"));
+		let _ = std::fs::remove_file(State::path(AUTOSAVE_SLOT));
+
+		assert_eq!(state.slot, "test523");
+		let named = named.unwrap();
+		assert_eq!((named.checkpoint.as_str(), named.banned.len()), ("мост", 0));
+		let auto = auto.unwrap();
+		assert_eq!((auto.checkpoint.as_str(), auto.banned.len()), ("мост", 1));
+		assert_eq!(backups.len(), 1);
+	}
+
+	#[test]
+	fn inventoriesSurviveASave()
+	{
+		let ip = "10.0.0.4".parse().unwrap();
+		let items = vec![(String::from("зелье"), 3), (String::from("меч"), 1)];
+		let mut state = State::new();
+		state.slot = String::from("test530");
+		state.setPlayerInfo(ip, String::from("Alice"), String::from("warrior"));
+		state.setInventory(ip, items.clone());
+		state.save(String::new(), 0).unwrap();
+		let loaded = State::open("test530");
+		let _ = std::fs::remove_file(State::path("test530"));
+
+		let mut loaded = loaded.unwrap().getInventory(ip);
+		loaded.sort();
+		assert_eq!(loaded, items);
+	}
+}
@@ -1,20 +1,46 @@
-use std::net::SocketAddr;
+use std::{io::Write, net::{IpAddr, SocketAddr}};
+
+use flate2::{write::GzEncoder, Compression};
+
+use super::Config::Config;
 
 // Incoming messages
 #[derive(Debug, Clone)]
 pub enum ServerMessage
 {
-	Invalid(SocketAddr),
-	Register(String),
+	Invalid,
+	// Name, capability flags and class, the class is empty if not chosen
+	Register(String, u8, String),
 	Chat(String, SocketAddr),
 	Disconnected,
 	PlayersList(SocketAddr),
-	SaveGame(String),
+	SaveGame(String, String, SocketAddr),
+	LoadGame(String, SocketAddr),
+	ListSaves(SocketAddr),
 	ChatHistory(usize, SocketAddr),
 	GameState(SocketAddr),
 	ChatLength(SocketAddr),
 	GetSettings(SocketAddr),
-	SaveSettings(SocketAddr)
+	// Validated settings, applied by the server as it knows the connected players
+	SaveSettings(Box<Config>, SocketAddr),
+	SettingsRejected(Vec<String>, SocketAddr),
+	Shutdown(SocketAddr),
+	ChangeStats(i16, i16),
+	ListBans(SocketAddr),
+	ListMutes(SocketAddr),
+	Unban(String, SocketAddr),
+	Unmute(String, SocketAddr),
+	ReloadConfig(SocketAddr),
+	Positions(SocketAddr),
+	SetPosition(u16, u16),
+	Reconnect(u64),
+	AddItem(String, u16),
+	RemoveItem(String, u16),
+	Inventory(String, SocketAddr),
+	Health(bool, SocketAddr),
+	Metrics(SocketAddr),
+	// Stamp of the Ping the client answers
+	Pong(u32)
 }
 
 impl ServerMessage
@@ -27,30 +53,105 @@ impl ServerMessage
 
 		match code
 		{
-			1 => Self::Register(String::from_utf8_lossy(&args).to_string()),
+			// Name, optionally followed by 0, a byte of capability flags and the class.
+			// Login separates name and class with 0, so no other 0 may follow
+			1 => match args.iter().position(|x| *x == 0)
+			{
+				None => Self::Register(String::from_utf8_lossy(&args).to_string(), 0, String::new()),
+				Some(i) if i + 2 <= args.len() && !args[i + 2..].contains(&0) => Self::Register(
+					String::from_utf8_lossy(&args[..i]).to_string(),
+					args[i + 1],
+					String::from_utf8_lossy(&args[i + 2..]).to_string()
+				),
+				Some(_) => Self::Invalid
+			},
 			2 => Self::Chat(String::from_utf8_lossy(&args).to_string(), "0.0.0.0:0".parse().unwrap()),
-			3 => Self::SaveGame(String::from_utf8_lossy(&args).to_string()),
-			_ => Self::Invalid("0.0.0.0:0".parse().unwrap())
+			3 =>
+			{
+				// Checkpoint, optionally followed by 0 and a save slot
+				let text = String::from_utf8_lossy(&args).to_string();
+				let (checkpoint, slot) = text.split_once('\0').unwrap_or((&text, ""));
+				Self::SaveGame(checkpoint.to_string(), slot.to_string(), "0.0.0.0:0".parse().unwrap())
+			},
+			5 => Self::PlayersList("0.0.0.0:0".parse().unwrap()),
+			4 if args.len() == 4 => Self::ChangeStats(
+				i16::from_le_bytes([args[0], args[1]]),
+				i16::from_le_bytes([args[2], args[3]])
+			),
+			// Position fallback for clients which can't use UDP
+			6 if args.len() == 4 => Self::SetPosition(
+				u16::from_le_bytes([args[0], args[1]]),
+				u16::from_le_bytes([args[2], args[3]])
+			),
+			// Token received in the Token message of the previous connection
+			7 if args.len() == 8 => Self::Reconnect(u64::from_le_bytes([
+				args[0], args[1], args[2], args[3],
+				args[4], args[5], args[6], args[7]
+			])),
+			// Item changes: count (u16 LE), then the item id
+			8 | 9 if args.len() > 2 =>
+			{
+				let count = u16::from_le_bytes([args[0], args[1]]);
+				let item = String::from_utf8_lossy(&args[2..]).to_string();
+				if code == 8 { Self::AddItem(item, count) } else { Self::RemoveItem(item, count) }
+			},
+			// Stamp (u32 LE) of a Ping, sent back right away
+			10 if args.len() == 4 => Self::Pong(u32::from_le_bytes([args[0], args[1], args[2], args[3]])),
+			_ => Self::Invalid
 		}
 	}
 }
 
+// Capability flags sent by the client in Register
+pub const CAP_ACK: u8 = 0b0000_0001;
+
+// Bumped on every incompatible change of the wire format below
+pub const PROTOCOL_VERSION: u8 = 3;
+
 // Outcoming messages
-#[derive(Debug, Clone)]
+// Every frame starts with its length (u16 LE, not counting the prefix itself),
+// incoming frames from clients use the same prefix.
+// Wire format after the prefix (first byte is the message code):
+// Login         [1, id, name.., 0, class..] (name and class never contain 0)
+// Disconnected  [2, id]
+// Chat          [3, text..]
+// SetPosition   [4, x (u16 LE), y (u16 LE)]
+// GetInfo       [5, udp (u16 LE), tickRate, playersCount, checkpoint..]
+// Stats         [6, id, hp (u16 LE), max hp (u16 LE), mana (u16 LE), max mana (u16 LE)]
+// PlayersList   [7, (id, name.., 0, class.., 0) for every player]
+// State         [8, (9 bytes of player state) for every other player], sent to TCP-only clients
+// Token         [9, reconnection token (u64 LE)]
+// Inventory     [10, (count (u16 LE), item.., 0) for every item]
+// Joined        [11, id, name.., 0, class..], sent to everyone but the new player
+// Ack           [12, request code, ok (0 or 1)], only if the client registered with CAP_ACK
+// ClearChat     [13], the chat was cleared by a moderator
+// Ping          [14, stamp (u32 LE)], the client answers with [10, stamp] to measure RTT
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientMessage
 {
 	Login(u8, String, String),
 	Disconnected(u8),
 	Chat(String),
 	SetPosition(u16, u16),
-	GetInfo(u16, u8, String, u8)
+	// Part of the wire format, the server doesn't send it yet
+	#[cfg_attr(not(test), allow(dead_code))]
+	GetInfo(u16, u8, String, u8),
+	Stats(u8, (u16, u16), (u16, u16)),
+	PlayersList(Vec<(u8, String, String)>),
+	State(Vec<u8>),
+	Token(u64),
+	Inventory(Vec<(String, u16)>),
+	Joined(u8, String, String),
+	Ack(u8, bool),
+	ClearChat,
+	Ping(u32)
 }
 
 impl ClientMessage
 {
 	pub fn toRaw(self) -> Vec<u8>
 	{
-		match self
+		let data = match self
 		{
 			Self::Login(
 				id, name, class) => [
@@ -66,61 +167,798 @@ impl ClientMessage
 			Self::GetInfo(udp, tickRate, checkpoint, playersCount) => [
 					&[5u8] as &[u8], &udp.to_le_bytes(), &[tickRate],
 					&[playersCount], checkpoint.as_bytes()
-				].concat().to_vec()
+				].concat().to_vec(),
+			Self::Stats(id, hp, mana) => [&[6u8, id] as &[u8],
+					&hp.0.to_le_bytes(), &hp.1.to_le_bytes(),
+					&mana.0.to_le_bytes(), &mana.1.to_le_bytes()
+				].concat().to_vec(),
+			Self::PlayersList(list) =>
+			{
+				let mut data = vec![7u8];
+				for (id, name, class) in list
+				{
+					data.push(id);
+					data.extend_from_slice(name.as_bytes());
+					data.push(0);
+					data.extend_from_slice(class.as_bytes());
+					data.push(0);
+				}
+				data
+			},
+			Self::State(states) => [&[8u8] as &[u8], &states].concat().to_vec(),
+			Self::Token(token) => [&[9u8] as &[u8], &token.to_le_bytes()].concat().to_vec(),
+			Self::Inventory(items) =>
+			{
+				let mut data = vec![10u8];
+				for (item, count) in items
+				{
+					data.extend_from_slice(&count.to_le_bytes());
+					data.extend_from_slice(item.as_bytes());
+					data.push(0);
+				}
+				data
+			},
+			Self::Joined(id, name, class) => [
+					&[11], &[id],
+					name.as_bytes(), &[0],
+					class.as_bytes()
+				].concat().to_vec(),
+			Self::Ack(code, ok) => vec![12, code, ok as u8],
+			Self::ClearChat => vec![13],
+			Self::Ping(stamp) => [&[14u8] as &[u8], &stamp.to_le_bytes()].concat().to_vec()
+		};
+		[&(data.len() as u16).to_le_bytes(), data.as_slice()].concat()
+	}
+
+	// Reverse of toRaw, tests read the server's frames with it
+	#[cfg(test)]
+	pub fn fromRaw(frame: &[u8]) -> Option<Self>
+	{
+		if frame.len() < 3 { return None; }
+		let size = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+		let data = &frame[2..];
+		if data.len() != size { return None; }
+		let args = &data[1..];
+
+		match data[0]
+		{
+			1 =>
+			{
+				if args.is_empty() { return None; }
+				let sep = args[1..].iter().position(|x| *x == 0)? + 1;
+				Some(Self::Login(
+					args[0],
+					String::from_utf8_lossy(&args[1..sep]).to_string(),
+					String::from_utf8_lossy(&args[sep + 1..]).to_string()
+				))
+			},
+			2 => Some(Self::Disconnected(*args.first()?)),
+			3 => Some(Self::Chat(String::from_utf8_lossy(args).to_string())),
+			4 =>
+			{
+				if args.len() != 4 { return None; }
+				Some(Self::SetPosition(
+					u16::from_le_bytes([args[0], args[1]]),
+					u16::from_le_bytes([args[2], args[3]])
+				))
+			},
+			5 =>
+			{
+				if args.len() < 4 { return None; }
+				Some(Self::GetInfo(
+					u16::from_le_bytes([args[0], args[1]]),
+					args[2],
+					String::from_utf8_lossy(&args[4..]).to_string(),
+					args[3]
+				))
+			},
+			6 =>
+			{
+				if args.len() != 9 { return None; }
+				let get = |i: usize| u16::from_le_bytes([args[i], args[i + 1]]);
+				Some(Self::Stats(args[0], (get(1), get(3)), (get(5), get(7))))
+			},
+			7 =>
+			{
+				let mut list = vec![];
+				let mut rest = args;
+				while !rest.is_empty()
+				{
+					let id = rest[0];
+					let mut parts = rest[1..].splitn(3, |x| *x == 0);
+					let name = String::from_utf8_lossy(parts.next()?).to_string();
+					let class = String::from_utf8_lossy(parts.next()?).to_string();
+					rest = parts.next()?;
+					list.push((id, name, class));
+				}
+				Some(Self::PlayersList(list))
+			},
+			8 =>
+			{
+				if !args.len().is_multiple_of(9) { return None; }
+				Some(Self::State(args.to_vec()))
+			},
+			9 => Some(Self::Token(u64::from_le_bytes(args.try_into().ok()?))),
+			10 =>
+			{
+				let mut items = vec![];
+				let mut rest = args;
+				while !rest.is_empty()
+				{
+					if rest.len() < 3 { return None; }
+					let count = u16::from_le_bytes([rest[0], rest[1]]);
+					let end = rest[2..].iter().position(|x| *x == 0)? + 2;
+					items.push((String::from_utf8_lossy(&rest[2..end]).to_string(), count));
+					rest = &rest[end + 1..];
+				}
+				Some(Self::Inventory(items))
+			},
+			11 =>
+			{
+				if args.is_empty() { return None; }
+				let sep = args[1..].iter().position(|x| *x == 0)? + 1;
+				Some(Self::Joined(
+					args[0],
+					String::from_utf8_lossy(&args[1..sep]).to_string(),
+					String::from_utf8_lossy(&args[sep + 1..]).to_string()
+				))
+			},
+			12 =>
+			{
+				if args.len() != 2 { return None; }
+				Some(Self::Ack(args[0], args[1] != 0))
+			},
+			13 if args.is_empty() => Some(Self::ClearChat),
+			14 if args.len() == 4 => Some(Self::Ping(u32::from_le_bytes([args[0], args[1], args[2], args[3]]))),
+			_ => None
+		}
+	}
+}
+
+// Bumped on every change of the player state layout below
+pub const STATE_VERSION: u8 = 1;
+
+// Player state packet sent by clients over UDP (12 bytes):
+// [0]      layout version, packets of other versions are dropped
+// [1]      flags (high 5 bits) and player ID (low 3 bits)
+// [2..4]   x (u16 LE)
+// [4..6]   y (u16 LE)
+// [6..10]  the rest of the player state
+// [10..12] sequence number (u16 LE), grows with every packet
+// Only bytes 1..10 are relayed to other players, see `relay`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerStatePacket
+{
+	pub flags: u8,
+	pub id: u8,
+	pub x: u16,
+	pub y: u16,
+	pub extra: [u8; 4],
+	pub sequence: u16
+}
+
+impl PlayerStatePacket
+{
+	pub const SIZE: usize = 12;
+
+	pub fn decode(data: &[u8]) -> Result<Self, String>
+	{
+		if data.len() != Self::SIZE
+		{
+			return Err(format!("{} bytes instead of {}", data.len(), Self::SIZE));
 		}
+		if data[0] != STATE_VERSION { return Err(format!("unknown version {}", data[0])); }
+		Ok(Self
+		{
+			flags: data[1] >> 3,
+			id: data[1] & 0b00_00_01_11,
+			x: u16::from_le_bytes([data[2], data[3]]),
+			y: u16::from_le_bytes([data[4], data[5]]),
+			extra: [data[6], data[7], data[8], data[9]],
+			sequence: u16::from_le_bytes([data[10], data[11]])
+		})
+	}
+
+	pub fn encode(&self) -> Vec<u8>
+	{
+		[&[STATE_VERSION, (self.flags << 3) | (self.id & 0b00_00_01_11)] as &[u8],
+			&self.x.to_le_bytes(), &self.y.to_le_bytes(),
+			&self.extra, &self.sequence.to_le_bytes()
+		].concat()
+	}
+
+	// The part of the packet other players get
+	pub fn state(&self) -> [u8; 9]
+	{
+		let mut state = [0u8; 9];
+		state.copy_from_slice(&self.encode()[1..10]);
+		state
+	}
+
+	// Datagram with the states of several players: [version, (9 bytes of state)*],
+	// one without states is a keepalive
+	pub fn relay(states: &[u8]) -> Vec<u8>
+	{
+		[&[STATE_VERSION], states].concat()
 	}
 }
 
+// Limits of a web request, bigger ones are refused with 413
+pub const MAX_HEAD: usize = 16 * 1024;
+pub const MAX_BODY: usize = 64 * 1024;
+
 #[derive(Debug, Clone)]
-pub enum WebRequest { Invalid, Get(String), Post(String) }
+pub enum WebRequest { Invalid, Incomplete, TooLarge, Get(String), Post(String), Upgrade(String), Options, Unsupported(String) }
 
 impl WebRequest
 {
 	pub fn build(raw: String) -> Self
 	{
-		let mut data = raw.split("\n").collect::<Vec<&str>>();
-		let cmd = data[0].split(" ").collect::<Vec<&str>>();
-		if data.len() == 0 { return Self::Invalid; }
-		while data[0] != "\r" { data.remove(0); }
-		data.remove(0);
+		let end = match raw.find("\r\n\r\n")
+		{
+			Some(x) if x > MAX_HEAD => return Self::TooLarge,
+			Some(x) => x,
+			None if raw.len() > MAX_HEAD => return Self::TooLarge,
+			None => return Self::Incomplete
+		};
+		let body = &raw[end + 4..];
+		let mut head = raw[0..end].split("\r\n");
+		let cmd = head.next().unwrap_or("").split(" ").collect::<Vec<&str>>();
+		if cmd.len() < 2 { return Self::Invalid; }
+
+		let mut length = 0;
+		let mut upgrade = false;
+		let mut key = None;
+		for line in head
+		{
+			if let Some((name, value)) = line.split_once(":")
+			{
+				let name = name.trim();
+				if name.eq_ignore_ascii_case("content-length")
+				{
+					length = value.trim().parse::<usize>().unwrap_or(0);
+				}
+				if name.eq_ignore_ascii_case("upgrade")
+				{
+					upgrade = value.trim().eq_ignore_ascii_case("websocket");
+				}
+				if name.eq_ignore_ascii_case("sec-websocket-key")
+				{
+					key = Some(value.trim().to_string());
+				}
+			}
+		}
 		
-		if cmd[0] == "GET" { return Self::Get(cmd[1].to_string()); }
-		if cmd[0] == "POST" { return Self::Post(data[0..data.len()].concat().to_string()); }
-		println!("Unparsed request: {cmd:#?}");
-		Self::Invalid
+		if cmd[0] == "GET"
+		{
+			if let (true, Some(key)) = (upgrade, key) { return Self::Upgrade(key); }
+			return Self::Get(cmd[1].to_string());
+		}
+		if cmd[0] == "OPTIONS" { return Self::Options; }
+		if cmd[0] == "POST"
+		{
+			if length > MAX_BODY { return Self::TooLarge; }
+			if body.len() < length { return Self::Incomplete; }
+			return Self::Post(body.get(0..length).unwrap_or(body).to_string());
+		}
+		Self::Unsupported(cmd[0].to_string())
+	}
+
+	// Value of the first header with the given name
+	pub fn header(raw: &str, name: &str) -> Option<String>
+	{
+		let head = raw.split("\r\n\r\n").next().unwrap_or("");
+		head.split("\r\n").skip(1).find_map(|line| match line.split_once(":")
+		{
+			Some((key, value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim().to_string()),
+			_ => None
+		})
+	}
+
+	// Token of the `Authorization: Bearer <token>` header
+	pub fn bearer(raw: &str) -> Option<String>
+	{
+		WebRequest::header(raw, "authorization")
+			.and_then(|x| x.strip_prefix("Bearer ").map(|x| x.trim().to_string()))
+	}
+
+	// Whether the request asks to keep the connection open
+	pub fn keepAlive(raw: &str) -> bool
+	{
+		WebRequest::header(raw, "connection").is_some_and(|x| x.eq_ignore_ascii_case("keep-alive"))
+	}
+
+	// Original client of a proxied request, the first address in the list
+	pub fn forwardedFor(raw: &str) -> Option<IpAddr>
+	{
+		WebRequest::header(raw, "x-forwarded-for")
+			.and_then(|x| x.split(',').next().and_then(|ip| ip.trim().parse().ok()))
+	}
+
+	pub fn acceptsGzip(raw: &str) -> bool
+	{
+		WebRequest::header(raw, "accept-encoding")
+			.is_some_and(|x| x.split(',').any(|e| e.trim().split(';').next() == Some("gzip")))
 	}
 }
 
+// Why a web request was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum WebError
+{
+	// Body is not JSON or has no command
+	Malformed(String),
+	// Arguments of the command are not an object
+	NotObject(String),
+	MissingField(String),
+	UnknownCommand(String),
+	// The command needs Config.webToken
+	Unauthorized(String)
+}
+
+impl WebError
+{
+	pub fn status(&self) -> u16
+	{
+		match self
+		{
+			Self::UnknownCommand(_) => 404,
+			Self::Unauthorized(_) => 401,
+			_ => 400
+		}
+	}
+
+	pub fn toString(&self) -> String
+	{
+		match self
+		{
+			Self::Malformed(x) => format!("malformed request: {x}"),
+			Self::NotObject(x) => format!("arguments of {x} must be an object"),
+			Self::MissingField(x) => format!("missing field: {x}"),
+			Self::UnknownCommand(x) => format!("unknown command: {x}"),
+			Self::Unauthorized(x) => format!("{x} needs a valid token")
+		}
+	}
+
+	pub fn toJson(&self) -> json::JsonValue
+	{
+		let kind = match self
+		{
+			Self::Malformed(_) => "malformed",
+			Self::NotObject(_) => "notObject",
+			Self::MissingField(_) => "missingField",
+			Self::UnknownCommand(_) => "unknownCommand",
+			Self::Unauthorized(_) => "unauthorized"
+		};
+		json::object! { error: kind, message: self.toString() }
+	}
+}
+
+// Smaller bodies are not worth compressing
+const GZIP_THRESHOLD: usize = 1024;
+
 // https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Status
 #[derive(Debug, Clone)]
 pub enum WebResponse
 {
 	Ok(String, String),
 	OkRaw(Vec<u8>, String),
+	Download(String, String),
+	SwitchingProtocols(String),
+	NoContent,
 	MovedPermanently(String),
-	NotFound
+	NotFound,
+	Error(WebError),
+	BadRequest(String),
+	InternalServerError(String),
+	Unauthorized,
+	MethodNotAllowed,
+	PayloadTooLarge,
+	ServiceUnavailable
 }
 
 impl WebResponse
 {
-	pub fn build(self) -> Vec<u8>
+	// `origin` is the value of Access-Control-Allow-Origin,
+	// `gzip` allows compressing 200 responses
+	pub fn build(self, origin: &str, gzip: bool) -> Vec<u8>
 	{
+		let cors = String::from("\r\nAccess-Control-Allow-Origin: ") + origin +
+			"\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS" +
+			"\r\nAccess-Control-Allow-Headers: Content-Type, Authorization";
 		match self
 		{
 			Self::Ok(data, filetype) =>
+				WebResponse::ok(data.into_bytes(), filetype + "; charset=UTF-8", &cors, gzip),
+			Self::OkRaw(data, filetype) => WebResponse::ok(data, filetype, &cors, gzip),
+			Self::Download(data, filename) =>
 				(String::from("HTTP/1.1 200 OK") +
-				"\r\nContent-Type: " + &filetype + "; charset=UTF-8" +
+				"\r\nContent-Type: application/json; charset=UTF-8" +
+				"\r\nContent-Disposition: attachment; filename=\"" + &filename + "\"" +
 				"\r\nContent-Length: " + &data.len().to_string() +
 				"\r\n\r\n" + &data).as_bytes().to_vec(),
-			Self::OkRaw(data, filetype) =>
-				[(String::from("HTTP/1.1 200 OK") +
-				"\r\nContent-Type: " + &filetype +
-				"\r\nContent-Length: " + &data.len().to_string() +
-				"\r\n\r\n").as_bytes(), &data].concat().to_vec(),
+			Self::SwitchingProtocols(accept) =>
+				(String::from("HTTP/1.1 101 Switching Protocols") +
+				"\r\nUpgrade: websocket" +
+				"\r\nConnection: Upgrade" +
+				"\r\nSec-WebSocket-Accept: " + &accept +
+				"\r\n\r\n").as_bytes().to_vec(),
+			Self::NoContent =>
+				(String::from("HTTP/1.1 204 No Content") + &cors +
+				"\r\n\r\n").as_bytes().to_vec(),
 			Self::MovedPermanently(path) =>
 				(String::from("HTTP/1.1 301 Moved Permanently") +
-				"\r\nLocation: " + &path).as_bytes().to_vec(),
-			Self::NotFound => String::from("HTTP/1.1 404 Not Found").as_bytes().to_vec(),
+				"\r\nLocation: " + &path +
+				"\r\nContent-Length: 0" +
+				"\r\n\r\n").as_bytes().to_vec(),
+			Self::NotFound =>
+			{
+				let data = "<html><body><h1>404 Not Found</h1></body></html>";
+				(String::from("HTTP/1.1 404 Not Found") +
+				"\r\nContent-Type: text/html; charset=UTF-8" +
+				"\r\nContent-Length: " + &data.len().to_string() +
+				"\r\n\r\n" + data).as_bytes().to_vec()
+			},
+			Self::Error(x) => WebResponse::error(
+				match x.status()
+				{
+					401 => "401 Unauthorized",
+					404 => "404 Not Found",
+					_ => "400 Bad Request"
+				},
+				&cors, x.toJson()
+			),
+			Self::BadRequest(x) =>
+				WebResponse::error("400 Bad Request", &cors, json::object! { error: x }),
+			Self::InternalServerError(x) =>
+				WebResponse::error("500 Internal Server Error", &cors, json::object! { error: x }),
+			Self::Unauthorized =>
+				WebResponse::error("401 Unauthorized", &cors, json::object! { error: "missing or wrong token" }),
+			Self::MethodNotAllowed =>
+				(String::from("HTTP/1.1 405 Method Not Allowed") + &cors +
+				"\r\nAllow: GET, POST, OPTIONS" +
+				"\r\nContent-Length: 0" +
+				"\r\n\r\n").as_bytes().to_vec(),
+			Self::PayloadTooLarge =>
+				(String::from("HTTP/1.1 413 Payload Too Large") + &cors +
+				"\r\nConnection: close" +
+				"\r\nContent-Length: 0" +
+				"\r\n\r\n").as_bytes().to_vec(),
+			Self::ServiceUnavailable =>
+				(String::from("HTTP/1.1 503 Service Unavailable") +
+				"\r\nRetry-After: 1" +
+				"\r\nConnection: close" +
+				"\r\nContent-Length: 0" +
+				"\r\n\r\n").as_bytes().to_vec()
+		}
+	}
+
+	// Bad input of the client is 400, anything else is the server's fault
+	pub fn ioError(x: &std::io::Error) -> Self
+	{
+		match x.kind()
+		{
+			std::io::ErrorKind::InvalidInput | std::io::ErrorKind::NotFound => Self::BadRequest(x.to_string()),
+			_ => Self::InternalServerError(x.to_string())
+		}
+	}
+
+	fn error(status: &str, cors: &str, data: json::JsonValue) -> Vec<u8>
+	{
+		let data = json::stringify(data);
+		(String::from("HTTP/1.1 ") + status + cors +
+		"\r\nContent-Type: text/json; charset=UTF-8" +
+		"\r\nContent-Length: " + &data.len().to_string() +
+		"\r\n\r\n" + &data).as_bytes().to_vec()
+	}
+
+	fn ok(data: Vec<u8>, filetype: String, cors: &str, gzip: bool) -> Vec<u8>
+	{
+		// Images and fonts other than SVG are compressed already
+		let packed = (filetype.starts_with("image/") && !filetype.starts_with("image/svg")) ||
+			filetype.starts_with("font/woff");
+		let compressed =
+			if gzip && !packed && data.len() >= GZIP_THRESHOLD
+			{
+				let mut encoder = GzEncoder::new(vec![], Compression::default());
+				encoder.write_all(&data).and_then(|_| encoder.finish()).ok()
+			}
+			else { None };
+		let (data, encoding) = match compressed
+		{
+			Some(x) => (x, "\r\nContent-Encoding: gzip\r\nVary: Accept-Encoding"),
+			None => (data, "")
+		};
+		[(String::from("HTTP/1.1 200 OK") + cors +
+			"\r\nContent-Type: " + &filetype + encoding +
+			"\r\nContent-Length: " + &data.len().to_string() +
+			"\r\n\r\n").as_bytes(), &data].concat()
+	}
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+	use super::super::Server::Rng;
+
+	fn roundTrip(msg: ClientMessage)
+	{
+		assert_eq!(ClientMessage::fromRaw(&msg.clone().toRaw()), Some(msg));
+	}
+
+	#[test]
+	fn login()
+	{
+		roundTrip(ClientMessage::Login(3, String::from("Алиса"), String::from("warrior")));
+		roundTrip(ClientMessage::Login(1, String::from("noname"), String::new()));
+	}
+
+	#[test]
+	fn setPosition()
+	{
+		roundTrip(ClientMessage::SetPosition(0, u16::MAX));
+		let raw = ClientMessage::SetPosition(0x0102, 0x0304).toRaw();
+		assert_eq!(raw, vec![5, 0, 4, 0x02, 0x01, 0x04, 0x03]);
+	}
+
+	#[test]
+	fn getInfo()
+	{
+		roundTrip(ClientMessage::GetInfo(40000, 20, String::from("forest"), 4));
+		roundTrip(ClientMessage::GetInfo(0, 1, String::new(), 0));
+		// The player count comes before the checkpoint on the wire
+		let raw = ClientMessage::GetInfo(0x1234, 20, String::from("a"), 3).toRaw();
+		assert_eq!(raw, vec![6, 0, 5, 0x34, 0x12, 20, 3, b'a']);
+	}
+
+	#[test]
+	fn everyOtherVariant()
+	{
+		roundTrip(ClientMessage::Disconnected(7));
+		roundTrip(ClientMessage::Chat(String::from("Bob: привет 👋")));
+		roundTrip(ClientMessage::Stats(2, (150, 200), (0, 20)));
+		roundTrip(ClientMessage::PlayersList(vec![]));
+		roundTrip(ClientMessage::PlayersList(vec![
+			(1, String::from("Alice"), String::from("mage")),
+			(4, String::from("Bob"), String::new())
+		]));
+		roundTrip(ClientMessage::State(vec![]));
+		roundTrip(ClientMessage::State((0..18).collect()));
+		roundTrip(ClientMessage::Token(u64::MAX - 1));
+		roundTrip(ClientMessage::Inventory(vec![]));
+		roundTrip(ClientMessage::Inventory(vec![(String::from("sword"), 1), (String::from("зелье"), 300)]));
+		roundTrip(ClientMessage::Joined(5, String::from("Carol"), String::from("rogue")));
+		roundTrip(ClientMessage::Ack(2, true));
+		roundTrip(ClientMessage::Ack(1, false));
+		roundTrip(ClientMessage::ClearChat);
+	}
+
+	// Random text without 0, which separates strings on the wire
+	fn text(rng: &mut Rng) -> String
+	{
+		let chars = ['a', 'Z', '7', ' ', 'ж', 'Ё', '👋', '\n', '\u{7f}'];
+		(0..rng.next() % 12).map(|_| chars[(rng.next() % chars.len() as u64) as usize]).collect()
+	}
+
+	// Numbers which read differently in the other byte order
+	fn u16Asymmetric(rng: &mut Rng) -> u16
+	{
+		loop { let x = rng.next() as u16; if x.swap_bytes() != x { return x; } }
+	}
+
+	fn random(rng: &mut Rng, variant: u64) -> ClientMessage
+	{
+		let byte = |rng: &mut Rng| rng.next() as u8;
+		match variant % 14
+		{
+			0 => ClientMessage::Login(byte(rng), text(rng), text(rng)),
+			1 => ClientMessage::Disconnected(byte(rng)),
+			2 => ClientMessage::Chat(text(rng)),
+			3 => ClientMessage::SetPosition(u16Asymmetric(rng), u16Asymmetric(rng)),
+			4 => ClientMessage::GetInfo(u16Asymmetric(rng), byte(rng), text(rng), byte(rng)),
+			5 => ClientMessage::Stats(byte(rng),
+				(u16Asymmetric(rng), u16Asymmetric(rng)), (u16Asymmetric(rng), u16Asymmetric(rng))),
+			6 => ClientMessage::PlayersList((0..rng.next() % 4).map(|_| (byte(rng), text(rng), text(rng))).collect()),
+			7 => ClientMessage::State((0..rng.next() % 5 * 9).map(|_| byte(rng)).collect()),
+			8 => ClientMessage::Token(rng.next()),
+			9 => ClientMessage::Inventory((0..rng.next() % 4).map(|_| (text(rng), u16Asymmetric(rng))).collect()),
+			10 => ClientMessage::Joined(byte(rng), text(rng), text(rng)),
+			11 => ClientMessage::Ack(byte(rng), rng.next().is_multiple_of(2)),
+			12 => ClientMessage::ClearChat,
+			_ => ClientMessage::Ping(rng.next() as u32)
 		}
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn randomMessagesRoundTrip()
+	{
+		let mut rng = Rng::new(499);
+		let mut seen = [false; 14];
+		for i in 0..3000
+		{
+			let msg = random(&mut rng, i);
+			let raw = msg.clone().toRaw();
+			// Numbers at a fixed place are little endian, a swap on both sides still fails here
+			let le = |at: usize, x: &[u8]| assert_eq!(&raw[at..at + x.len()], x, "{msg:?}");
+			// No wildcard: a new variant has to be generated above
+			let index = match &msg
+			{
+				ClientMessage::Login(..) => 0,
+				ClientMessage::Disconnected(_) => 1,
+				ClientMessage::Chat(_) => 2,
+				ClientMessage::SetPosition(x, y) => { le(3, &x.to_le_bytes()); le(5, &y.to_le_bytes()); 3 },
+				ClientMessage::GetInfo(udp, ..) => { le(3, &udp.to_le_bytes()); 4 },
+				ClientMessage::Stats(_, hp, mana) => { le(4, &hp.0.to_le_bytes()); le(10, &mana.1.to_le_bytes()); 5 },
+				ClientMessage::PlayersList(_) => 6,
+				ClientMessage::State(_) => 7,
+				ClientMessage::Token(x) => { le(3, &x.to_le_bytes()); 8 },
+				ClientMessage::Inventory(_) => 9,
+				ClientMessage::Joined(..) => 10,
+				ClientMessage::Ack(..) => 11,
+				ClientMessage::ClearChat => 12,
+				ClientMessage::Ping(x) => { le(3, &x.to_le_bytes()); 13 }
+			};
+			seen[index] = true;
+			assert_eq!(ClientMessage::fromRaw(&raw), Some(msg));
+		}
+		assert!(seen.iter().all(|x| *x));
+	}
+
+	#[test]
+	fn lengthPrefix()
+	{
+		let raw = ClientMessage::Chat(String::from("hi")).toRaw();
+		assert_eq!(&raw[..2], &3u16.to_le_bytes());
+		assert_eq!(ClientMessage::fromRaw(&raw[..raw.len() - 1]), None);
+	}
+
+	#[test]
+	fn postWaitsForTheWholeBody()
+	{
+		let head = "POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\n";
+		assert!(matches!(WebRequest::build(String::from("POST / HTTP/1.1\r\nContent-Le")), WebRequest::Incomplete));
+		assert!(matches!(WebRequest::build(format!("{head}{{\"a\":")), WebRequest::Incomplete));
+		assert!(matches!(WebRequest::build(format!("{head}{{\"a\": 1}}")), WebRequest::Post(x) if x == "{\"a\": 1}"));
+	}
+
+	#[test]
+	fn oversizedRequestsAreRefused()
+	{
+		let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1);
+		assert!(matches!(WebRequest::build(head), WebRequest::TooLarge));
+		let endless = format!("GET / HTTP/1.1\r\nX: {}", "a".repeat(MAX_HEAD));
+		assert!(matches!(WebRequest::build(endless), WebRequest::TooLarge));
+		let response = String::from_utf8(WebResponse::PayloadTooLarge.build("*", false)).unwrap();
+		assert!(response.starts_with("HTTP/1.1 413 "));
+		assert!(response.contains("Connection: close"));
+	}
+
+	#[test]
+	fn apiResponsesCarryCors()
+	{
+		let origin = "https://map.example";
+		let ok = String::from_utf8(WebResponse::Ok(String::from("{}"), String::from("text/json")).build(origin, false)).unwrap();
+		assert!(ok.starts_with("HTTP/1.1 200 "));
+		assert!(ok.contains("\r\nAccess-Control-Allow-Origin: https://map.example\r\n"));
+		assert!(ok.contains("\r\nAccess-Control-Allow-Methods: GET, POST, OPTIONS\r\n"));
+		assert!(ok.contains("\r\nAccess-Control-Allow-Headers: Content-Type, Authorization\r\n"));
+
+		let preflight = String::from("OPTIONS / HTTP/1.1\r\nOrigin: https://map.example\r\n\r\n");
+		assert!(matches!(WebRequest::build(preflight), WebRequest::Options));
+		let none = String::from_utf8(WebResponse::NoContent.build(origin, false)).unwrap();
+		assert!(none.starts_with("HTTP/1.1 204 ") && none.contains("Access-Control-Allow-Origin: https://map.example"));
+	}
+
+	// Head and body of a built response, the head must end with an empty line
+	fn split(response: WebResponse) -> (String, String)
+	{
+		let raw = String::from_utf8(response.build("*", false)).unwrap();
+		let (head, body) = raw.split_once("\r\n\r\n").expect("Unterminated head");
+		let length = head.lines().find_map(|x| x.strip_prefix("Content-Length: ")).and_then(|x| x.parse().ok());
+		assert_eq!(length, Some(body.len()));
+		(head.to_string(), body.to_string())
+	}
+
+	#[test]
+	fn responsesEndTheirHeads()
+	{
+		let (head, body) = split(WebResponse::NotFound);
+		assert!(head.starts_with("HTTP/1.1 404 Not Found\r\n"));
+		assert!(head.contains("Content-Type: text/html"));
+		assert!(body.contains("404"));
+
+		let (head, body) = split(WebResponse::MovedPermanently(String::from("/index.html")));
+		assert!(head.starts_with("HTTP/1.1 301 ") && head.contains("\r\nLocation: /index.html"));
+		assert!(body.is_empty());
+	}
+
+	// Body bytes of a built response
+	fn body(raw: &[u8]) -> &[u8]
+	{
+		let end = raw.windows(4).position(|x| x == b"\r\n\r\n").unwrap();
+		&raw[end + 4..]
+	}
+
+	#[test]
+	fn largeBodiesAreGzipped()
+	{
+		use std::io::Read;
+
+		let text = "{ \"msg\": \"привет\" }, ".repeat(200);
+		let raw = WebResponse::Ok(text.clone(), String::from("text/json")).build("*", true);
+		let head = String::from_utf8_lossy(&raw[..raw.len() - body(&raw).len()]).to_string();
+		assert!(head.contains("Content-Encoding: gzip"));
+		assert!(head.contains(&format!("Content-Length: {}", body(&raw).len())));
+		let mut unpacked = String::new();
+		flate2::read::GzDecoder::new(body(&raw)).read_to_string(&mut unpacked).unwrap();
+		assert_eq!(unpacked, text);
+
+		// Small, already packed or not accepted
+		let small = WebResponse::Ok(String::from("{}"), String::from("text/json")).build("*", true);
+		let png = WebResponse::OkRaw(vec![0; 4096], String::from("image/png")).build("*", true);
+		let plain = WebResponse::Ok(text.clone(), String::from("text/json")).build("*", false);
+		for raw in [small, png, plain]
+		{
+			assert!(!String::from_utf8_lossy(&raw).contains("Content-Encoding"));
+		}
+	}
+
+	#[test]
+	fn statePacketRoundTrip()
+	{
+		let packet = PlayerStatePacket { flags: 0b10101, id: 3, x: 0x0102, y: u16::MAX, extra: [9, 8, 7, 6], sequence: 513 };
+		let raw = packet.encode();
+		assert_eq!(raw.len(), PlayerStatePacket::SIZE);
+		assert_eq!(raw[..6], [STATE_VERSION, (0b10101 << 3) | 3, 0x02, 0x01, 0xFF, 0xFF]);
+		assert_eq!(PlayerStatePacket::decode(&raw), Ok(packet));
+		assert_eq!(packet.state()[..], raw[1..10]);
+	}
+
+	#[test]
+	fn unknownStateVersionIsRejected()
+	{
+		let mut raw = PlayerStatePacket { flags: 0, id: 1, x: 1, y: 2, extra: [0; 4], sequence: 0 }.encode();
+		raw[0] = STATE_VERSION.wrapping_add(1);
+		assert!(PlayerStatePacket::decode(&raw).is_err());
+		assert!(PlayerStatePacket::decode(&raw[..9]).is_err());
+	}
+
+	#[test]
+	fn errorResponsesHaveTheirStatus()
+	{
+		let (head, body) = split(WebResponse::BadRequest(String::from("port: 80")));
+		assert!(head.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+		assert_eq!(json::parse(&body).unwrap()["error"], "port: 80");
+
+		let (head, body) = split(WebResponse::InternalServerError(String::from("disk full")));
+		assert!(head.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+		assert_eq!(json::parse(&body).unwrap()["error"], "disk full");
+
+		let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "no slot");
+		assert!(matches!(WebResponse::ioError(&missing), WebResponse::BadRequest(_)));
+		assert!(matches!(WebResponse::ioError(&std::io::Error::other("x")), WebResponse::InternalServerError(_)));
+	}
+
+	#[test]
+	fn forwardedForTakesTheClient()
+	{
+		let head = |x: &str| format!("GET / HTTP/1.1\r\nHost: envell\r\n{x}\r\n\r\n");
+		let client = Some("203.0.113.7".parse().unwrap());
+		assert_eq!(WebRequest::forwardedFor(&head("X-Forwarded-For: 203.0.113.7")), client);
+		assert_eq!(WebRequest::forwardedFor(&head("x-forwarded-for:  203.0.113.7 , 10.0.0.1, 10.0.0.2")), client);
+		assert_eq!(WebRequest::forwardedFor(&head("X-Forwarded-For: 2001:db8::1")), Some("2001:db8::1".parse().unwrap()));
+		assert_eq!(WebRequest::forwardedFor(&head("X-Forwarded-For: unknown")), None);
+		assert_eq!(WebRequest::forwardedFor(&head("X-Real-IP: 203.0.113.7")), None);
+	}
+
+	#[test]
+	fn registerHasOneSeparator()
+	{
+		let register = |x: &[u8]| ServerMessage::fromRaw(&[&[1], x].concat());
+		assert!(matches!(register(b"Alice"), ServerMessage::Register(n, 0, c) if n == "Alice" && c.is_empty()));
+		assert!(matches!(register(b"Alice\0\x01mage"), ServerMessage::Register(n, 1, c) if n == "Alice" && c == "mage"));
+		assert!(matches!(register(b"Al\0x\0ice"), ServerMessage::Invalid));
+		assert!(matches!(register(b"Alice\0"), ServerMessage::Invalid));
+	}
+}
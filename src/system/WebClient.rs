@@ -1,137 +1,437 @@
-use std::{io::{ErrorKind, Read, Write}, net::{SocketAddr, TcpStream}, time::Duration};
+use std::{collections::HashMap, io::{ErrorKind, Read, Write}, net::{IpAddr, Shutdown, SocketAddr, TcpStream}, time::{Duration, Instant}};
 
-use crate::system::Server::Server;
+use super::{Log, WebSocket};
+use super::Config::Config;
+use super::Transmission::{ServerMessage, WebError, WebRequest, WebResponse, MAX_BODY, MAX_HEAD};
 
-use super::Transmission::{ServerMessage, WebRequest, WebResponse};
+// Commands which need `Authorization: Bearer <webToken>`,
+// so do chat messages starting with a slash
+const RESTRICTED: &[&str] = &[
+	"getSettings", "saveSettings", "save", "load", "shutdown", "reloadConfig",
+	"listBans", "listMutes", "unban", "unmute"
+];
+
+// Responses are written blocking, a browser which doesn't take one in this time is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Connection
+{
+	pub tcp: TcpStream,
+	pub buffer: Vec<u8>,
+	// Set by the last request, the connection stays open after the response
+	pub keepAlive: bool,
+	// Set by the last request, the response may be compressed
+	pub gzip: bool,
+	// Client behind the proxy if Config.trustProxy is set, responses still go to `tcp`
+	pub forwarded: Option<IpAddr>,
+	// Bearer token of the last request, a WebSocket keeps the one of its upgrade
+	pub token: Option<String>
+}
 
 pub struct WebClient
 {
-	pub tcp: Vec<TcpStream>
+	pub connections: Vec<Connection>,
+	// Connections upgraded to WebSocket, receive chat as it happens
+	pub sockets: Vec<Connection>
 }
 
 impl WebClient
 {
 	pub fn new() -> Self
 	{
-		Self { tcp: vec![] }
+		Self { connections: vec![], sockets: vec![] }
 	}
 	
-	pub fn connect(&mut self, tcp: TcpStream)
+	// Past maxWebConnections the oldest idle keep-alive connection is closed,
+	// if there is none the new one is refused with 503
+	pub fn connect(&mut self, mut tcp: TcpStream, config: &Config)
 	{
-		self.tcp.push(tcp);
+		if self.connections.len() >= config.maxWebConnections
+		{
+			match self.connections.iter().position(|c| c.keepAlive && c.buffer.is_empty())
+			{
+				Some(i) =>
+				{
+					let old = self.connections.remove(i);
+					let _ = old.tcp.shutdown(Shutdown::Both);
+				},
+				None =>
+				{
+					Log::warn(format!("Too many web connections, refused {:?}", tcp.peer_addr()));
+					let _ = tcp.write_all(&WebResponse::ServiceUnavailable.build(&config.corsOrigin, false));
+					let _ = tcp.shutdown(Shutdown::Both);
+					return;
+				}
+			}
+		}
+
+		// Idle keep-alive connections must not block the server
+		let _ = tcp.set_nonblocking(true);
+		self.connections.push(Connection { tcp, buffer: vec![], keepAlive: false, gzip: false, forwarded: None, token: None });
 	}
 
-	pub fn update(&mut self) -> Vec<ServerMessage>
+	pub fn close(&mut self)
 	{
-		let mut req = vec![];
-		for i in 0..self.tcp.len()
+		for c in &mut self.connections
 		{
-			if i >= self.tcp.len() { break; }
-			let buffer = &mut [0u8; 1024];
-			if self.tcp[i].peer_addr().is_err()
+			let _ = c.tcp.flush();
+			let _ = c.tcp.shutdown(Shutdown::Both);
+		}
+		self.connections.clear();
+		for c in &mut self.sockets
+		{
+			let _ = c.tcp.write_all(&WebSocket::encodeFrame(WebSocket::CLOSE, &[]));
+			let _ = c.tcp.shutdown(Shutdown::Both);
+		}
+		self.sockets.clear();
+	}
+
+	// Runs inside Server::update, so everything the server has to answer
+	// itself is returned as a request instead of being read from it here
+	pub fn update(&mut self, config: &mut Config) -> Vec<ServerMessage>
+	{
+		let mut requests = vec![];
+		let buffer = &mut [0u8; 1024];
+		let trustProxy = config.trustProxy;
+		self.connections.retain_mut(|c|
+		{
+			let addr = match c.tcp.peer_addr()
+			{
+				Ok(x) => x,
+				Err(_) => return false
+			};
+			// Everything that arrived, a request over several segments is read in one go
+			let mut read = false;
+			while c.buffer.len() <= MAX_HEAD + MAX_BODY
 			{
-				self.tcp.swap_remove(i);
+				match c.tcp.read(buffer)
+				{
+					// Whatever came before the peer closed its side is still answered
+					Ok(0) if read => break,
+					Ok(0) => return false,
+					Ok(size) => { c.buffer.extend_from_slice(&buffer[0..size]); read = true; },
+					Err(x) if x.kind() == ErrorKind::WouldBlock => break,
+					Err(_) => return false
+				}
 			}
-			let addr = self.tcp[i].peer_addr().unwrap();
-			match self.tcp[i].read(buffer)
+			if !read { return true; }
+			let msg = String::from_utf8_lossy(&c.buffer).to_string();
+			c.keepAlive = WebRequest::keepAlive(&msg);
+			c.gzip = WebRequest::acceptsGzip(&msg);
+			c.forwarded = if trustProxy { WebRequest::forwardedFor(&msg) } else { None };
+			c.token = WebRequest::bearer(&msg);
+			let request = WebRequest::build(msg);
+			// Headers or body are still on their way
+			if let WebRequest::Incomplete = request { return true; }
+			// Answered and closed, the rest of the body is never read
+			if let WebRequest::TooLarge = request { c.keepAlive = false; }
+			c.buffer.clear();
+			requests.push((addr, c.forwarded.unwrap_or(addr.ip()), c.token.clone(), request));
+			true
+		});
+
+		let mut req = vec![];
+		for (addr, ip, token, request) in requests
+		{
+			match request
 			{
-				Ok(size) =>
+				WebRequest::Invalid | WebRequest::Incomplete => {},
+				WebRequest::Get(data) => req.extend(self.get(addr, data, token, config)),
+				WebRequest::Post(data) => match WebClient::post(addr, data, token, config)
 				{
-					if size == 0 { continue; }
-					let msg = String::from_utf8_lossy(&buffer[0..size]).to_string();
-					match WebRequest::build(msg)
+					Ok(x) => req.push(x),
+					Err(x) =>
 					{
-						WebRequest::Invalid => continue,
-						WebRequest::Get(data) => WebClient::get(addr, data),
-						WebRequest::Post(data) => req.push(WebClient::post(addr, data))
+						Log::warn(format!("Rejected web request from {ip}: {}", x.toString()));
+						self.sendResponse(addr, WebResponse::Error(x), &config.corsOrigin);
 					}
 				},
-				Err(_) => {}
+				WebRequest::Options => self.sendResponse(addr, WebResponse::NoContent, &config.corsOrigin),
+				WebRequest::TooLarge =>
+				{
+					Log::warn(format!("Web request from {ip} is too large"));
+					self.sendResponse(addr, WebResponse::PayloadTooLarge, &config.corsOrigin);
+				},
+				WebRequest::Unsupported(method) =>
+				{
+					Log::debug(format!("Unsupported method {method} from {ip}"));
+					self.sendResponse(addr, WebResponse::MethodNotAllowed, &config.corsOrigin);
+				}
+				WebRequest::Upgrade(key) => self.upgrade(addr, key, &config.corsOrigin)
 			}
 		}
+		req.append(&mut self.updateSockets(config));
 
 		req
 	}
 
-	fn get(id: SocketAddr, data: String)
+	fn upgrade(&mut self, addr: SocketAddr, key: String, origin: &str)
 	{
-		let data = data.split("?").collect::<Vec<&str>>()[0];
-		if data == "/"
+		let i = match self.connections.iter().position(|c| c.tcp.peer_addr().is_ok_and(|x| x == addr))
 		{
-			WebClient::sendResponse(id,
-				WebResponse::MovedPermanently(String::from("/index.html")),
-			);
-		}
-		else
+			Some(i) => i,
+			None => return
+		};
+		let mut c = self.connections.remove(i);
+		let msg = WebResponse::SwitchingProtocols(WebSocket::acceptKey(&key))
+			.build(origin, false);
+		let _ = c.tcp.set_nonblocking(false);
+		if c.tcp.write_all(&msg).is_err() { return; }
+		let _ = c.tcp.set_nonblocking(true);
+		Log::debug(format!("WebSocket opened: {addr}"));
+		self.sockets.push(c);
+	}
+
+	fn updateSockets(&mut self, config: &mut Config) -> Vec<ServerMessage>
+	{
+		let mut req = vec![];
+		let buffer = &mut [0u8; 1024];
+		self.sockets.retain_mut(|c|
 		{
-			let path = String::from("res/web") + &data;
-			WebClient::sendResponse(id,
-				match std::fs::read_to_string(path.clone())
+			let addr = match c.tcp.peer_addr()
+			{
+				Ok(x) => x,
+				Err(_) => return false
+			};
+			loop
+			{
+				match c.tcp.read(buffer)
+				{
+					Ok(0) => return false,
+					Ok(size) => c.buffer.extend_from_slice(&buffer[0..size]),
+					Err(x) if x.kind() == ErrorKind::WouldBlock => break,
+					Err(_) => return false
+				}
+				// Well above one full frame, the peer is flooding
+				if c.buffer.len() > 2 * WebSocket::MAX_PAYLOAD
+				{
+					Log::warn(format!("WebSocket {addr} sent too much data, closing"));
+					return false;
+				}
+			}
+			loop
+			{
+				let (opcode, payload, size) = match WebSocket::decodeFrame(&c.buffer)
 				{
-					Ok(text) =>
+					Ok(Some(x)) => x,
+					Ok(None) => break,
+					Err(x) =>
 					{
-						WebResponse::Ok(text, match path.split(".").last().unwrap()
+						Log::warn(format!("WebSocket {addr} closed: {x}"));
+						let _ = c.tcp.write_all(&WebSocket::encodeFrame(WebSocket::CLOSE, &[]));
+						return false;
+					}
+				};
+				c.buffer.drain(0..size);
+				match opcode
+				{
+					WebSocket::TEXT =>
+					{
+						let mut data = json::JsonValue::new_object();
+						let _ = data.insert("msg", String::from_utf8_lossy(&payload).to_string());
+						match WebClient::parsePost(addr, String::from("chat"), data, c.token.clone(), config)
 						{
-							"js" => String::from("text/javascript"),
-							s => String::from("text/") + s
-						})
+							Ok(x) => req.push(x),
+							Err(x) => Log::warn(format!("Rejected WebSocket message: {}", x.toString()))
+						}
+					},
+					WebSocket::PING =>
+					{
+						let _ = c.tcp.write_all(&WebSocket::encodeFrame(WebSocket::PONG, &payload));
 					},
+					WebSocket::CLOSE =>
+					{
+						let _ = c.tcp.write_all(&WebSocket::encodeFrame(WebSocket::CLOSE, &[]));
+						Log::debug(format!("WebSocket closed: {addr}"));
+						return false;
+					},
+					_ => {}
+				}
+			}
+			true
+		});
+		req
+	}
+
+	// Sends a chat line to every open WebSocket, dropping the dead ones
+	pub fn pushChat(&mut self, text: &str)
+	{
+		let frame = WebSocket::encodeFrame(WebSocket::TEXT, text.as_bytes());
+		self.sockets.retain_mut(|c| c.tcp.write_all(&frame).is_ok());
+	}
+
+	// Routes answered by the server itself are returned as requests
+	fn get(&mut self, id: SocketAddr, data: String, token: Option<String>, config: &Config) -> Option<ServerMessage>
+	{
+		let (data, query) = data.split_once('?').unwrap_or((&data, ""));
+		let query = WebClient::parseQuery(query);
+		let response =
+			if data == "/" { WebResponse::MovedPermanently(String::from("/index.html")) }
+			else if data == "/health" || data == "/api/stats"
+			{
+				return Some(ServerMessage::Health(query.get("verbose").is_some_and(|x| x == "1"), id));
+			}
+			else if data == "/metrics" { return Some(ServerMessage::Metrics(id)); }
+			else if data == "/api/players" { return Some(ServerMessage::PlayersList(id)); }
+			else if data == "/api/config/export"
+			{
+				if !config.checkToken(token.as_deref().unwrap_or("")) { WebResponse::Unauthorized }
+				else
+				{
+					let mut cfg = config.toJson();
+					cfg["settings"].remove("webToken");
+					WebResponse::Download(json::stringify_pretty(cfg, 4), String::from("config.json"))
+				}
+			}
+			else if data == "/audit"
+			{
+				if !config.checkToken(token.as_deref().unwrap_or("")) { WebResponse::Unauthorized }
+				else
+				{
+					let count = query.get("count").and_then(|x| x.parse().ok()).unwrap_or(100);
+					WebResponse::Ok(json::stringify(Log::auditTail(count)), String::from("text/json"))
+				}
+			}
+			else
+			{
+				let path = config.webRoot.clone() + data;
+				if !WebClient::isPublic(&path, &config.webRoot)
+				{
+					Log::warn(format!("File is missing or outside of {}: {data}", config.webRoot));
+					self.sendResponse(id, WebResponse::NotFound, &config.corsOrigin);
+					return None;
+				}
+				match std::fs::read_to_string(path.clone())
+				{
+					Ok(text) => WebResponse::Ok(text, String::from(WebClient::mimeType(&path))),
 					Err(x) => match x.kind()
 					{
 						ErrorKind::InvalidData => match std::fs::read(path.clone())
 						{
-							Ok(data) =>
-							{
-								WebResponse::OkRaw(data, match path.split(".").last().unwrap()
-								{
-									"png" => String::from("image/png"),
-									"otf" => String::from("application/x-font-opentype"),
-									s => { println!("Unknown file: {s}"); String::from(s) }
-								})
-							},
-							Err(x) => { println!("{x:#?}"); WebResponse::NotFound }
+							Ok(data) => WebResponse::OkRaw(data, String::from(WebClient::mimeType(&path))),
+							Err(x) => { Log::error(format!("{x:#?}")); WebResponse::InternalServerError(x.to_string()) }
 						},
-						_ => { println!("{x:#?}"); WebResponse::NotFound }
+						ErrorKind::NotFound => { Log::warn(format!("{x:#?}")); WebResponse::NotFound },
+						_ => { Log::error(format!("{x:#?}")); WebResponse::InternalServerError(x.to_string()) }
 					}
 				}
-			);
+			};
+		self.sendResponse(id, response, &config.corsOrigin);
+		None
+	}
+
+	// Splits `a=1&b=x%20y` into pairs, decoding `+` and %XX escapes
+	fn parseQuery(query: &str) -> HashMap<String, String>
+	{
+		let decode = |x: &str|
+		{
+			let x = x.replace('+', " ");
+			let bytes = x.as_bytes();
+			let mut out = vec![];
+			let mut i = 0;
+			while i < bytes.len()
+			{
+				let hex = bytes.get(i + 1..i + 3)
+					.and_then(|h| std::str::from_utf8(h).ok())
+					.and_then(|h| u8::from_str_radix(h, 16).ok());
+				match hex
+				{
+					Some(b) if bytes[i] == b'%' => { out.push(b); i += 3; },
+					_ => { out.push(bytes[i]); i += 1; }
+				}
+			}
+			String::from_utf8_lossy(&out).to_string()
+		};
+
+		query.split('&')
+			.filter(|x| !x.is_empty())
+			.map(|x| match x.split_once('=')
+			{
+				Some((key, value)) => (decode(key), decode(value)),
+				None => (decode(x), String::new())
+			})
+			.collect()
+	}
+
+	// Only files inside the web root can be served
+	fn isPublic(path: &str, root: &str) -> bool
+	{
+		if path.contains('\0') || path.split(['/', '\\']).any(|x| x == "..") { return false; }
+		match (std::fs::canonicalize(root), std::fs::canonicalize(path))
+		{
+			(Ok(root), Ok(file)) => file.starts_with(root),
+			_ => false
 		}
 	}
 
-	fn post(id: SocketAddr, data: String) -> ServerMessage
+	fn mimeType(path: &str) -> &'static str
 	{
-		match json::parse(&data)
+		let ext = path.rsplit_once('.').map(|x| x.1).unwrap_or("").to_lowercase();
+		match ext.as_str()
 		{
-			Ok(parsed) => {
-				let (cmd, data) = parsed.entries().nth(0).unwrap();
-				WebClient::parsePost(id, cmd.to_string(), data.clone())
-			},
-			Err(_) => ServerMessage::Invalid(id)
+			"html" | "htm" => "text/html",
+			"css" => "text/css",
+			"js" | "mjs" => "text/javascript",
+			"json" => "application/json",
+			"txt" => "text/plain",
+			"svg" => "image/svg+xml",
+			"png" => "image/png",
+			"jpg" | "jpeg" => "image/jpeg",
+			"gif" => "image/gif",
+			"webp" => "image/webp",
+			"ico" => "image/x-icon",
+			"otf" => "font/otf",
+			"ttf" => "font/ttf",
+			"woff" => "font/woff",
+			"woff2" => "font/woff2",
+			"wasm" => "application/wasm",
+			_ =>
+			{
+				Log::warn(format!("Unknown file type: {path}"));
+				"application/octet-stream"
+			}
+		}
+	}
+
+	// Body is `{ "command": { arguments } }`
+	fn post(id: SocketAddr, data: String, token: Option<String>, config: &mut Config) -> Result<ServerMessage, WebError>
+	{
+		let parsed = json::parse(&data).map_err(|x| WebError::Malformed(x.to_string()))?;
+		match parsed.entries().nth(0)
+		{
+			Some((cmd, data)) => WebClient::parsePost(id, cmd.to_string(), data.clone(), token, config),
+			None => Err(WebError::Malformed(String::from("no command")))
 		}
 	}
 
-	fn parsePost(id: SocketAddr, cmd: String, data: json::JsonValue) -> ServerMessage
+	fn parsePost(
+		id: SocketAddr, cmd: String, data: json::JsonValue, token: Option<String>, config: &mut Config
+	) -> Result<ServerMessage, WebError>
 	{
-		if !data.is_object()
+		let authorized = config.checkToken(token.as_deref().unwrap_or(""));
+		if RESTRICTED.contains(&cmd.as_str()) && !authorized
 		{
-			println!("Wrong request: arguments should be provided as object with properties.");
-			return ServerMessage::Invalid(id);
+			return Err(WebError::Unauthorized(cmd));
 		}
+		if !data.is_object() { return Err(WebError::NotObject(cmd)); }
 
-		if cmd == "players" { return ServerMessage::PlayersList(id); }
+		if cmd == "players" { return Ok(ServerMessage::PlayersList(id)); }
 		else if cmd == "chat"
 		{
 			for (section, value) in data.entries()
 			{
 				if section == "msg"
 				{
-					return ServerMessage::Chat(
-						value.as_str().unwrap_or("").to_string(),
-						id
-					);
+					let msg = value.as_str().unwrap_or("").to_string();
+					// Chat commands act on the server like the restricted commands do
+					if msg.trim_start().starts_with('/') && !authorized
+					{
+						return Err(WebError::Unauthorized(String::from("chat command")));
+					}
+					return Ok(ServerMessage::Chat(msg, id));
 				}
 			}
-			return ServerMessage::Invalid(id);
+			return Err(WebError::MissingField(String::from("msg")));
 		}
 		else if cmd == "getChat"
 		{
@@ -139,32 +439,78 @@ impl WebClient
 			{
 				if section == "messagesLength"
 				{
-					return ServerMessage::ChatHistory(value.as_usize().unwrap_or(0), id);
+					let length = Config::number(value).unwrap_or(0) as usize;
+					return Ok(ServerMessage::ChatHistory(length, id));
 				}
 			}
-			return ServerMessage::Invalid(id);
+			return Err(WebError::MissingField(String::from("messagesLength")));
 		}
-		else if cmd == "state" { return ServerMessage::GameState(id); }
-		else if cmd == "chatLength" { return ServerMessage::ChatLength(id); }
-		else if cmd == "getSettings" { return ServerMessage::GetSettings(id); }
-		else if cmd == "saveSettings"
+		else if cmd == "save"
 		{
-			let cfg = Server::getInstance().getConfig();
-			for (var, value) in data.entries()
+			let mut checkpoint = String::new();
+			let mut slot = String::new();
+			for (section, value) in data.entries()
+			{
+				if section == "checkpoint" { checkpoint = value.as_str().unwrap_or("").to_string(); }
+				if section == "slot" { slot = value.as_str().unwrap_or("").to_string(); }
+			}
+			return Ok(ServerMessage::SaveGame(checkpoint, slot, id));
+		}
+		else if cmd == "load"
+		{
+			for (section, value) in data.entries()
 			{
-				if var == "maxPlayersCount"
+				if section == "slot"
 				{
-					cfg.maxPlayersCount = value.as_u8().unwrap_or(1);
+					return Ok(ServerMessage::LoadGame(value.as_str().unwrap_or("").to_string(), id));
 				}
-				else if var == "port"
+			}
+			return Err(WebError::MissingField(String::from("slot")));
+		}
+		else if cmd == "listSaves" { return Ok(ServerMessage::ListSaves(id)); }
+		else if cmd == "state" { return Ok(ServerMessage::GameState(id)); }
+		else if cmd == "positions" { return Ok(ServerMessage::Positions(id)); }
+		else if cmd == "inventory"
+		{
+			for (section, value) in data.entries()
+			{
+				if section == "name"
+				{
+					return Ok(ServerMessage::Inventory(value.as_str().unwrap_or("").to_string(), id));
+				}
+			}
+			return Err(WebError::MissingField(String::from("name")));
+		}
+		else if cmd == "shutdown" { return Ok(ServerMessage::Shutdown(id)); }
+		else if cmd == "reloadConfig" { return Ok(ServerMessage::ReloadConfig(id)); }
+		else if cmd == "listBans" { return Ok(ServerMessage::ListBans(id)); }
+		else if cmd == "listMutes" { return Ok(ServerMessage::ListMutes(id)); }
+		else if cmd == "unban" || cmd == "unmute"
+		{
+			for (section, value) in data.entries()
+			{
+				if section == "ip" && cmd == "unban"
 				{
-					cfg.port = value.as_u16().unwrap_or(2018);
+					return Ok(ServerMessage::Unban(value.as_str().unwrap_or("").to_string(), id));
 				}
-				else if var == "tickRate"
+				if section == "name" && cmd == "unmute"
 				{
-					cfg.tickRate = value.as_u8().unwrap_or(1);
-					cfg.sendTime = Duration::from_secs_f32(1.0 / cfg.tickRate as f32);
-					cfg.recvTime = Duration::from_secs_f32(0.5 / cfg.tickRate as f32);
+					return Ok(ServerMessage::Unmute(value.as_str().unwrap_or("").to_string(), id));
+				}
+			}
+			return Err(WebError::MissingField(String::from(if cmd == "unban" { "ip" } else { "name" })));
+		}
+		else if cmd == "chatLength" { return Ok(ServerMessage::ChatLength(id)); }
+		else if cmd == "getSettings" { return Ok(ServerMessage::GetSettings(id)); }
+		else if cmd == "saveSettings"
+		{
+			let mut cfg = config.clone();
+			let mut errors = vec![];
+			for (var, value) in data.entries()
+			{
+				if Config::isSetting(var)
+				{
+					if let Err(x) = cfg.applySetting(var, value) { errors.push(x); }
 				}
 				else
 				{
@@ -173,37 +519,389 @@ impl WebClient
 					{
 						"Разработчик" => super::Config::Permission::Developer,
 						"Администратор" => super::Config::Permission::Admin,
+						"Гость" => super::Config::Permission::Guest,
 						_ => super::Config::Permission::Player,
 					});
 				}
 			}
-			cfg.save();
-			return ServerMessage::SaveSettings(id);
+			if let Err(mut x) = cfg.validate() { errors.append(&mut x); }
+			if !errors.is_empty() { return Ok(ServerMessage::SettingsRejected(errors, id)); }
+
+			return Ok(ServerMessage::SaveSettings(Box::new(cfg), id));
 		}
-		else
+		else { return Err(WebError::UnknownCommand(cmd)); }
+	}
+
+	// `origin` is the configured CORS origin
+	pub fn sendResponse(&mut self, id: SocketAddr, code: WebResponse, origin: &str)
+	{
+		let i = match self.connections.iter().position(|x| x.tcp.peer_addr().is_ok_and(|x| x == id))
+		{
+			Some(i) => i,
+			None => return
+		};
+		let conn = &mut self.connections[i];
+		let msg = code.build(origin, conn.gzip);
+		let _ = conn.tcp.set_nonblocking(false);
+		match Self::write(&mut conn.tcp, &msg, Instant::now() + WRITE_TIMEOUT)
 		{
-			println!("Unknown command: {cmd}");
-			return ServerMessage::Invalid(id);
+			Ok(_) if conn.keepAlive =>
+			{
+				let _ = conn.tcp.set_nonblocking(true);
+				return;
+			},
+			Ok(_) => {},
+			Err(x) => { Log::error(format!("Error occured when sending response: {x:?}")); }
 		}
+		self.connections.remove(i);
 	}
 
-	pub fn sendResponse(id: SocketAddr, code: WebResponse)
+	// write_all finishing before `deadline`, a socket timeout alone only bounds each partial write
+	fn write(tcp: &mut TcpStream, mut data: &[u8], deadline: Instant) -> std::io::Result<()>
 	{
-		let c = Server::getInstance().getWebClient();
-		let msg = code.build();
-		for i in 0..c.tcp.len()
+		while !data.is_empty()
 		{
-			let tcp = &mut c.tcp[i];
-			if tcp.peer_addr().unwrap() == id
+			let left = deadline.saturating_duration_since(Instant::now());
+			if left.is_zero() { return Err(ErrorKind::TimedOut.into()); }
+			tcp.set_write_timeout(Some(left))?;
+			match tcp.write(data)
 			{
-				match tcp.write_all(&msg)
-				{
-					Ok(_) => {},
-					Err(x) => { println!("Error occured when sending response: {x:?}"); }
-				}
-				c.tcp.remove(i);
-				break;
+				Ok(0) => return Err(ErrorKind::WriteZero.into()),
+				Ok(n) => data = &data[n..],
+				Err(x) if x.kind() == ErrorKind::Interrupted => {},
+				Err(x) => return Err(x)
 			}
 		}
+		Ok(())
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+	use std::net::TcpListener;
+
+	use super::*;
+	use super::super::RES_DIR;
+
+	// Adds the server side of a loopback connection, returns the browser side
+	fn browser(web: &mut WebClient, config: &Config) -> TcpStream
+	{
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let _ = peer.set_read_timeout(Some(Duration::from_millis(200)));
+		web.connect(listener.accept().unwrap().0, config);
+		peer
+	}
+
+	// The response the browser got so far
+	fn response(peer: &mut TcpStream) -> String
+	{
+		let mut data = vec![];
+		let buffer = &mut [0u8; 4096];
+		while let Ok(size) = peer.read(buffer)
+		{
+			if size == 0 { break; }
+			data.extend_from_slice(&buffer[..size]);
+		}
+		String::from_utf8_lossy(&data).to_string()
+	}
+
+	// Reads what arrived by now
+	fn poll(web: &mut WebClient, config: &mut Config) -> Vec<ServerMessage>
+	{
+		std::thread::sleep(Duration::from_millis(20));
+		web.update(config)
+	}
+
+	#[test]
+	fn bodyIsAwaitedUntilContentLength()
+	{
+		let (mut web, mut config) = (WebClient::new(), Config::default());
+		let mut peer = browser(&mut web, &config);
+		let text = "ы".repeat(2048);
+		let body = format!("{{ \"chat\": {{ \"msg\": \"{text}\" }} }}");
+		assert!(body.len() > 4096);
+		let head = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", body.len());
+		peer.write_all(head.as_bytes()).unwrap();
+		peer.write_all(&body.as_bytes()[..1000]).unwrap();
+		assert!(poll(&mut web, &mut config).is_empty());
+		peer.write_all(&body.as_bytes()[1000..]).unwrap();
+		let msgs = poll(&mut web, &mut config);
+		assert_eq!(msgs.len(), 1);
+		assert!(matches!(&msgs[0], ServerMessage::Chat(x, _) if *x == text));
+	}
+
+	#[test]
+	fn saveNeedsTheToken()
+	{
+		let mut config = Config { webToken: String::from("secret"), ..Config::default() };
+		let body = String::from("{ \"save\": { \"checkpoint\": \"мост\" } }");
+		let addr = "127.0.0.1:1".parse().unwrap();
+		assert_eq!(WebClient::post(addr, body.clone(), None, &mut config).unwrap_err(),
+			WebError::Unauthorized(String::from("save")));
+		let msg = WebClient::post(addr, body, Some(String::from("secret")), &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SaveGame(x, slot, _)) if x == "мост" && slot.is_empty()));
+	}
+
+	#[test]
+	fn configExportMasksTheToken()
+	{
+		let mut config = Config { webToken: String::from("secret"), tickRate: 30, ..Config::default() };
+		let mut web = WebClient::new();
+		let mut peer = browser(&mut web, &config);
+		peer.write_all(b"GET /api/config/export HTTP/1.1\r\n\r\n").unwrap();
+		poll(&mut web, &mut config);
+		assert!(response(&mut peer).starts_with("HTTP/1.1 401"));
+
+		peer = browser(&mut web, &config);
+		peer.write_all(b"GET /api/config/export HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n").unwrap();
+		poll(&mut web, &mut config);
+		let text = response(&mut peer);
+		let body = json::parse(text.split_once("\r\n\r\n").unwrap().1).unwrap();
+		assert!(text.starts_with("HTTP/1.1 200") && text.contains("attachment"));
+		assert_eq!(body["settings"]["tickRate"].as_u8(), Some(30));
+		assert!(!body["settings"].has_key("webToken") && !text.contains("secret"));
+	}
+
+	#[test]
+	fn invalidSettingsAreRejected()
+	{
+		let mut config = Config { webToken: String::from("secret"), ..Config::default() };
+		let addr = "127.0.0.1:1".parse().unwrap();
+		let post = |body: &str, config: &mut Config|
+			WebClient::post(addr, String::from(body), Some(String::from("secret")), config);
+
+		let msg = post("{ \"saveSettings\": { \"port\": 80, \"tickRate\": 0 } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SettingsRejected(x, _)) if x.len() == 2));
+		let msg = post("{ \"saveSettings\": { \"port\": 0, \"tickRate\": 20 } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SaveSettings(x, _)) if x.port == 0 && x.tickRate == 20));
+	}
+
+	#[test]
+	fn keepAliveServesSeveralRequests()
+	{
+		let mut config = Config::default();
+		let mut web = WebClient::new();
+		let mut peer = browser(&mut web, &config);
+		for _ in 0..2
+		{
+			peer.write_all(b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+			poll(&mut web, &mut config);
+			let text = response(&mut peer);
+			assert!(text.starts_with("HTTP/1.1 301 ") && text.contains("Content-Length: 0"));
+			assert_eq!(web.connections.len(), 1);
+		}
+
+		peer.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+		poll(&mut web, &mut config);
+		assert!(response(&mut peer).starts_with("HTTP/1.1 301 "));
+		assert!(web.connections.is_empty());
+	}
+
+	#[test]
+	fn deadSocketDoesntSkipTheNext()
+	{
+		let mut config = Config::default();
+		let mut web = WebClient::new();
+		let mut peers = (0..3).map(|_| browser(&mut web, &config)).collect::<Vec<TcpStream>>();
+		drop(peers.remove(1));
+		for peer in &mut peers
+		{
+			peer.write_all(b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+		}
+		poll(&mut web, &mut config);
+		for peer in &mut peers
+		{
+			assert!(response(peer).starts_with("HTTP/1.1 301 "));
+		}
+		assert_eq!(web.connections.len(), 2);
+	}
+
+	#[test]
+	fn mimeTypesByExtension()
+	{
+		let table =
+		[
+			("web/index.html", "text/html"),
+			("web/style.CSS", "text/css"),
+			("web/app.js", "text/javascript"),
+			("web/map.json", "application/json"),
+			("web/icon.svg", "image/svg+xml"),
+			("web/logo.png", "image/png"),
+			("web/favicon.ico", "image/x-icon"),
+			("web/font.woff2", "font/woff2"),
+			("web/font.otf", "font/otf"),
+			("web/data.bin", "application/octet-stream"),
+			("web/README", "application/octet-stream")
+		];
+		for (path, mime) in table
+		{
+			assert_eq!(WebClient::mimeType(path), mime, "{path}");
+		}
+	}
+
+	#[test]
+	fn onlyFilesUnderTheRootArePublic()
+	{
+		let root = format!("{RES_DIR}/web526");
+		std::fs::create_dir_all(format!("{root}/css")).unwrap();
+		std::fs::write(format!("{root}/css/site.css"), "body {}").unwrap();
+		std::fs::write(format!("{RES_DIR}/secret526.json"), "{}").unwrap();
+
+		let nested = WebClient::isPublic(&format!("{root}/css/site.css"), &root);
+		let escaped = WebClient::isPublic(&format!("{root}/../secret526.json"), &root);
+		let hidden = WebClient::isPublic(&format!("{root}/css/..\\../secret526.json"), &root);
+		let nul = WebClient::isPublic(&format!("{root}/css/site.css\0"), &root);
+		let missing = WebClient::isPublic(&format!("{root}/css/none.css"), &root);
+
+		let mut config = Config { webRoot: root.clone(), ..Config::default() };
+		let mut web = WebClient::new();
+		let mut answers = vec![];
+		for path in ["/css/site.css", "/../secret526.json"]
+		{
+			let mut peer = browser(&mut web, &config);
+			peer.write_all(format!("GET {path} HTTP/1.1\r\n\r\n").as_bytes()).unwrap();
+			poll(&mut web, &mut config);
+			answers.push(response(&mut peer));
+		}
+		let _ = std::fs::remove_dir_all(&root);
+		let _ = std::fs::remove_file(format!("{RES_DIR}/secret526.json"));
+
+		assert!(nested);
+		assert!(!escaped && !hidden && !nul && !missing);
+		assert!(answers[0].starts_with("HTTP/1.1 200 ") && answers[0].ends_with("body {}"));
+		assert!(answers[1].starts_with("HTTP/1.1 404 "));
+	}
+
+	#[test]
+	fn queryIsDecoded()
+	{
+		let query = WebClient::parseQuery("a=1&b=hello%20world&c=a+b&d=%D1%82%D0%B5%D1%81%D1%82&e=100%&flag");
+		assert_eq!(query.get("a").map(String::as_str), Some("1"));
+		assert_eq!(query.get("b").map(String::as_str), Some("hello world"));
+		assert_eq!(query.get("c").map(String::as_str), Some("a b"));
+		assert_eq!(query.get("d").map(String::as_str), Some("тест"));
+		assert_eq!(query.get("e").map(String::as_str), Some("100%"));
+		assert_eq!(query.get("flag").map(String::as_str), Some(""));
+		assert!(WebClient::parseQuery("").is_empty());
+	}
+
+	#[test]
+	fn connectionsPastTheLimitAreRefused()
+	{
+		let mut config = Config { maxWebConnections: 2, ..Config::default() };
+		let mut web = WebClient::new();
+		let mut peers = (0..2).map(|_| browser(&mut web, &config)).collect::<Vec<TcpStream>>();
+		let mut extra = browser(&mut web, &config);
+		assert!(response(&mut extra).starts_with("HTTP/1.1 503 "));
+		assert_eq!(web.connections.len(), 2);
+
+		// An idle keep-alive connection makes room instead
+		peers[0].write_all(b"GET / HTTP/1.1\r\nConnection: keep-alive\r\n\r\n").unwrap();
+		poll(&mut web, &mut config);
+		assert!(response(&mut peers[0]).starts_with("HTTP/1.1 301 "));
+		let mut next = browser(&mut web, &config);
+		next.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+		poll(&mut web, &mut config);
+		assert!(response(&mut next).starts_with("HTTP/1.1 301 "));
+		assert!(response(&mut peers[0]).is_empty());
+	}
+
+	#[test]
+	fn putIsNotAllowed()
+	{
+		assert!(matches!(WebRequest::build(String::from("PUT /api HTTP/1.1\r\n\r\n")), WebRequest::Unsupported(x) if x == "PUT"));
+
+		let mut config = Config::default();
+		let mut web = WebClient::new();
+		let mut peer = browser(&mut web, &config);
+		peer.write_all(b"PUT /api HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+		assert!(poll(&mut web, &mut config).is_empty());
+		let text = response(&mut peer);
+		assert!(text.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+		assert!(text.contains("\r\nAllow: GET, POST, OPTIONS\r\n"));
+	}
+
+	#[test]
+	fn saveSettingsNeedsTheToken()
+	{
+		let mut config = Config { webToken: String::from("secret"), ..Config::default() };
+		let addr = "127.0.0.1:1".parse().unwrap();
+		let body = String::from("{ \"saveSettings\": { \"tickRate\": 20 } }");
+		for token in [None, Some(String::from("wrong")), Some(String::new())]
+		{
+			assert_eq!(WebClient::post(addr, body.clone(), token, &mut config).unwrap_err(),
+				WebError::Unauthorized(String::from("saveSettings")));
+		}
+		let msg = WebClient::post(addr, body.clone(), Some(String::from("secret")), &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SaveSettings(x, _)) if x.tickRate == 20));
+
+		// Without a configured token nobody gets in
+		config.webToken.clear();
+		assert!(WebClient::post(addr, body.clone(), Some(String::new()), &mut config).is_err());
+
+		let mut web = WebClient::new();
+		let mut peer = browser(&mut web, &config);
+		peer.write_all(format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}", body.len()).as_bytes()).unwrap();
+		assert!(poll(&mut web, &mut config).is_empty());
+		assert!(response(&mut peer).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+	}
+
+	#[test]
+	fn numbersMayComeAsStrings()
+	{
+		let mut config = Config { webToken: String::from("secret"), ..Config::default() };
+		let addr = "127.0.0.1:1".parse().unwrap();
+		let post = |body: &str, config: &mut Config|
+			WebClient::post(addr, String::from(body), Some(String::from("secret")), config);
+
+		let msg = post("{ \"saveSettings\": { \"maxPlayersCount\": \"6\", \"tickRate\": \" 30 \" } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SaveSettings(x, _)) if x.maxPlayersCount == 6 && x.tickRate == 30));
+
+		// A number, only past the limit of the state packet
+		let msg = post("{ \"saveSettings\": { \"maxPlayersCount\": \"8\" } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SettingsRejected(x, _)) if x == ["maxPlayersCount: 8 is not in 1-7"]));
+		let msg = post("{ \"saveSettings\": { \"maxPlayersCount\": \"много\" } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::SettingsRejected(x, _)) if x[0].ends_with("is not a number")));
+
+		let msg = post("{ \"getChat\": { \"messagesLength\": \"3\" } }", &mut config);
+		assert!(matches!(msg, Ok(ServerMessage::ChatHistory(3, _))));
+	}
+
+	#[test]
+	fn forwardedForNeedsTrustProxy()
+	{
+		let request = b"GET / HTTP/1.1\r\nX-Forwarded-For: 203.0.113.7\r\nConnection: keep-alive\r\n\r\n";
+		let mut config = Config::default();
+		let mut web = WebClient::new();
+		let mut peer = browser(&mut web, &config);
+		peer.write_all(request).unwrap();
+		poll(&mut web, &mut config);
+		assert_eq!(web.connections[0].forwarded, None);
+		assert!(response(&mut peer).starts_with("HTTP/1.1 301 "));
+
+		// The proxy's socket still gets the response
+		config.trustProxy = true;
+		peer.write_all(request).unwrap();
+		poll(&mut web, &mut config);
+		assert_eq!(web.connections[0].forwarded, Some("203.0.113.7".parse().unwrap()));
+		assert!(response(&mut peer).starts_with("HTTP/1.1 301 "));
+	}
+
+	#[test]
+	fn stalledBrowserIsDropped()
+	{
+		let mut web = WebClient::new();
+		let config = Config::default();
+		let peer = browser(&mut web, &config);
+		let id = peer.local_addr().unwrap();
+		web.connections[0].keepAlive = true;
+
+		// Far more than the socket buffers hold, and the browser never reads
+		let start = Instant::now();
+		web.sendResponse(id, WebResponse::Ok("x".repeat(64 << 20), String::from("text/plain")), "*");
+		assert!(start.elapsed() < WRITE_TIMEOUT * 2);
+		assert!(web.connections.is_empty());
+	}
+}
@@ -0,0 +1,191 @@
+// https://datatracker.ietf.org/doc/html/rfc6455
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub const TEXT: u8 = 0x1;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+// Chat messages are short, anything longer closes the socket
+pub const MAX_PAYLOAD: usize = 64 * 1024;
+
+// Value of Sec-WebSocket-Accept for the client's Sec-WebSocket-Key
+pub fn acceptKey(key: &str) -> String
+{
+	base64(&sha1((String::from(key.trim()) + GUID).as_bytes()))
+}
+
+// Server frames are never masked or fragmented
+pub fn encodeFrame(opcode: u8, payload: &[u8]) -> Vec<u8>
+{
+	let mut frame = vec![0x80 | opcode];
+	let len = payload.len();
+	if len < 126 { frame.push(len as u8); }
+	else if len <= u16::MAX as usize
+	{
+		frame.push(126);
+		frame.extend_from_slice(&(len as u16).to_be_bytes());
+	}
+	else
+	{
+		frame.push(127);
+		frame.extend_from_slice(&(len as u64).to_be_bytes());
+	}
+	frame.extend_from_slice(payload);
+	frame
+}
+
+// Returns the opcode, unmasked payload and the frame size,
+// or None if the frame is not fully received yet.
+// Fails on payloads longer than MAX_PAYLOAD.
+pub fn decodeFrame(data: &[u8]) -> Result<Option<(u8, Vec<u8>, usize)>, String>
+{
+	if data.len() < 2 { return Ok(None); }
+	let opcode = data[0] & 0x0F;
+	let masked = data[1] & 0x80 != 0;
+	let mut pos = 2;
+	let len = match data[1] & 0x7F
+	{
+		126 =>
+		{
+			pos += 2;
+			match data.get(2..4)
+			{
+				Some(x) => u16::from_be_bytes([x[0], x[1]]) as u64,
+				None => return Ok(None)
+			}
+		},
+		127 =>
+		{
+			pos += 8;
+			match data.get(2..10).and_then(|x| x.try_into().ok())
+			{
+				Some(x) => u64::from_be_bytes(x),
+				None => return Ok(None)
+			}
+		},
+		x => x as u64
+	};
+	if len > MAX_PAYLOAD as u64 { return Err(format!("frame of {len} bytes")); }
+	let len = len as usize;
+	let mask = if masked
+	{
+		pos += 4;
+		match data.get(pos - 4..pos)
+		{
+			Some(x) => x.to_vec(),
+			None => return Ok(None)
+		}
+	} else { vec![0; 4] };
+
+	let payload = match data.get(pos..pos + len)
+	{
+		Some(x) => x.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]).collect(),
+		None => return Ok(None)
+	};
+	Ok(Some((opcode, payload, pos + len)))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20]
+{
+	let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+	let mut msg = data.to_vec();
+	msg.push(0x80);
+	while msg.len() % 64 != 56 { msg.push(0); }
+	msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+	for chunk in msg.chunks(64)
+	{
+		let mut w = [0u32; 80];
+		for (i, word) in chunk.chunks(4).enumerate()
+		{
+			w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+		}
+		for i in 16..80
+		{
+			w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+		}
+
+		let [mut a, mut b, mut c, mut d, mut e] = h;
+		for (i, word) in w.iter().enumerate()
+		{
+			let (f, k) = match i
+			{
+				0..=19 => ((b & c) | (!b & d), 0x5A827999),
+				20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+				40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+				_ => (b ^ c ^ d, 0xCA62C1D6)
+			};
+			let t = a.rotate_left(5)
+				.wrapping_add(f)
+				.wrapping_add(e)
+				.wrapping_add(k)
+				.wrapping_add(*word);
+			e = d;
+			d = c;
+			c = b.rotate_left(30);
+			b = a;
+			a = t;
+		}
+
+		for (x, y) in h.iter_mut().zip([a, b, c, d, e]) { *x = x.wrapping_add(y); }
+	}
+
+	let mut out = [0u8; 20];
+	for (i, x) in h.iter().enumerate() { out[i * 4..i * 4 + 4].copy_from_slice(&x.to_be_bytes()); }
+	out
+}
+
+fn base64(data: &[u8]) -> String
+{
+	const TABLE: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+	let mut out = String::new();
+	for chunk in data.chunks(3)
+	{
+		let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+		let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+		for i in 0..4
+		{
+			if i <= chunk.len() { out.push(TABLE[(n >> (18 - i * 6) & 0x3F) as usize] as char); }
+			else { out.push('='); }
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests
+{
+	use super::*;
+
+	#[test]
+	fn acceptKeyMatchesTheRfc()
+	{
+		assert_eq!(acceptKey("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+		assert_eq!(base64(b"ab"), "YWI=");
+		assert_eq!(base64(b"a"), "YQ==");
+	}
+
+	#[test]
+	fn maskedFrameIsDecoded()
+	{
+		// "Hello" from the RFC, section 5.7
+		let frame = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+		assert_eq!(decodeFrame(&frame), Ok(Some((TEXT, b"Hello".to_vec(), 11))));
+		assert_eq!(decodeFrame(&frame[..8]), Ok(None));
+
+		let long = encodeFrame(TEXT, &[b'x'; 300]);
+		assert_eq!(long[1], 126);
+		assert_eq!(decodeFrame(&long), Ok(Some((TEXT, vec![b'x'; 300], 304))));
+	}
+
+	#[test]
+	fn oversizedFrameIsRefused()
+	{
+		let mut frame = vec![0x81, 127];
+		frame.extend_from_slice(&(MAX_PAYLOAD as u64 + 1).to_be_bytes());
+		assert!(decodeFrame(&frame).is_err());
+	}
+}
@@ -1,6 +1,17 @@
 pub mod Config;
 pub mod Client;
+pub mod Log;
 pub mod Server;
 pub mod State;
 pub mod Transmission;
-pub mod WebClient;
\ No newline at end of file
+pub mod WebClient;
+pub mod WebSocket;
+
+// Config, saves and the audit log, test runs keep theirs under target/
+#[cfg(not(test))]
+pub const RES_DIR: &str = "res/system";
+#[cfg(test)]
+pub const RES_DIR: &str = "target/test-res";
+
+#[cfg(test)]
+pub mod testsupport;
@@ -0,0 +1,209 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::Config::Config;
+use super::Server::Server;
+use super::State::State;
+use super::Transmission::ClientMessage;
+
+// How long a helper waits for the other side before the test fails
+const WAIT: Duration = Duration::from_secs(2);
+
+static CONFIG_FILE: Mutex<()> = Mutex::new(());
+
+// Held by tests that write or read the config file, they would see each other's otherwise
+pub fn configFile() -> MutexGuard<'static, ()>
+{
+	CONFIG_FILE.lock().unwrap_or_else(|x| x.into_inner())
+}
+
+// A server on free loopback ports, driven tick by tick on its own clock
+pub struct TestServer
+{
+	pub server: Server,
+	pub now: Instant
+}
+
+impl TestServer
+{
+	pub fn start() -> Self
+	{
+		Self::with(Self::config())
+	}
+
+	// Loopback and free ports, tests change the rest on top of it
+	pub fn config() -> Config
+	{
+		Config
+		{
+			bindAddress: String::from("127.0.0.1"),
+			port: 0,
+			webPort: 0,
+			// Every tick polls the sockets and sends the states
+			recvTime: Duration::ZERO,
+			sendTime: Duration::ZERO,
+			chatCooldown: Duration::ZERO,
+			..Config::default()
+		}
+	}
+
+	pub fn with(config: Config) -> Self
+	{
+		Self { server: Server::new(config, State::new()), now: Instant::now() }
+	}
+
+	pub fn addr(&self) -> SocketAddr
+	{
+		self.server.tcpAddr().unwrap()
+	}
+
+	pub fn port(&self) -> u16
+	{
+		self.addr().port()
+	}
+
+	pub fn connect(&mut self) -> TestClient
+	{
+		let client = TestClient::connect(self.addr());
+		self.step(Duration::from_millis(1));
+		client
+	}
+
+	// Sends a web command like the dashboard does, returns the status code and the body
+	pub fn post(&mut self, body: &str, token: Option<&str>) -> (u16, String)
+	{
+		let auth = token.map(|x| format!("Authorization: Bearer {x}\r\n")).unwrap_or_default();
+		self.http(format!("POST / HTTP/1.1\r\n{auth}Content-Length: {}\r\n\r\n{body}", body.len()))
+	}
+
+	pub fn get(&mut self, path: &str) -> (u16, String)
+	{
+		self.http(format!("GET {path} HTTP/1.1\r\n\r\n"))
+	}
+
+	fn http(&mut self, request: String) -> (u16, String)
+	{
+		let addr = match self.server.webAddr()
+		{
+			Some(x) => x.unwrap(),
+			None => self.addr()
+		};
+		let mut tcp = TcpStream::connect(addr).unwrap();
+		tcp.write_all(request.as_bytes()).unwrap();
+		let _ = tcp.set_nonblocking(true);
+
+		// Responses without keep-alive close the connection
+		let mut response = vec![];
+		let start = Instant::now();
+		while start.elapsed() < WAIT
+		{
+			self.step(Duration::from_millis(10));
+			let buffer = &mut [0u8; 4096];
+			match tcp.read(buffer)
+			{
+				Ok(0) => break,
+				Ok(size) => response.extend_from_slice(&buffer[..size]),
+				Err(x) if x.kind() == ErrorKind::WouldBlock => std::thread::sleep(Duration::from_millis(1)),
+				Err(x) => panic!("Read failed: {x}")
+			}
+		}
+		let response = String::from_utf8_lossy(&response).to_string();
+		let status = response.split(' ').nth(1).and_then(|x| x.parse().ok()).expect("No response");
+		let body = response.split_once("\r\n\r\n").map(|x| x.1.to_string()).unwrap_or_default();
+		(status, body)
+	}
+
+	// Accepts new sockets and runs one tick `by` after the previous one
+	pub fn step(&mut self, by: Duration)
+	{
+		self.now += by;
+		self.server.listen(self.now);
+		self.server.step(self.now);
+	}
+}
+
+// A game client speaking the length-prefixed frames of Transmission
+pub struct TestClient
+{
+	tcp: TcpStream,
+	buffer: Vec<u8>
+}
+
+impl TestClient
+{
+	pub fn connect(addr: SocketAddr) -> Self
+	{
+		let tcp = TcpStream::connect(addr).unwrap();
+		let _ = tcp.set_read_timeout(Some(Duration::from_millis(20)));
+		Self { tcp, buffer: vec![] }
+	}
+
+	pub fn send(&mut self, code: u8, args: &[u8])
+	{
+		let size = (args.len() + 1) as u16;
+		let frame = [&size.to_le_bytes() as &[u8], &[code], args].concat();
+		self.tcp.write_all(&frame).unwrap();
+	}
+
+	pub fn register(&mut self, name: &str, class: &str)
+	{
+		self.send(1, &[name.as_bytes(), &[0, 0], class.as_bytes()].concat());
+	}
+
+	pub fn chat(&mut self, text: &str)
+	{
+		self.send(2, text.as_bytes());
+	}
+
+	// Every message that arrived so far
+	pub fn receive(&mut self) -> Vec<ClientMessage>
+	{
+		let buffer = &mut [0u8; 1024];
+		loop
+		{
+			match self.tcp.read(buffer)
+			{
+				Ok(0) => break,
+				Ok(size) => self.buffer.extend_from_slice(&buffer[..size]),
+				Err(x) if matches!(x.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+				Err(x) => panic!("Read failed: {x}")
+			}
+		}
+
+		let mut msgs = vec![];
+		while self.buffer.len() >= 2
+		{
+			let size = u16::from_le_bytes([self.buffer[0], self.buffer[1]]) as usize + 2;
+			if self.buffer.len() < size { break; }
+			let frame = self.buffer.drain(..size).collect::<Vec<u8>>();
+			msgs.push(ClientMessage::fromRaw(&frame).expect("Malformed frame"));
+		}
+		msgs
+	}
+
+	// Everything that arrives while the server runs a few more ticks
+	pub fn drain(&mut self, server: &mut TestServer) -> Vec<ClientMessage>
+	{
+		let mut msgs = vec![];
+		for _ in 0..5
+		{
+			server.step(Duration::from_millis(10));
+			msgs.append(&mut self.receive());
+		}
+		msgs
+	}
+
+	// Drives the server until a message matching `f` arrives
+	pub fn expect(&mut self, server: &mut TestServer, f: impl Fn(&ClientMessage) -> bool) -> ClientMessage
+	{
+		let start = Instant::now();
+		while start.elapsed() < WAIT
+		{
+			if let Some(msg) = self.receive().into_iter().find(&f) { return msg; }
+			server.step(Duration::from_millis(10));
+		}
+		panic!("No expected message within {WAIT:?}");
+	}
+}